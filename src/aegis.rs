@@ -0,0 +1,418 @@
+use std::convert::TryInto;
+
+use crate::aes_ctr_hmac::AuthenticationError;
+use crate::aes_ctr_optimized::aes_round;
+use crate::secret::wipe_value;
+
+// AEGIS-128L and AEGIS-256 authenticated encryption
+// (draft-irtf-cfrg-aegis-aead), built on the single AES round function
+//
+// Both variants support 128-bit and 256-bit tags; the tag length is chosen
+// by the length of the tag buffer.
+
+const C0: u128 = 0x000101020305080d1522375990e97962;
+const C1: u128 = 0xdb3d18556dc22ff12011314273b528dd;
+
+fn block(bytes: &[u8]) -> u128 {
+    u128::from_be_bytes(bytes.try_into().unwrap())
+}
+
+// Function to zero pad a partial block
+fn pad_block<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut padded = [0u8; N];
+    padded[..bytes.len()].copy_from_slice(bytes);
+    padded
+}
+
+// Function to encode the bit lengths of associated data and message
+fn length_block(ad_length: usize, message_length: usize) -> u128 {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&(ad_length as u64 * 8).to_le_bytes());
+    bytes[8..].copy_from_slice(&(message_length as u64 * 8).to_le_bytes());
+    u128::from_be_bytes(bytes)
+}
+
+// Function to compare tags without an early exit
+fn tags_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn check_tag_length(tag: &[u8]) {
+    assert!(tag.len() == 16 || tag.len() == 32, "AEGIS tags have to be 128-bit or 256-bit");
+}
+
+// common interface of both state variants, used by the generic AEAD functions below
+trait AegisState {
+    const RATE: usize;
+
+    fn absorb(&mut self, input: &[u8]);
+    fn encrypt_block(&mut self, input: &[u8], output: &mut [u8]);
+    fn decrypt_block(&mut self, input: &[u8], output: &mut [u8]);
+    fn decrypt_partial(&mut self, input: &[u8], output: &mut [u8]);
+    fn finalize(&mut self, ad_length: usize, message_length: usize, tag: &mut [u8]);
+}
+
+struct Aegis128LState([u128; 8]);
+
+impl Drop for Aegis128LState {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.0);
+    }
+}
+
+impl Aegis128LState {
+
+    fn new(key: &[u8; 16], nonce: &[u8; 16]) -> Aegis128LState {
+
+        let key = u128::from_be_bytes(*key);
+        let nonce = u128::from_be_bytes(*nonce);
+        let mut state = Aegis128LState([key ^ nonce, C1, C0, C1, key ^ nonce, key ^ C0, key ^ C1, key ^ C0]);
+
+        for _ in 0..10 {
+            state.update(nonce, key);
+        }
+        state
+    }
+
+    fn update(&mut self, m0: u128, m1: u128) {
+
+        let s = self.0;
+        self.0 = [
+            aes_round(s[7], s[0] ^ m0),
+            aes_round(s[0], s[1]),
+            aes_round(s[1], s[2]),
+            aes_round(s[2], s[3]),
+            aes_round(s[3], s[4] ^ m1),
+            aes_round(s[4], s[5]),
+            aes_round(s[5], s[6]),
+            aes_round(s[6], s[7]),
+        ];
+    }
+
+    fn keystream(&self) -> (u128, u128) {
+        let s = &self.0;
+        (s[6] ^ s[1] ^ (s[2] & s[3]), s[2] ^ s[5] ^ (s[6] & s[7]))
+    }
+}
+
+impl AegisState for Aegis128LState {
+    const RATE: usize = 32;
+
+    fn absorb(&mut self, input: &[u8]) {
+        self.update(block(&input[..16]), block(&input[16..]));
+    }
+
+    fn encrypt_block(&mut self, input: &[u8], output: &mut [u8]) {
+
+        let (z0, z1) = self.keystream();
+        let (t0, t1) = (block(&input[..16]), block(&input[16..]));
+        output[..16].copy_from_slice(&(t0 ^ z0).to_be_bytes());
+        output[16..].copy_from_slice(&(t1 ^ z1).to_be_bytes());
+        self.update(t0, t1);
+    }
+
+    fn decrypt_block(&mut self, input: &[u8], output: &mut [u8]) {
+
+        let (z0, z1) = self.keystream();
+        let (t0, t1) = (block(&input[..16]) ^ z0, block(&input[16..]) ^ z1);
+        output[..16].copy_from_slice(&t0.to_be_bytes());
+        output[16..].copy_from_slice(&t1.to_be_bytes());
+        self.update(t0, t1);
+    }
+
+    fn decrypt_partial(&mut self, input: &[u8], output: &mut [u8]) {
+
+        let (z0, z1) = self.keystream();
+        let padded: [u8; 32] = pad_block(input);
+        let mut plaintext = [0u8; 32];
+        plaintext[..16].copy_from_slice(&(block(&padded[..16]) ^ z0).to_be_bytes());
+        plaintext[16..].copy_from_slice(&(block(&padded[16..]) ^ z1).to_be_bytes());
+
+        // only the plaintext part is absorbed, the keystream tail is zeroed
+        for byte in plaintext[input.len()..].iter_mut() {
+            *byte = 0;
+        }
+        output.copy_from_slice(&plaintext[..input.len()]);
+        self.update(block(&plaintext[..16]), block(&plaintext[16..]));
+    }
+
+    fn finalize(&mut self, ad_length: usize, message_length: usize, tag: &mut [u8]) {
+
+        let t = self.0[2] ^ length_block(ad_length, message_length);
+        for _ in 0..7 {
+            self.update(t, t);
+        }
+
+        let s = &self.0;
+        if tag.len() == 16 {
+            tag.copy_from_slice(&(s[0] ^ s[1] ^ s[2] ^ s[3] ^ s[4] ^ s[5] ^ s[6]).to_be_bytes());
+        } else {
+            tag[..16].copy_from_slice(&(s[0] ^ s[1] ^ s[2] ^ s[3]).to_be_bytes());
+            tag[16..].copy_from_slice(&(s[4] ^ s[5] ^ s[6] ^ s[7]).to_be_bytes());
+        }
+    }
+}
+
+struct Aegis256State([u128; 6]);
+
+impl Drop for Aegis256State {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.0);
+    }
+}
+
+impl Aegis256State {
+
+    fn new(key: &[u8; 32], nonce: &[u8; 32]) -> Aegis256State {
+
+        let (k0, k1) = (block(&key[..16]), block(&key[16..]));
+        let (n0, n1) = (block(&nonce[..16]), block(&nonce[16..]));
+        let mut state = Aegis256State([k0 ^ n0, k1 ^ n1, C1, C0, k0 ^ C0, k1 ^ C1]);
+
+        for _ in 0..4 {
+            state.update(k0);
+            state.update(k1);
+            state.update(k0 ^ n0);
+            state.update(k1 ^ n1);
+        }
+        state
+    }
+
+    fn update(&mut self, m: u128) {
+
+        let s = self.0;
+        self.0 = [
+            aes_round(s[5], s[0] ^ m),
+            aes_round(s[0], s[1]),
+            aes_round(s[1], s[2]),
+            aes_round(s[2], s[3]),
+            aes_round(s[3], s[4]),
+            aes_round(s[4], s[5]),
+        ];
+    }
+
+    fn keystream(&self) -> u128 {
+        let s = &self.0;
+        s[1] ^ s[4] ^ s[5] ^ (s[2] & s[3])
+    }
+}
+
+impl AegisState for Aegis256State {
+    const RATE: usize = 16;
+
+    fn absorb(&mut self, input: &[u8]) {
+        self.update(block(input));
+    }
+
+    fn encrypt_block(&mut self, input: &[u8], output: &mut [u8]) {
+
+        let z = self.keystream();
+        let t = block(input);
+        output.copy_from_slice(&(t ^ z).to_be_bytes());
+        self.update(t);
+    }
+
+    fn decrypt_block(&mut self, input: &[u8], output: &mut [u8]) {
+
+        let t = block(input) ^ self.keystream();
+        output.copy_from_slice(&t.to_be_bytes());
+        self.update(t);
+    }
+
+    fn decrypt_partial(&mut self, input: &[u8], output: &mut [u8]) {
+
+        let padded: [u8; 16] = pad_block(input);
+        let mut plaintext = (block(&padded) ^ self.keystream()).to_be_bytes();
+
+        // only the plaintext part is absorbed, the keystream tail is zeroed
+        for byte in plaintext[input.len()..].iter_mut() {
+            *byte = 0;
+        }
+        output.copy_from_slice(&plaintext[..input.len()]);
+        self.update(block(&plaintext));
+    }
+
+    fn finalize(&mut self, ad_length: usize, message_length: usize, tag: &mut [u8]) {
+
+        let t = self.0[3] ^ length_block(ad_length, message_length);
+        for _ in 0..7 {
+            self.update(t);
+        }
+
+        let s = &self.0;
+        if tag.len() == 16 {
+            tag.copy_from_slice(&(s[0] ^ s[1] ^ s[2] ^ s[3] ^ s[4] ^ s[5]).to_be_bytes());
+        } else {
+            tag[..16].copy_from_slice(&(s[0] ^ s[1] ^ s[2]).to_be_bytes());
+            tag[16..].copy_from_slice(&(s[3] ^ s[4] ^ s[5]).to_be_bytes());
+        }
+    }
+}
+
+// Function to absorb the associated data (zero padded to full blocks)
+fn absorb_ad<S: AegisState>(state: &mut S, ad: &[u8]) {
+
+    for chunk in ad.chunks(S::RATE) {
+        if chunk.len() == S::RATE {
+            state.absorb(chunk);
+        } else {
+            let padded: [u8; 32] = pad_block(chunk);
+            state.absorb(&padded[..S::RATE]);
+        }
+    }
+}
+
+fn encrypt_in_place<S: AegisState>(mut state: S, ad: &[u8], data: &mut [u8], tag: &mut [u8]) {
+
+    check_tag_length(tag);
+    absorb_ad(&mut state, ad);
+
+    for chunk in data.chunks_mut(S::RATE) {
+        let input: [u8; 32] = pad_block(chunk);
+        let mut output = [0u8; 32];
+        state.encrypt_block(&input[..S::RATE], &mut output[..S::RATE]);
+        let length = chunk.len();
+        chunk.copy_from_slice(&output[..length]);
+    }
+
+    state.finalize(ad.len(), data.len(), tag);
+}
+
+fn decrypt_in_place<S: AegisState>(mut state: S, ad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), AuthenticationError> {
+
+    check_tag_length(tag);
+    absorb_ad(&mut state, ad);
+
+    let message_length = data.len();
+    for chunk in data.chunks_mut(S::RATE) {
+        let mut output = [0u8; 32];
+        if chunk.len() == S::RATE {
+            state.decrypt_block(chunk, &mut output[..S::RATE]);
+        } else {
+            state.decrypt_partial(chunk, &mut output[..chunk.len()]);
+        }
+        let length = chunk.len();
+        chunk.copy_from_slice(&output[..length]);
+    }
+
+    let mut expected_tag = [0u8; 32];
+    state.finalize(ad.len(), message_length, &mut expected_tag[..tag.len()]);
+
+    if tags_match(&expected_tag[..tag.len()], tag) {
+        Ok(())
+    } else {
+        // do not release unauthenticated plaintext
+        data.fill(0);
+        Err(AuthenticationError)
+    }
+}
+
+// Function to encrypt a message in place with AEGIS-128L and write the
+// 128-bit or 256-bit tag
+pub fn aegis128l_encrypt(key: &[u8; 16], nonce: &[u8; 16], ad: &[u8], data: &mut [u8], tag: &mut [u8]) {
+    encrypt_in_place(Aegis128LState::new(key, nonce), ad, data, tag)
+}
+
+// Function to verify and decrypt a message in place with AEGIS-128L,
+// the buffer is zeroed if the tag does not match
+pub fn aegis128l_decrypt(key: &[u8; 16], nonce: &[u8; 16], ad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), AuthenticationError> {
+    decrypt_in_place(Aegis128LState::new(key, nonce), ad, data, tag)
+}
+
+// Function to encrypt a message in place with AEGIS-256 and write the
+// 128-bit or 256-bit tag
+pub fn aegis256_encrypt(key: &[u8; 32], nonce: &[u8; 32], ad: &[u8], data: &mut [u8], tag: &mut [u8]) {
+    encrypt_in_place(Aegis256State::new(key, nonce), ad, data, tag)
+}
+
+// Function to verify and decrypt a message in place with AEGIS-256,
+// the buffer is zeroed if the tag does not match
+pub fn aegis256_decrypt(key: &[u8; 32], nonce: &[u8; 32], ad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), AuthenticationError> {
+    decrypt_in_place(Aegis256State::new(key, nonce), ad, data, tag)
+}
+
+#[test]
+fn test_aes_round_vector() {
+
+    // AESRound test vector from draft-irtf-cfrg-aegis-aead, appendix A.1
+    let state = 0x000102030405060708090a0b0c0d0e0f;
+    let round_key = 0x101112131415161718191a1b1c1d1e1f;
+    assert_eq!(aes_round(state, round_key), 0x7a7b4e5638782546a8c0477a3b813f43);
+}
+
+#[test]
+fn test_aegis128l_vectors() {
+
+    // test vectors 1 to 3 from draft-irtf-cfrg-aegis-aead, appendix A.2
+    let key = hex::decode("10010000000000000000000000000000").unwrap().try_into().unwrap();
+    let nonce = hex::decode("10000200000000000000000000000000").unwrap().try_into().unwrap();
+
+    let mut data = [0u8; 16];
+    let mut tag = [0u8; 16];
+    let mut tag_256 = [0u8; 32];
+    aegis128l_encrypt(&key, &nonce, &[], &mut data, &mut tag);
+    assert_eq!(hex::encode(data), "c1c0e58bd913006feba00f4b3cc3594e");
+    assert_eq!(hex::encode(tag), "abe0ece80c24868a226a35d16bdae37a");
+    aegis128l_encrypt(&key, &nonce, &[], &mut [0u8; 16], &mut tag_256);
+    assert_eq!(hex::encode(tag_256), "25835bfbb21632176cf03840687cb968cace4617af1bd0f7d064c639a5c79ee4");
+    aegis128l_decrypt(&key, &nonce, &[], &mut data, &tag).unwrap();
+    assert_eq!(data, [0u8; 16]);
+
+    aegis128l_encrypt(&key, &nonce, &[], &mut [], &mut tag);
+    assert_eq!(hex::encode(tag), "c2b879a67def9d74e6c14f708bbcc9b4");
+
+    let ad: Vec<u8> = (0..8).collect();
+    let mut data: Vec<u8> = (0..32).collect();
+    aegis128l_encrypt(&key, &nonce, &ad, &mut data, &mut tag);
+    assert_eq!(hex::encode(&data), "79d94593d8c2119d7e8fd9b8fc77845c5c077a05b2528b6ac54b563aed8efe84");
+    assert_eq!(hex::encode(tag), "cc6f3372f6aa1bb82388d695c3962d9a");
+}
+
+#[test]
+fn test_aegis256_vectors() {
+
+    // test vectors 1 and 3 from draft-irtf-cfrg-aegis-aead, appendix A.3
+    let key = hex::decode("1001000000000000000000000000000000000000000000000000000000000000").unwrap().try_into().unwrap();
+    let nonce = hex::decode("1000020000000000000000000000000000000000000000000000000000000000").unwrap().try_into().unwrap();
+
+    let mut data = [0u8; 16];
+    let mut tag = [0u8; 16];
+    let mut tag_256 = [0u8; 32];
+    aegis256_encrypt(&key, &nonce, &[], &mut data, &mut tag);
+    assert_eq!(hex::encode(data), "754fc3d8c973246dcc6d741412a4b236");
+    assert_eq!(hex::encode(tag), "3fe91994768b332ed7f570a19ec5896e");
+    aegis256_encrypt(&key, &nonce, &[], &mut [0u8; 16], &mut tag_256);
+    assert_eq!(hex::encode(tag_256), "1181a1d18091082bf0266f66297d167d2e68b845f61a3b0527d31fc7b7b89f13");
+
+    let ad: Vec<u8> = (0..8).collect();
+    let mut data: Vec<u8> = (0..32).collect();
+    aegis256_encrypt(&key, &nonce, &ad, &mut data, &mut tag);
+    assert_eq!(hex::encode(&data), "f373079ed84b2709faee373584585d60accd191db310ef5d8b11833df9dec711");
+    assert_eq!(hex::encode(tag), "8d86f91ee606e9ff26a01b64ccbdd91d");
+}
+
+#[test]
+fn test_aegis_partial_blocks_and_forgery() {
+
+    let message: Vec<u8> = (0..77).collect();
+    let ad: Vec<u8> = (0..13).collect();
+
+    let mut data = message.clone();
+    let mut tag = [0u8; 32];
+    aegis128l_encrypt(&[1; 16], &[2; 16], &ad, &mut data, &mut tag);
+    let mut modified = data.clone();
+    aegis128l_decrypt(&[1; 16], &[2; 16], &ad, &mut data, &tag).unwrap();
+    assert_eq!(data, message);
+
+    // a flipped bit in the final partial block is detected and nothing is released
+    modified[76] ^= 1;
+    assert_eq!(aegis128l_decrypt(&[1; 16], &[2; 16], &ad, &mut modified, &tag), Err(AuthenticationError));
+    assert_eq!(modified, vec![0u8; 77]);
+
+    let mut data = message.clone();
+    aegis256_encrypt(&[1; 32], &[2; 32], &ad, &mut data, &mut tag[..16]);
+    aegis256_decrypt(&[1; 32], &[2; 32], &ad, &mut data, &tag[..16]).unwrap();
+    assert_eq!(data, message);
+}
@@ -0,0 +1,387 @@
+use std::ptr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::aes_bitsliced::BitslicedAes;
+use crate::aes_ctr_optimized::{encrypt_aes, encrypt_aes_x4, expand_key_words, RoundKeyWords};
+use crate::secret::wipe_value;
+
+#[cfg(target_arch = "x86_64")]
+use crate::aes_ni::{self, AesNi};
+#[cfg(target_arch = "x86_64")]
+use crate::aes_vpaes::Vpaes;
+
+// Common block-cipher interface for the AES implementations and the runtime
+// selection between them
+
+// number of blocks the CTR keystream generator hands to a backend at once
+pub const PARALLEL_BLOCKS: usize = 8;
+
+/// AES block encryption with an already expanded key
+pub trait BlockCipher: Send + Sync {
+
+    // Function to encrypt one 128-bit block (big-endian byte order)
+    fn encrypt_block(&self, block: u128) -> u128;
+
+    // Function to encrypt several independent blocks in place; backends
+    // override it to interleave the rounds of neighbouring blocks
+    fn encrypt_blocks(&self, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+        for block in blocks.iter_mut() {
+            *block = self.encrypt_block(*block);
+        }
+    }
+
+    // Function to name the implementation, e.g. for benchmarks
+    fn backend(&self) -> Backend;
+}
+
+/// Available AES implementations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    // portable T-table implementation (`encrypt_aes`)
+    TTable,
+    // x86-64 AES-NI instructions
+    AesNi,
+    // constant-time bitsliced software implementation
+    Bitsliced,
+    // constant-time vector permute implementation with x86-64 SSSE3
+    Vpaes,
+}
+
+pub const ALL_BACKENDS: [Backend; 4] = [Backend::TTable, Backend::AesNi, Backend::Bitsliced, Backend::Vpaes];
+
+// backend forced with `force_backend`, 0 for automatic selection
+static FORCED_BACKEND: AtomicU8 = AtomicU8::new(0);
+
+impl Backend {
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::TTable => "t-table",
+            Backend::AesNi => "aes-ni",
+            Backend::Bitsliced => "bitsliced",
+            Backend::Vpaes => "vpaes",
+        }
+    }
+
+    // Function to look a backend up by its name
+    pub fn from_name(name: &str) -> Option<Backend> {
+        ALL_BACKENDS.iter().cloned().find(|backend| backend.name() == name)
+    }
+
+    // Function to check whether the backend can run on this CPU
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::TTable => true,
+            Backend::AesNi => aes_ni_available(),
+            Backend::Bitsliced => true,
+            Backend::Vpaes => ssse3_available(),
+        }
+    }
+
+    // Function to pick the backend for this CPU: the hardware instructions if
+    // present, otherwise one of the constant-time software implementations,
+    // as the table lookups of the T-table implementation leak key material
+    // through cache timing
+    pub fn detect() -> Backend {
+        if Backend::AesNi.is_available() {
+            Backend::AesNi
+        } else if Backend::Vpaes.is_available() {
+            Backend::Vpaes
+        } else {
+            Backend::Bitsliced
+        }
+    }
+}
+
+// Function to make `new_default_cipher` use the given backend instead of the
+// detected one, panics if the backend is not available on this CPU
+pub fn force_backend(backend: Backend) {
+    assert!(backend.is_available(), "AES backend '{}' is not available on this CPU", backend.name());
+    let index = ALL_BACKENDS.iter().position(|b| *b == backend).unwrap();
+    FORCED_BACKEND.store(index as u8 + 1, Ordering::Relaxed);
+}
+
+// Function to get the backend used by `new_default_cipher`
+pub fn default_backend() -> Backend {
+    match FORCED_BACKEND.load(Ordering::Relaxed) {
+        0 => Backend::detect(),
+        index => ALL_BACKENDS[index as usize - 1],
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn aes_ni_available() -> bool {
+    is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse2")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn aes_ni_available() -> bool {
+    false
+}
+
+#[cfg(target_arch = "x86_64")]
+fn ssse3_available() -> bool {
+    is_x86_feature_detected!("ssse3")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn ssse3_available() -> bool {
+    false
+}
+
+/// Portable T-table implementation
+pub struct TTableAes {
+    expanded_keys: RoundKeyWords,
+    word_count: usize,
+}
+
+impl TTableAes {
+
+    pub fn new(key: &[u8]) -> TTableAes {
+        TTableAes { expanded_keys: expand_key_words(key), word_count: key.len() + 28 }
+    }
+
+    fn keys(&self) -> &[u32] {
+        &self.expanded_keys[..self.word_count]
+    }
+}
+
+impl Drop for TTableAes {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.expanded_keys);
+    }
+}
+
+impl BlockCipher for TTableAes {
+
+    fn encrypt_block(&self, block: u128) -> u128 {
+        encrypt_aes(block, self.keys())
+    }
+
+    fn encrypt_blocks(&self, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+        for quad in blocks.chunks_exact_mut(4) {
+            let output = encrypt_aes_x4([quad[0], quad[1], quad[2], quad[3]], self.keys());
+            quad.copy_from_slice(&output);
+        }
+    }
+
+    fn backend(&self) -> Backend {
+        Backend::TTable
+    }
+}
+
+/// Key schedule of one backend, computed once and then only read
+///
+/// All variants store their round keys in fixed-size arrays, so the value
+/// can be built on the stack without allocating. It can not be cloned: one
+/// schedule is shared between streams and threads only by reference (`&` or
+/// `Arc`), so there is exactly one copy of the round keys, which is wiped
+/// when dropped.
+// the bitsliced round keys are the largest, boxing them would defeat the point
+#[allow(clippy::large_enum_variant)]
+pub enum ExpandedKey {
+    TTable(TTableAes),
+    #[cfg(target_arch = "x86_64")]
+    AesNi(AesNi),
+    Bitsliced(BitslicedAes),
+    #[cfg(target_arch = "x86_64")]
+    Vpaes(Vpaes),
+}
+
+impl ExpandedKey {
+
+    // Function to expand a 128-bit, 192-bit or 256-bit key for the default backend
+    pub fn new(key: &[u8]) -> ExpandedKey {
+        ExpandedKey::with_backend(default_backend(), key)
+    }
+
+    // Function to expand a 128-bit, 192-bit or 256-bit key for the given backend,
+    // panics if the backend is not available on this CPU
+    pub fn with_backend(backend: Backend, key: &[u8]) -> ExpandedKey {
+
+        assert_aes_key_length(key.len());
+        assert!(backend.is_available(), "AES backend '{}' is not available on this CPU", backend.name());
+
+        match backend {
+            Backend::TTable => ExpandedKey::TTable(TTableAes::new(key)),
+            #[cfg(target_arch = "x86_64")]
+            Backend::AesNi => ExpandedKey::AesNi(AesNi::new(key)),
+            #[cfg(not(target_arch = "x86_64"))]
+            Backend::AesNi => unreachable!(),
+            Backend::Bitsliced => ExpandedKey::Bitsliced(BitslicedAes::new(key)),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Vpaes => ExpandedKey::Vpaes(Vpaes::new(key)),
+            #[cfg(not(target_arch = "x86_64"))]
+            Backend::Vpaes => unreachable!(),
+        }
+    }
+
+    fn cipher(&self) -> &dyn BlockCipher {
+        match self {
+            ExpandedKey::TTable(cipher) => cipher,
+            #[cfg(target_arch = "x86_64")]
+            ExpandedKey::AesNi(cipher) => cipher,
+            ExpandedKey::Bitsliced(cipher) => cipher,
+            #[cfg(target_arch = "x86_64")]
+            ExpandedKey::Vpaes(cipher) => cipher,
+        }
+    }
+}
+
+impl BlockCipher for ExpandedKey {
+
+    fn encrypt_block(&self, block: u128) -> u128 {
+        self.cipher().encrypt_block(block)
+    }
+
+    fn encrypt_blocks(&self, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+        self.cipher().encrypt_blocks(blocks)
+    }
+
+    fn backend(&self) -> Backend {
+        self.cipher().backend()
+    }
+}
+
+// Function to check for the length in bytes of a 128-bit, 192-bit or 256-bit
+// AES key, which every backend supports
+pub const fn is_aes_key_length(length: usize) -> bool {
+    matches!(length, 16 | 24 | 32)
+}
+
+// Function to panic unless the length in bytes is an AES key length
+pub(crate) fn assert_aes_key_length(length: usize) {
+    assert!(is_aes_key_length(length), "AES key has to be 128-bit, 192-bit or 256-bit");
+}
+
+// Function to encrypt a batch of blocks, every block under its own key
+//
+// If all keys are AES-NI keys, the rounds of all blocks are interleaved like
+// in `encrypt_blocks` whatever the keys. Otherwise the blocks are grouped by
+// key and every group goes through `encrypt_blocks` of its key, so vpaes and
+// the bitsliced backend still encrypt several blocks per call.
+pub(crate) fn encrypt_blocks_multi_key(keys: &[&ExpandedKey; PARALLEL_BLOCKS], blocks: &mut [u128; PARALLEL_BLOCKS]) {
+
+    if keys.iter().all(|key| ptr::eq(*key, keys[0])) {
+        return keys[0].encrypt_blocks(blocks);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let aes_ni_keys = keys.map(|key| match key {
+            ExpandedKey::AesNi(key) => Some(key),
+            _ => None,
+        });
+        if aes_ni_keys.iter().all(Option::is_some) {
+            return aes_ni::encrypt_blocks_multi_key(&aes_ni_keys.map(Option::unwrap), blocks);
+        }
+    }
+
+    let mut done = [false; PARALLEL_BLOCKS];
+    for first in 0..PARALLEL_BLOCKS {
+        if done[first] {
+            continue;
+        }
+
+        // collect the remaining blocks of this key, the unused entries of the
+        // group are encrypted too and thrown away
+        let key = keys[first];
+        let mut group = [0u128; PARALLEL_BLOCKS];
+        let mut members = [0usize; PARALLEL_BLOCKS];
+        let mut count = 0;
+        for i in first..PARALLEL_BLOCKS {
+            if !done[i] && ptr::eq(keys[i], key) {
+                group[count] = blocks[i];
+                members[count] = i;
+                done[i] = true;
+                count += 1;
+            }
+        }
+
+        if count == 1 {
+            blocks[first] = key.encrypt_block(blocks[first]);
+            continue;
+        }
+        key.encrypt_blocks(&mut group);
+        for (output, i) in group.iter().zip(members[..count].iter()) {
+            blocks[*i] = *output;
+        }
+    }
+}
+
+// Function to set up a 128-bit, 192-bit or 256-bit key for the given backend,
+// panics if the backend is not available on this CPU
+pub fn new_cipher(backend: Backend, key: &[u8]) -> Box<dyn BlockCipher> {
+    Box::new(ExpandedKey::with_backend(backend, key))
+}
+
+// Function to set up a key for the default backend
+pub fn new_default_cipher(key: &[u8]) -> Box<dyn BlockCipher> {
+    new_cipher(default_backend(), key)
+}
+
+#[cfg(test)]
+fn available_backends() -> Vec<Backend> {
+    ALL_BACKENDS.iter().cloned().filter(|backend| backend.is_available()).collect()
+}
+
+#[test]
+fn test_all_backends_known_answers() {
+
+    // FIPS-197 appendix C.1 to C.3, NIST SP 800-38A F.1.1, F.1.3 and F.1.5
+    let vectors = [
+        ("000102030405060708090a0b0c0d0e0f", "00112233445566778899aabbccddeeff", "69c4e0d86a7b0430d8cdb78070b4c55a"),
+        ("000102030405060708090a0b0c0d0e0f1011121314151617",
+         "00112233445566778899aabbccddeeff", "dda97ca4864cdfe06eaf70a0ec0d7191"),
+        ("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+         "00112233445566778899aabbccddeeff", "8ea2b7ca516745bfeafc49904b496089"),
+        ("2b7e151628aed2a6abf7158809cf4f3c", "6bc1bee22e409f96e93d7e117393172a", "3ad77bb40d7a3660a89ecaf32466ef97"),
+        ("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+         "6bc1bee22e409f96e93d7e117393172a", "f3eed1bdb5d2a03c064b5a7e3db181f8"),
+        ("8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+         "6bc1bee22e409f96e93d7e117393172a", "bd334f1d6e45f25ff712a214571fa5cc"),
+    ];
+
+    for backend in available_backends() {
+        for (key, plaintext, ciphertext) in vectors.iter() {
+            let cipher = new_cipher(backend, &hex::decode(key).unwrap());
+            let block = u128::from_str_radix(plaintext, 16).unwrap();
+            assert_eq!(format!("{:032x}", cipher.encrypt_block(block)), *ciphertext, "backend {}", backend.name());
+            assert_eq!(cipher.backend(), backend);
+        }
+    }
+}
+
+#[test]
+fn test_all_backends_agree() {
+
+    let mut value = 0x0123456789abcdef0011223344556677u128;
+
+    for key_length in [16, 24, 32].iter() {
+        let key: Vec<u8> = (0..*key_length).map(|i| (i * 17 + 3) as u8).collect();
+        let reference = new_cipher(Backend::TTable, &key);
+        let ciphers: Vec<_> = available_backends().into_iter().map(|backend| new_cipher(backend, &key)).collect();
+
+        for _ in 0..1000 {
+            let expected = reference.encrypt_block(value);
+            for cipher in ciphers.iter() {
+                assert_eq!(cipher.encrypt_block(value), expected, "backend {}", cipher.backend().name());
+            }
+            value = expected ^ (value >> 3);
+        }
+
+        // multi-block path against the single block path of the reference
+        let mut blocks = [0u128; PARALLEL_BLOCKS];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = value.wrapping_add(i as u128);
+        }
+        for cipher in ciphers.iter() {
+            let mut encrypted = blocks;
+            cipher.encrypt_blocks(&mut encrypted);
+            for (block, output) in blocks.iter().zip(encrypted.iter()) {
+                assert_eq!(*output, reference.encrypt_block(*block), "backend {}", cipher.backend().name());
+            }
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use crate::aes_backend::{Backend, BlockCipher, PARALLEL_BLOCKS};
+use crate::secret::wipe_value;
+
+// Constant-time bitsliced AES
+//
+// Four blocks are processed at once as eight 64-bit bit planes: plane b holds
+// bit b of all 64 state bytes. The bit of byte (row r, column c) of block k
+// sits at position 16 * r + 4 * c + k, so ShiftRows becomes a rotation inside
+// each 16-bit row and MixColumns a rotation of the whole plane by whole rows.
+// SubBytes is evaluated as a boolean circuit (Boyar-Peralta), so there are no
+// table lookups or branches that depend on key or data, including during the
+// key expansion.
+
+type State = [u64; 8];
+
+const BLOCKS: usize = 4;
+
+// Function to evaluate the AES S-box on all 64 bytes of a bitsliced state
+fn sub_bytes(state: &mut State) {
+
+    // circuit by Boyar and Peralta, u0 / s0 being the most significant bit
+    let u7 = state[0];
+    let u6 = state[1];
+    let u5 = state[2];
+    let u4 = state[3];
+    let u3 = state[4];
+    let u2 = state[5];
+    let u1 = state[6];
+    let u0 = state[7];
+
+    // top linear layer
+    let y14 = u3 ^ u5;
+    let y13 = u0 ^ u6;
+    let y12 = y13 ^ y14;
+    let t1 = u4 ^ y12;
+    let y15 = t1 ^ u5;
+    let t2 = y12 & y15;
+    let y6 = y15 ^ u7;
+    let y20 = t1 ^ u1;
+    let y9 = u0 ^ u3;
+    let y11 = y20 ^ y9;
+    let t12 = y9 & y11;
+    let y7 = u7 ^ y11;
+    let y8 = u0 ^ u5;
+    let t0 = u1 ^ u2;
+    let y10 = y15 ^ t0;
+    let y17 = y10 ^ y11;
+    let t13 = y14 & y17;
+    let t14 = t13 ^ t12;
+    let y19 = y10 ^ y8;
+    let t15 = y8 & y10;
+    let t16 = t15 ^ t12;
+    let y16 = t0 ^ y11;
+    let y21 = y13 ^ y16;
+    let t7 = y13 & y16;
+    let y18 = u0 ^ y16;
+    let y1 = t0 ^ u7;
+    let y4 = y1 ^ u3;
+    let t5 = y4 & u7;
+    let t6 = t5 ^ t2;
+    let t18 = t6 ^ t16;
+    let t22 = t18 ^ y19;
+    let y2 = y1 ^ u0;
+    let t10 = y2 & y7;
+    let t11 = t10 ^ t7;
+    let t20 = t11 ^ t16;
+    let t24 = t20 ^ y18;
+    let y5 = y1 ^ u6;
+    let t8 = y5 & y1;
+    let t9 = t8 ^ t7;
+    let t19 = t9 ^ t14;
+    let t23 = t19 ^ y21;
+    let y3 = y5 ^ y8;
+    let t3 = y3 & y6;
+    let t4 = t3 ^ t2;
+    let t17 = t4 ^ y20;
+    let t21 = t17 ^ t14;
+
+    // inversion in GF(2^4)
+    let t26 = t21 & t23;
+    let t27 = t24 ^ t26;
+    let t31 = t22 ^ t26;
+    let t25 = t21 ^ t22;
+    let t28 = t25 & t27;
+    let t29 = t28 ^ t22;
+    let t30 = t23 ^ t24;
+    let t32 = t31 & t30;
+    let t33 = t32 ^ t24;
+    let t35 = t27 ^ t33;
+    let t36 = t24 & t35;
+    let t38 = t27 ^ t36;
+    let t39 = t29 & t38;
+    let t40 = t25 ^ t39;
+    let t43 = t29 ^ t40;
+    let t34 = t23 ^ t33;
+    let t37 = t36 ^ t34;
+    let t41 = t40 ^ t37;
+    let t44 = t33 ^ t37;
+    let t42 = t29 ^ t33;
+    let t45 = t42 ^ t41;
+
+    // multiplications
+    let z0 = t44 & y15;
+    let z1 = t37 & y6;
+    let z2 = t33 & u7;
+    let z3 = t43 & y16;
+    let z4 = t40 & y1;
+    let z5 = t29 & y7;
+    let z6 = t42 & y11;
+    let z7 = t45 & y17;
+    let z8 = t41 & y10;
+    let z9 = t44 & y12;
+    let z10 = t37 & y3;
+    let z11 = t33 & y4;
+    let z12 = t43 & y13;
+    let z13 = t40 & y5;
+    let z14 = t29 & y2;
+    let z15 = t42 & y9;
+    let z16 = t45 & y14;
+    let z17 = t41 & y8;
+
+    // bottom linear layer
+    let tc12 = z3 ^ z5;
+    let tc6 = z3 ^ z4;
+    let tc5 = z1 ^ z0;
+    let tc11 = tc6 ^ tc5;
+    let tc8 = z7 ^ tc6;
+    let tc16 = z6 ^ tc8;
+    let tc20 = z15 ^ tc16;
+    let tc1 = z15 ^ z16;
+    let tc2 = z10 ^ tc1;
+    let tc21 = tc2 ^ z11;
+    let tc3 = z9 ^ tc2;
+    let s0 = tc3 ^ tc16;
+    let s3 = tc3 ^ tc11;
+    let s1 = s3 ^ tc16;
+    let tc13 = z13 ^ tc1;
+    let tc4 = z0 ^ z2;
+    let tc7 = z12 ^ tc4;
+    let tc9 = z8 ^ tc7;
+    let tc10 = tc8 ^ tc9;
+    let tc17 = z14 ^ tc10;
+    let s5 = tc21 ^ tc17;
+    let tc26 = tc17 ^ tc20;
+    let s2 = tc26 ^ z17;
+    let tc14 = tc4 ^ tc12;
+    let tc18 = tc13 ^ tc14;
+    let s6 = tc10 ^ tc18;
+    let s7 = z12 ^ tc18;
+    let s4 = tc14 ^ s3;
+
+    // the circuit omits the NOTs of the affine constant 0x63
+    state[0] = !s7;
+    state[1] = !s6;
+    state[2] = s5;
+    state[3] = s4;
+    state[4] = s3;
+    state[5] = !s2;
+    state[6] = !s1;
+    state[7] = s0;
+}
+
+// Function to rotate each 16-bit row of a plane by whole columns
+fn shift_rows(state: &mut State) {
+
+    for plane in state.iter_mut() {
+        let mut shifted = *plane & 0xffff;
+        for row in 1..4 {
+            let bits = (*plane >> (16 * row)) as u16;
+            shifted |= (bits.rotate_right(4 * row) as u64) << (16 * row);
+        }
+        *plane = shifted;
+    }
+}
+
+fn mix_columns(state: &mut State) {
+
+    // row r + 1, r + 2 and r + 3 of every column, moved to row r
+    let rotate_rows = |plane: u64, rows: u32| plane.rotate_right(16 * rows);
+
+    let mut sum = [0u64; 8];
+    let mut rest = [0u64; 8];
+    for (b, plane) in state.iter().enumerate() {
+        sum[b] = plane ^ rotate_rows(*plane, 1);
+        rest[b] = rotate_rows(*plane, 1) ^ rotate_rows(*plane, 2) ^ rotate_rows(*plane, 3);
+    }
+
+    // 2 * (a_r ^ a_(r+1)), reduction by x^8 + x^4 + x^3 + x + 1
+    let top = sum[7];
+    state[7] = sum[6] ^ rest[7];
+    state[6] = sum[5] ^ rest[6];
+    state[5] = sum[4] ^ rest[5];
+    state[4] = sum[3] ^ top ^ rest[4];
+    state[3] = sum[2] ^ top ^ rest[3];
+    state[2] = sum[1] ^ rest[2];
+    state[1] = sum[0] ^ top ^ rest[1];
+    state[0] = top ^ rest[0];
+}
+
+fn add_round_key(state: &mut State, round_key: &State) {
+    for (plane, key) in state.iter_mut().zip(round_key.iter()) {
+        *plane ^= key;
+    }
+}
+
+// Function to transpose up to four blocks into bit planes
+fn pack(blocks: &[u128]) -> State {
+
+    let mut state = [0u64; 8];
+    for (k, block) in blocks.iter().enumerate() {
+        for (i, byte) in block.to_be_bytes().iter().enumerate() {
+            let position = 16 * (i % 4) + 4 * (i / 4) + k;
+            for (b, plane) in state.iter_mut().enumerate() {
+                *plane |= (((*byte >> b) & 1) as u64) << position;
+            }
+        }
+    }
+    state
+}
+
+fn unpack(state: &State, blocks: &mut [u128]) {
+
+    for (k, block) in blocks.iter_mut().enumerate() {
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let position = 16 * (i % 4) + 4 * (i / 4) + k;
+            for (b, plane) in state.iter().enumerate() {
+                *byte |= (((plane >> position) & 1) as u8) << b;
+            }
+        }
+        *block = u128::from_be_bytes(bytes);
+    }
+}
+
+// Function to apply the S-box to the four bytes of a key schedule word
+fn sub_word(word: u32) -> u32 {
+
+    let mut state = [0u64; 8];
+    for (i, byte) in word.to_be_bytes().iter().enumerate() {
+        for (b, plane) in state.iter_mut().enumerate() {
+            *plane |= (((*byte >> b) & 1) as u64) << i;
+        }
+    }
+
+    sub_bytes(&mut state);
+
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        for (b, plane) in state.iter().enumerate() {
+            *byte |= (((plane >> i) & 1) as u8) << b;
+        }
+    }
+    u32::from_be_bytes(bytes)
+}
+
+// Function to expand a 128-bit, 192-bit or 256-bit key without table lookups
+fn key_expansion(key: &[u8]) -> [u32; 60] {
+
+    const RCON: [u32; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+    let n = key.len() / 4;
+    let mut words = [0u32; 60];
+    for (word, bytes) in words.iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    for i in n..4 * (n + 7) {
+        let mut temp = words[i - 1];
+        if i % n == 0 {
+            temp = sub_word(temp.rotate_left(8)) ^ (RCON[i / n - 1] << 24);
+        } else if n > 6 && i % n == 4 {
+            temp = sub_word(temp);
+        }
+        words[i] = words[i - n] ^ temp;
+    }
+    words
+}
+
+/// Constant-time bitsliced implementation
+pub struct BitslicedAes {
+    round_keys: [State; 15],
+    rounds: usize,
+}
+
+impl BitslicedAes {
+
+    pub fn new(key: &[u8]) -> BitslicedAes {
+
+        // every round key is replicated into all four block lanes
+        let mut words = key_expansion(key);
+        let mut round_keys = [[0u64; 8]; 15];
+        for (round_key, k) in round_keys.iter_mut().zip(words.chunks_exact(4)) {
+            let key_block = (k[0] as u128) << 96 | (k[1] as u128) << 64 | (k[2] as u128) << 32 | k[3] as u128;
+            *round_key = pack(&[key_block; BLOCKS]);
+        }
+        wipe_value(&mut words);
+
+        BitslicedAes { round_keys, rounds: key.len() / 4 + 6 }
+    }
+
+    // Function to encrypt up to four blocks in parallel
+    fn encrypt_x4(&self, blocks: &mut [u128]) {
+
+        let mut state = pack(blocks);
+        let last_round = self.rounds;
+
+        add_round_key(&mut state, &self.round_keys[0]);
+        for round_key in self.round_keys[1..last_round].iter() {
+            sub_bytes(&mut state);
+            shift_rows(&mut state);
+            mix_columns(&mut state);
+            add_round_key(&mut state, round_key);
+        }
+        sub_bytes(&mut state);
+        shift_rows(&mut state);
+        add_round_key(&mut state, &self.round_keys[last_round]);
+
+        unpack(&state, blocks);
+    }
+}
+
+impl Drop for BitslicedAes {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.round_keys);
+    }
+}
+
+impl BlockCipher for BitslicedAes {
+
+    fn encrypt_block(&self, block: u128) -> u128 {
+        let mut blocks = [block];
+        self.encrypt_x4(&mut blocks);
+        blocks[0]
+    }
+
+    fn encrypt_blocks(&self, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+        for quad in blocks.chunks_exact_mut(BLOCKS) {
+            self.encrypt_x4(quad);
+        }
+    }
+
+    fn backend(&self) -> Backend {
+        Backend::Bitsliced
+    }
+}
+
+#[test]
+fn test_bitsliced_sbox_matches_table() {
+
+    // all 256 inputs, 64 at a time
+    for chunk in 0..4 {
+        let mut state = [0u64; 8];
+        for j in 0..64 {
+            let value = (chunk * 64 + j) as u8;
+            for (b, plane) in state.iter_mut().enumerate() {
+                *plane |= (((value >> b) & 1) as u64) << j;
+            }
+        }
+
+        sub_bytes(&mut state);
+
+        for j in 0..64 {
+            let mut output = 0u8;
+            for (b, plane) in state.iter().enumerate() {
+                output |= (((plane >> j) & 1) as u8) << b;
+            }
+            assert_eq!(output, crate::aes_tables::SUBSTITUTION[chunk * 64 + j]);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::aes_backend::{encrypt_blocks_multi_key, ExpandedKey, PARALLEL_BLOCKS};
+use crate::aes_ctr_optimized::xor_in_place;
+
+// Batch AES-CTR for many short independent messages
+//
+// Short messages alone rarely fill the PARALLEL_BLOCKS counters a backend
+// encrypts at once. Jobs therefore share batches: the counter blocks of
+// several messages are collected until a batch is full, encrypted together
+// and the keystream is scattered back. The blocks of one batch may belong to
+// different keys, AES-NI interleaves their rounds all the same and the other
+// backends encrypt the blocks of every key of the batch together.
+
+/// One message to encrypt or decrypt in place
+pub struct CtrJob<'a> {
+    pub key: &'a ExpandedKey,
+    pub iv: [u8; 16],
+    pub data: &'a mut [u8],
+}
+
+// Function to apply the keystream to every job, with the same result as an
+// `AesCtr` per job
+pub fn apply_keystream_batch(jobs: &mut [CtrJob]) {
+
+    let first_key = match jobs.first() {
+        Some(job) => job.key,
+        None => return,
+    };
+
+    let mut blocks = [0u128; PARALLEL_BLOCKS];
+    // key, job index and block index within the job of every batch entry
+    let mut keys = [first_key; PARALLEL_BLOCKS];
+    let mut owners = [(0usize, 0usize); PARALLEL_BLOCKS];
+    let mut filled = 0;
+
+    for job_index in 0..jobs.len() {
+        let key = jobs[job_index].key;
+        let counter = u128::from_be_bytes(jobs[job_index].iv);
+        for block_index in 0..jobs[job_index].data.len().div_ceil(16) {
+            blocks[filled] = counter.wrapping_add(block_index as u128);
+            keys[filled] = key;
+            owners[filled] = (job_index, block_index);
+            filled += 1;
+
+            if filled == PARALLEL_BLOCKS {
+                scatter_keystream(&keys, &mut blocks, &owners[..filled], jobs);
+                filled = 0;
+            }
+        }
+    }
+
+    if filled > 0 {
+        // the unused entries repeat a key of the batch, so a batch of one key
+        // stays one
+        let batch_key = keys[0];
+        keys[filled..].fill(batch_key);
+        scatter_keystream(&keys, &mut blocks, &owners[..filled], jobs);
+    }
+}
+
+// Function to encrypt a batch of counters and xor every keystream block onto
+// the message block it belongs to
+fn scatter_keystream(keys: &[&ExpandedKey; PARALLEL_BLOCKS], blocks: &mut [u128; PARALLEL_BLOCKS],
+                     owners: &[(usize, usize)], jobs: &mut [CtrJob]) {
+
+    encrypt_blocks_multi_key(keys, blocks);
+
+    for (block, (job_index, block_index)) in blocks.iter().zip(owners.iter()) {
+        let data = &mut *jobs[*job_index].data;
+        let end = data.len().min(block_index * 16 + 16);
+        let chunk = &mut data[block_index * 16..end];
+        xor_in_place(chunk, &block.to_be_bytes()[..chunk.len()]);
+    }
+}
+
+#[test]
+fn test_batch_matches_single_messages() {
+
+    use crate::aes_backend::Backend;
+    use crate::aes_ctr_optimized::AesCtr;
+
+    // keys of all sizes on the default backend, and one on the T-table
+    // backend which can not share a batch with the others
+    let keys = [ExpandedKey::new(&[0x11; 16]), ExpandedKey::new(&[0x22; 32]), ExpandedKey::new(&[0x33; 24]),
+                ExpandedKey::with_backend(Backend::TTable, &[0x44; 16])];
+
+    // lengths around the block and batch sizes, keys switching within
+    // batches, counters close to the wrap-around
+    let lengths = [0, 1, 16, 17, 3, 128, 129, 5, 0, 200, 31, 64];
+    let mut messages: Vec<Vec<u8>> = lengths.iter().map(|length| (0..*length).map(|i| i as u8).collect()).collect();
+    let mut expected = messages.clone();
+    let ivs: Vec<[u8; 16]> = (0..lengths.len()).map(|i| {
+        let mut iv = [0xff; 16];
+        iv[0] = i as u8;
+        iv[15] = 0xfe;
+        iv
+    }).collect();
+    let key_of = |i: usize| &keys[if i < 9 { i % 3 } else { 3 }];
+
+    for (i, message) in expected.iter_mut().enumerate() {
+        AesCtr::new(key_of(i), &ivs[i]).apply_keystream(message);
+    }
+
+    let mut jobs: Vec<CtrJob> = messages.iter_mut().enumerate()
+                                        .map(|(i, data)| CtrJob { key: key_of(i), iv: ivs[i], data })
+                                        .collect();
+    apply_keystream_batch(&mut jobs);
+
+    assert_eq!(messages, expected);
+}
+
+#[test]
+fn test_batch_with_mixed_keys_on_software_backends() {
+
+    use crate::aes_backend::Backend;
+    use crate::aes_ctr_optimized::AesCtr;
+
+    // the backends used without AES-NI, every batch mixes three keys
+    for backend in [Backend::Vpaes, Backend::Bitsliced, Backend::TTable].iter().filter(|backend| backend.is_available()) {
+        let keys = [ExpandedKey::with_backend(*backend, &[0x55; 16]), ExpandedKey::with_backend(*backend, &[0x66; 24]),
+                    ExpandedKey::with_backend(*backend, &[0x77; 32])];
+
+        let lengths = [16, 48, 16, 32, 80, 1, 16, 100, 7];
+        let mut messages: Vec<Vec<u8>> = lengths.iter().map(|length| (0..*length).map(|i| (i * 3) as u8).collect()).collect();
+        let mut expected = messages.clone();
+        let iv_of = |i: usize| [i as u8; 16];
+
+        for (i, message) in expected.iter_mut().enumerate() {
+            AesCtr::new(&keys[i % 3], &iv_of(i)).apply_keystream(message);
+        }
+
+        let mut jobs: Vec<CtrJob> = messages.iter_mut().enumerate()
+                                            .map(|(i, data)| CtrJob { key: &keys[i % 3], iv: iv_of(i), data })
+                                            .collect();
+        apply_keystream_batch(&mut jobs);
+
+        assert_eq!(messages, expected, "backend {}", backend.name());
+    }
+}
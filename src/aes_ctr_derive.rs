@@ -0,0 +1,134 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::aes_backend::assert_aes_key_length;
+use crate::aes_ctr_optimized::CtrFileError;
+use crate::header_file::{self, apply_header_keystream};
+use crate::kdf::hkdf_sha256;
+use crate::secret::SecretBytes;
+
+// AES-CTR with a per-file key and IV derived from a master key
+//
+// File layout:
+//
+//     magic (8 bytes) || salt (32 bytes) || ciphertext
+//
+// Key and IV are HKDF-SHA-256 of the master key with the random salt and an
+// optional context string (e.g. the file path) as info. The context is not
+// stored, decryption has to supply the same one. The AES key has the length
+// of the master key. As with plain AES-CTR there is no integrity protection.
+
+pub const SALT_LENGTH: usize = 32;
+
+const MAGIC: &[u8; 8] = b"AESCTRHK";
+const INFO_LABEL: &[u8] = b"aes-ctr per-file key and iv";
+
+pub use crate::header_file::InvalidHeader;
+
+const INVALID_HEADER: InvalidHeader = InvalidHeader("key derivation");
+
+// Function to derive the AES key (as long as the master key) and the IV for
+// one file
+pub fn derive_file_key(master_key: &[u8], salt: &[u8], context: &[u8]) -> (SecretBytes, [u8; 16]) {
+
+    assert_aes_key_length(master_key.len());
+
+    let mut info = INFO_LABEL.to_vec();
+    info.extend_from_slice(context);
+
+    let mut derived = SecretBytes::zeroed(master_key.len() + 16);
+    hkdf_sha256(salt, master_key, &info, derived.as_mut_slice());
+
+    let key = SecretBytes::from_slice(&derived.as_slice()[..master_key.len()]);
+    let mut iv = [0u8; 16];
+    iv.copy_from_slice(&derived.as_slice()[master_key.len()..]);
+    (key, iv)
+}
+
+// Function to encrypt from a reader to a writer under a fresh random salt,
+// header first
+pub fn encrypt<R: Read, W: Write>(master_key: &[u8], context: &[u8], reader: &mut R, writer: &mut W,
+                                  thread_count: usize) -> io::Result<()> {
+
+    let mut salt = [0u8; SALT_LENGTH];
+    getrandom::getrandom(&mut salt).map_err(|e| io::Error::other(e.to_string()))?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&salt)?;
+    apply_derived_keystream(master_key, &salt, context, reader, writer, thread_count)
+}
+
+// Function to read the header from a reader and decrypt the rest into a writer
+pub fn decrypt<R: Read, W: Write>(master_key: &[u8], context: &[u8], reader: &mut R, writer: &mut W,
+                                  thread_count: usize) -> Result<(), CtrFileError> {
+
+    let salt = read_header(reader)?;
+    apply_derived_keystream(master_key, &salt, context, reader, writer, thread_count)?;
+    Ok(())
+}
+
+// Function to read magic and salt
+fn read_header<R: Read>(reader: &mut R) -> Result<[u8; SALT_LENGTH], CtrFileError> {
+
+    let mut magic = [0u8; 8];
+    let mut salt = [0u8; SALT_LENGTH];
+    reader.read_exact(&mut magic).map_err(|_| INVALID_HEADER)?;
+    if &magic != MAGIC {
+        return Err(INVALID_HEADER.into());
+    }
+    reader.read_exact(&mut salt).map_err(|_| INVALID_HEADER)?;
+    Ok(salt)
+}
+
+fn apply_derived_keystream<R: Read, W: Write>(master_key: &[u8], salt: &[u8], context: &[u8],
+                                              reader: &mut R, writer: &mut W, thread_count: usize) -> io::Result<()> {
+
+    let (key, iv) = derive_file_key(master_key, salt, context);
+    apply_header_keystream(key, iv, reader, writer, thread_count)
+}
+
+// Function to encrypt a file with its own derived key and IV
+pub fn encrypt_file(master_key: &[u8], context: &[u8], input_file_path: &Path, output_file_path: &Path,
+                    thread_count: usize) -> io::Result<()> {
+    header_file::encrypt_file(input_file_path, output_file_path,
+                              |reader, writer| encrypt(master_key, context, reader, writer, thread_count))
+}
+
+// Function to decrypt a file written by `encrypt_file`, the output file is
+// only created once the header was read successfully
+pub fn decrypt_file(master_key: &[u8], context: &[u8], input_file_path: &Path, output_file_path: &Path,
+                    thread_count: usize) -> Result<(), CtrFileError> {
+    header_file::decrypt_file(input_file_path, output_file_path, read_header, |salt, reader, writer| {
+        apply_derived_keystream(master_key, &salt, context, reader, writer, thread_count)
+    })
+}
+
+#[test]
+fn test_derived_keys_are_unique_per_file() {
+
+    use crate::header_file::check_round_trip;
+
+    let master_key = [0x42u8; 32];
+    let plaintext: Vec<u8> = (0..1000u32).map(|i| (i * 13) as u8).collect();
+
+    // the context is part of the derivation, so a wrong one does not decrypt
+    let first = check_round_trip(&plaintext, 8 + SALT_LENGTH,
+                                 |reader, writer| encrypt(&master_key, b"a.txt", reader, writer, 2),
+                                 |context, reader, writer| decrypt(&master_key, context, reader, writer, 1),
+                                 b"a.txt", b"b.txt");
+
+    // same master key and plaintext, but fresh salts give unrelated ciphertexts
+    let mut second = Vec::new();
+    encrypt(&master_key, b"a.txt", &mut &plaintext[..], &mut second, 1).unwrap();
+    assert_ne!(first[8 + SALT_LENGTH..], second[8 + SALT_LENGTH..]);
+
+    let (key, iv) = derive_file_key(&master_key[..16], &[1; SALT_LENGTH], b"");
+    let (other_key, other_iv) = derive_file_key(&master_key[..16], &[1; SALT_LENGTH], b"x");
+    assert_eq!(key.len(), 16);
+    assert!(key.as_slice() != other_key.as_slice() && iv != other_iv);
+    assert_eq!(derive_file_key(&master_key[..24], &[1; SALT_LENGTH], b"").0.len(), 24);
+
+    assert!(matches!(decrypt(&master_key, b"", &mut &b"AESCTRPW"[..], &mut Vec::new(), 1),
+                     Err(CtrFileError::InvalidHeader(INVALID_HEADER))));
+}
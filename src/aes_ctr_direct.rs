@@ -0,0 +1,199 @@
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::aes_backend::BlockCipher;
+use crate::aes_ctr_optimized::{apply_keystream_parallel, keystream_workers, read_full, ALIGNED_STORED_IV_HEADER_LENGTH};
+use crate::secret::wipe;
+
+// AES-CTR with O_DIRECT file I/O on Linux
+//
+// Both files bypass the page cache, so encrypting huge files does not evict
+// the cached data of other processes. Direct I/O needs buffers, file offsets
+// and transfer sizes aligned to the logical block size of the device. The
+// last block of the output is written padded to the alignment and the file is
+// truncated to the real length afterwards.
+
+// alignment of buffers and transfers, covers every common logical block size
+pub const DIRECT_ALIGNMENT: usize = 4096;
+
+// the padded stored IV header keeps the ciphertext aligned
+const _: () = assert!(ALIGNED_STORED_IV_HEADER_LENGTH.is_multiple_of(DIRECT_ALIGNMENT));
+
+// minimum buffer size, direct transfers are only fast when large
+pub const DIRECT_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+// Heap buffer with the start address aligned to DIRECT_ALIGNMENT
+struct AlignedBuffer {
+    pointer: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+
+    fn new(size: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(size, DIRECT_ALIGNMENT).unwrap();
+        let pointer = unsafe { alloc::alloc_zeroed(layout) };
+        if pointer.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { pointer, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pointer, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.pointer, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+
+    fn drop(&mut self) {
+        wipe(&mut self[..]);
+        unsafe { alloc::dealloc(self.pointer, self.layout) }
+    }
+}
+
+// Function to open a file for direct I/O with the given options
+fn open_direct(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    options.custom_flags(libc::O_DIRECT).open(path)
+}
+
+// Function to apply the keystream to at most `max_length` bytes from one file,
+// starting at `input_offset`, and write them to the other after the header
+// with direct I/O, returns the counter of the next unused keystream block
+//
+// Offset and header length have to be multiples of DIRECT_ALIGNMENT. Fails with `ErrorKind::InvalidInput` before anything is written to the output
+// if the filesystem does not support O_DIRECT, so the caller can fall back to
+// buffered I/O. Errors of later transfers are returned as well.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn apply_keystream_direct(cipher: &dyn BlockCipher,
+                                     mut counter: u128,
+                                     input_file_path: &Path,
+                                     input_offset: u64,
+                                     header: &[u8],
+                                     output_file_path: &Path,
+                                     max_length: u64,
+                                     buffer_size: usize,
+                                     thread_count: usize) -> io::Result<u128> {
+
+    assert!(input_offset.is_multiple_of(DIRECT_ALIGNMENT as u64) && header.len().is_multiple_of(DIRECT_ALIGNMENT),
+            "direct I/O needs aligned offsets");
+    let buffer_size = buffer_size.max(DIRECT_BUFFER_SIZE).max(header.len()).next_multiple_of(DIRECT_ALIGNMENT);
+
+    let mut input_file = open_direct(OpenOptions::new().read(true), input_file_path)?;
+    let mut output_file = open_direct(OpenOptions::new().write(true).create(true).truncate(true),
+                                      output_file_path)?;
+
+    let mut buffer = AlignedBuffer::new(buffer_size);
+    let workers = keystream_workers(thread_count);
+    let mut length: u64 = 0;
+
+    // the header is the first transfer, the input is read after its own header
+    if !header.is_empty() {
+        buffer[..header.len()].copy_from_slice(header);
+        output_file.write_all(&buffer[..header.len()])?;
+    }
+    input_file.seek(SeekFrom::Start(input_offset))?;
+
+    loop {
+
+        // read a full buffer, some filesystems accept the flag but reject the read
+        let read_count = (max_length - length).min(read_full(&mut input_file, &mut buffer)? as u64) as usize;
+
+        counter = apply_keystream_parallel(cipher, counter, &mut buffer[..read_count], &workers);
+
+        // the unaligned end of the last buffer is written padded with zeros
+        let write_count = read_count.next_multiple_of(DIRECT_ALIGNMENT);
+        buffer[read_count..write_count].fill(0);
+        output_file.write_all(&buffer[..write_count])?;
+        length += read_count as u64;
+
+        // end loop if end of file or of the allowed length
+        if read_count < buffer.len() || length == max_length {
+            break;
+        }
+    }
+
+    // cut off the padding of the last block
+    output_file.set_len(header.len() as u64 + length)?;
+    output_file.sync_all()?;
+
+    Ok(counter)
+}
+
+// environment variable that lets `test_direct_matches_streaming` pass on
+// filesystems without O_DIRECT
+#[cfg(test)]
+const SKIP_DIRECT_TEST_VARIABLE: &str = "AES_CTR_SKIP_DIRECT_IO_TEST";
+
+#[test]
+fn test_direct_matches_streaming() {
+
+    use crate::test_dir::TestDir;
+
+    // the target directory usually is on a disk filesystem, unlike /tmp
+    let directory = TestDir::new_in(&std::env::current_dir().unwrap().join("target"), "direct");
+    let input = directory.join("input");
+    let output = directory.join("output");
+
+    let key: Vec<u8> = (0..32).collect();
+    let cipher = crate::aes_backend::new_default_cipher(&key);
+    let start = u128::MAX - 7;
+
+    for length in [0, 1, 4095, 4096, DIRECT_BUFFER_SIZE + 17].iter() {
+        let data: Vec<u8> = (0..*length).map(|i| (i * 11) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        let mut expected = data.clone();
+        let next = crate::aes_ctr_optimized::apply_keystream(cipher.as_ref(), start, &mut expected);
+
+        match apply_keystream_direct(cipher.as_ref(), start, &input, 0, &[], &output, u64::MAX, 0, 2) {
+            Ok(counter) => {
+                assert_eq!(counter, next);
+                assert_eq!(std::fs::read(&output).unwrap(), expected, "{} bytes", length);
+            }
+            // the filesystem decides once, at the first (empty) file; without
+            // O_DIRECT the test fails unless the skip was asked for explicitly
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput && *length == 0 => {
+                assert!(std::env::var_os(SKIP_DIRECT_TEST_VARIABLE).is_some(),
+                        "{} does not support O_DIRECT ({}), set {}=1 to skip this test",
+                        directory.path().display(), e, SKIP_DIRECT_TEST_VARIABLE);
+                eprintln!("skipping test_direct_matches_streaming: {} does not support O_DIRECT ({})",
+                          directory.path().display(), e);
+                return;
+            }
+            Err(e) => panic!("direct I/O failed for {} bytes: {}", length, e),
+        }
+    }
+
+    // input beyond the maximum length is neither read nor written
+    let mut expected: Vec<u8> = (0..5000u32).map(|i| (i * 11) as u8).collect();
+    crate::aes_ctr_optimized::apply_keystream(cipher.as_ref(), start, &mut expected);
+    apply_keystream_direct(cipher.as_ref(), start, &input, 0, &[], &output, 5000, 0, 2).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), expected);
+
+    // an aligned header is skipped in the input and another one written in
+    // front of the output
+    let mut with_header = vec![0x55u8; DIRECT_ALIGNMENT];
+    with_header.extend_from_slice(&std::fs::read(&input).unwrap()[..5000]);
+    std::fs::write(&input, &with_header).unwrap();
+    let header = [0xaau8; DIRECT_ALIGNMENT];
+    apply_keystream_direct(cipher.as_ref(), start, &input, DIRECT_ALIGNMENT as u64, &header, &output, u64::MAX, 0, 2).unwrap();
+    let written = std::fs::read(&output).unwrap();
+    assert_eq!(written[..DIRECT_ALIGNMENT], header);
+    assert_eq!(written[DIRECT_ALIGNMENT..], expected[..]);
+}
@@ -0,0 +1,395 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::aes_backend::{assert_aes_key_length, new_default_cipher, BlockCipher};
+use crate::aes_ctr_optimized::{apply_keystream, read_full, CtrFileError};
+use crate::iv_ledger::IvLedger;
+use crate::secret::{wipe_value, SecretBytes};
+
+// Encrypt-then-MAC composition of AES-CTR and HMAC-SHA-256
+//
+// The layout is the common "AES-CTR + HMAC-SHA256" one:
+//
+//     IV (16 bytes) || ciphertext || HMAC-SHA-256(mac key, IV || ciphertext) (32 bytes)
+
+pub(crate) type HmacSha256 = Hmac<Sha256>;
+
+pub const IV_LENGTH: usize = 16;
+pub const TAG_LENGTH: usize = 32;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Error returned when a ciphertext does not carry a valid tag
+#[derive(Debug, PartialEq)]
+pub struct AuthenticationError;
+
+impl fmt::Display for AuthenticationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "authentication tag does not match (wrong key or modified ciphertext)")
+    }
+}
+
+impl std::error::Error for AuthenticationError {}
+
+/// AES key and HMAC key used for one encrypt-then-MAC operation
+pub struct EtmKeys {
+    encryption_key: SecretBytes,
+    mac_key: [u8; 32],
+}
+
+impl EtmKeys {
+
+    // Function to split 48 or 64 bytes of key material into an AES-128 or
+    // AES-256 key (first bytes) and a 256-bit HMAC key (last 32 bytes)
+    pub fn split(key_material: &[u8]) -> EtmKeys {
+
+        assert!(key_material.len() == 48 || key_material.len() == 64,
+                "split key material has to be 384-bit or 512-bit");
+
+        let (encryption_key, mac_key) = key_material.split_at(key_material.len() - 32);
+        let mut mac_key_array = [0u8; 32];
+        mac_key_array.copy_from_slice(mac_key);
+
+        EtmKeys { encryption_key: SecretBytes::from_slice(encryption_key), mac_key: mac_key_array }
+    }
+
+    // Function to derive an AES key of the same size as the 128-bit, 192-bit
+    // or 256-bit master key and an independent HMAC key from it
+    pub fn derive(master_key: &[u8]) -> EtmKeys {
+
+        assert_aes_key_length(master_key.len());
+
+        let mut encryption_key = hmac_sha256(master_key, &[b"aes-ctr-hmac encryption key"]);
+        let mac_key = hmac_sha256(master_key, &[b"aes-ctr-hmac authentication key"]);
+
+        let keys = EtmKeys { encryption_key: SecretBytes::from_slice(&encryption_key[..master_key.len()]), mac_key };
+        wipe_value(&mut encryption_key);
+        keys
+    }
+
+    pub(crate) fn cipher(&self) -> Box<dyn BlockCipher> {
+        new_default_cipher(self.encryption_key.as_slice())
+    }
+
+    pub(crate) fn new_mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.mac_key).unwrap()
+    }
+}
+
+impl Drop for EtmKeys {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.mac_key);
+    }
+}
+
+// Function to compute HMAC-SHA-256 over the concatenation of several parts
+pub(crate) fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+
+    let mut tag = [0u8; 32];
+    tag.copy_from_slice(&mac.finalize().into_bytes());
+    tag
+}
+
+// Function to encrypt a message in memory, returns IV || ciphertext || tag
+pub fn etm_encrypt(keys: &EtmKeys, iv: &[u8; 16], plaintext: &[u8]) -> Vec<u8> {
+
+    let mut output = Vec::with_capacity(IV_LENGTH + plaintext.len() + TAG_LENGTH);
+    output.extend_from_slice(iv);
+    output.extend_from_slice(plaintext);
+
+    apply_keystream(keys.cipher().as_ref(), u128::from_be_bytes(*iv), &mut output[IV_LENGTH..]);
+
+    let tag = hmac_sha256(&keys.mac_key, &[&output]);
+    output.extend_from_slice(&tag);
+    output
+}
+
+// Function to verify and decrypt IV || ciphertext || tag in memory,
+// the plaintext is only produced after the tag was checked
+pub fn etm_decrypt(keys: &EtmKeys, data: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+
+    if data.len() < IV_LENGTH + TAG_LENGTH {
+        return Err(AuthenticationError);
+    }
+
+    let (authenticated, tag) = data.split_at(data.len() - TAG_LENGTH);
+    let mut mac = keys.new_mac();
+    mac.update(authenticated);
+    mac.verify_slice(tag).map_err(|_| AuthenticationError)?;
+
+    let mut iv = [0u8; 16];
+    iv.copy_from_slice(&authenticated[..IV_LENGTH]);
+    let mut plaintext = authenticated[IV_LENGTH..].to_vec();
+    apply_keystream(keys.cipher().as_ref(), u128::from_be_bytes(iv), &mut plaintext);
+
+    Ok(plaintext)
+}
+
+// Function to encrypt a file and append the tag, with a ledger only if the
+// counter range was not used with the encryption key before
+pub fn encrypt_file(keys: &EtmKeys, iv: &[u8; 16], input_file_path: &Path, output_file_path: &Path,
+                    ledger: Option<&IvLedger>) -> Result<(), CtrFileError> {
+
+    let cipher = keys.cipher();
+    let mut counter = u128::from_be_bytes(*iv);
+    let mut mac = keys.new_mac();
+
+    // nothing beyond the reserved range is encrypted, also if the input grows
+    let max_length = match ledger {
+        Some(ledger) => ledger.reserve_file(cipher.as_ref(), counter, input_file_path)?,
+        None => u64::MAX,
+    };
+
+    let mut reader = BufReader::new(File::open(input_file_path)?.take(max_length));
+    let mut writer = BufWriter::new(File::create(output_file_path)?);
+    let mut buffer = SecretBytes::zeroed(BUFFER_SIZE);
+
+    writer.write_all(iv)?;
+    mac.update(iv);
+
+    loop {
+        let read_count = read_full(&mut reader, buffer.as_mut_slice())?;
+        let chunk = &mut buffer.as_mut_slice()[..read_count];
+        counter = apply_keystream(cipher.as_ref(), counter, chunk);
+        mac.update(chunk);
+        writer.write_all(chunk)?;
+
+        if read_count < buffer.len() {
+            break;
+        }
+    }
+
+    writer.write_all(&mac.finalize().into_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Function to decrypt a file written by `encrypt_file`
+//
+// Input is read once, also from pipes; the plaintext goes to a temporary file
+// next to the output that only replaces the output once the tag has been
+// verified, so no unauthenticated plaintext ever appears under the output
+// path. The output therefore has to be a regular file (or not exist yet).
+// The temporary file is only readable by the owner, which the output keeps,
+// and I/O errors are returned so the temporary file is always removed.
+pub fn decrypt_file(keys: &EtmKeys, input_file_path: &Path, output_file_path: &Path) -> Result<(), FileDecryptionError> {
+
+    let mut reader = BufReader::new(File::open(input_file_path)?);
+    let mut iv = [0u8; IV_LENGTH];
+    if read_full(&mut reader, &mut iv)? < IV_LENGTH {
+        return Err(FileDecryptionError::Authentication);
+    }
+
+    let mut output = PendingOutput::create(output_file_path)?;
+    let cipher = keys.cipher();
+    let mut counter = u128::from_be_bytes(iv);
+    let mut mac = keys.new_mac();
+    mac.update(&iv);
+
+    // the last TAG_LENGTH bytes read so far may be the tag, so they are held
+    // back at the front of the buffer until more input follows
+    let mut buffer = SecretBytes::zeroed(BUFFER_SIZE + TAG_LENGTH);
+    let mut held = 0;
+    loop {
+        let read_count = read_full(&mut reader, &mut buffer.as_mut_slice()[held..])?;
+        let available = held + read_count;
+        if available < TAG_LENGTH {
+            return Err(FileDecryptionError::Authentication);
+        }
+
+        let ciphertext_length = available - TAG_LENGTH;
+        let ciphertext = &mut buffer.as_mut_slice()[..ciphertext_length];
+        mac.update(ciphertext);
+        counter = apply_keystream(cipher.as_ref(), counter, ciphertext);
+        output.writer().write_all(ciphertext)?;
+
+        if available < buffer.len() {
+            mac.verify_slice(&buffer.as_slice()[ciphertext_length..available])
+               .map_err(|_| FileDecryptionError::Authentication)?;
+            output.persist()?;
+            return Ok(());
+        }
+
+        buffer.as_mut_slice().copy_within(ciphertext_length..available, 0);
+        held = TAG_LENGTH;
+    }
+}
+
+/// Error returned by `decrypt_file`
+#[derive(Debug)]
+pub enum FileDecryptionError {
+    // the tag does not match, nothing was written to the output path
+    Authentication,
+    // the output is no regular file, so it can not be replaced only after
+    // the tag was verified
+    UnsupportedOutput,
+    // reading the input or writing the output failed, nothing was written
+    // to the output path
+    Io(io::Error),
+}
+
+impl fmt::Display for FileDecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileDecryptionError::Authentication => AuthenticationError.fmt(f),
+            FileDecryptionError::UnsupportedOutput =>
+                write!(f, "output has to be a regular file, it is only written once the tag is verified"),
+            FileDecryptionError::Io(e) => write!(f, "decryption failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FileDecryptionError {}
+
+impl From<io::Error> for FileDecryptionError {
+    fn from(e: io::Error) -> FileDecryptionError {
+        FileDecryptionError::Io(e)
+    }
+}
+
+// Output written to a hidden temporary file in the same directory, which is
+// renamed to the output path by `persist` and removed if dropped before or
+// if persisting fails
+struct PendingOutput {
+    temporary_path: PathBuf,
+    output_path: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl PendingOutput {
+
+    fn create(output_path: &Path) -> Result<PendingOutput, FileDecryptionError> {
+
+        match fs::metadata(output_path) {
+            Ok(metadata) if !metadata.is_file() => return Err(FileDecryptionError::UnsupportedOutput),
+            _ => {}
+        }
+
+        let mut suffix = [0u8; 8];
+        getrandom::getrandom(&mut suffix).map_err(|e| io::Error::other(e.to_string()))?;
+        let file_name = output_path.file_name().ok_or(FileDecryptionError::UnsupportedOutput)?;
+        let temporary_path = output_path.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), hex::encode(suffix)));
+
+        // unverified plaintext is never readable by other users
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&temporary_path)?;
+
+        Ok(PendingOutput { temporary_path, output_path: output_path.to_path_buf(), writer: Some(BufWriter::new(file)) })
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<File> {
+        self.writer.as_mut().unwrap()
+    }
+
+    fn persist(mut self) -> io::Result<()> {
+        let writer = self.writer();
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&self.temporary_path, &self.output_path)?;
+
+        // renamed, nothing left to remove
+        self.writer = None;
+        Ok(())
+    }
+}
+
+impl Drop for PendingOutput {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+#[test]
+fn test_etm_interoperates_with_split_key_layout() {
+
+    // AES-256-CTR with the first half of the key material, HMAC-SHA-256 with
+    // the second half, checked against NIST SP 800-38A F.5.5 for the CTR part
+    let mut key_material = hex::decode("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4").unwrap();
+    key_material.extend_from_slice(&[0x42; 32]);
+    let keys = EtmKeys::split(&key_material);
+
+    let iv = [0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff];
+    let plaintext = hex::decode("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51").unwrap();
+    let output = etm_encrypt(&keys, &iv, &plaintext);
+
+    assert_eq!(&output[..16], &iv);
+    assert_eq!(hex::encode(&output[16..48]), "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5");
+    assert_eq!(&output[48..], &hmac_sha256(&[0x42; 32], &[&output[..48]]));
+    assert_eq!(etm_decrypt(&keys, &output).unwrap(), plaintext);
+}
+
+#[test]
+fn test_etm_rejects_modified_ciphertext() {
+
+    let keys = EtmKeys::derive(&[7u8; 16]);
+    let output = etm_encrypt(&keys, &[1u8; 16], b"attack at dawn");
+
+    for i in 0..output.len() {
+        let mut modified = output.clone();
+        modified[i] ^= 0x01;
+        assert_eq!(etm_decrypt(&keys, &modified), Err(AuthenticationError));
+    }
+    assert_eq!(etm_decrypt(&keys, &output[..output.len() - 1]), Err(AuthenticationError));
+    assert_eq!(etm_decrypt(&EtmKeys::derive(&[8u8; 16]), &output), Err(AuthenticationError));
+}
+
+#[test]
+fn test_decrypt_file_only_writes_verified_plaintext() {
+
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("etm-file");
+    let (input, encrypted, decrypted) = (directory.join("input"), directory.join("encrypted"), directory.join("decrypted"));
+    let keys = EtmKeys::derive(&[3u8; 32]);
+
+    // lengths around the buffer size move the tag across reads
+    for length in [0, 1, BUFFER_SIZE - 1, BUFFER_SIZE, BUFFER_SIZE + TAG_LENGTH + 1].iter() {
+        let data: Vec<u8> = (0..*length).map(|i| (i * 5) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+        encrypt_file(&keys, &[9u8; 16], &input, &encrypted, None).unwrap();
+        decrypt_file(&keys, &encrypted, &decrypted).unwrap();
+        assert_eq!(std::fs::read(&decrypted).unwrap(), data);
+    }
+
+    // the plaintext was never readable by other users
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&decrypted).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    // a missing input or an output that can not be created is an error, not a panic
+    let missing = directory.join("missing").join("decrypted");
+    assert!(matches!(decrypt_file(&keys, &encrypted, &missing), Err(FileDecryptionError::Io(_))));
+    assert!(matches!(encrypt_file(&keys, &[9u8; 16], &input, &missing, None), Err(CtrFileError::Io(_))));
+    assert!(matches!(encrypt_file(&keys, &[9u8; 16], &missing, &encrypted, None), Err(CtrFileError::Io(_))));
+
+    // a modified file leaves an existing output untouched and no temporary file behind
+    let mut modified = std::fs::read(&encrypted).unwrap();
+    modified[20] ^= 1;
+    std::fs::write(&encrypted, &modified).unwrap();
+    std::fs::write(&decrypted, b"old").unwrap();
+    assert!(matches!(decrypt_file(&keys, &encrypted, &decrypted), Err(FileDecryptionError::Authentication)));
+    assert_eq!(std::fs::read(&decrypted).unwrap(), b"old");
+    assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 3);
+
+    std::fs::write(&encrypted, &modified[..IV_LENGTH + TAG_LENGTH - 1]).unwrap();
+    assert!(matches!(decrypt_file(&keys, &encrypted, &decrypted), Err(FileDecryptionError::Authentication)));
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use crate::aes_backend::{BlockCipher, ExpandedKey, PARALLEL_BLOCKS};
+use crate::secret::{wipe, wipe_value};
+
+// Ahead-of-time CTR keystream
+//
+// A background thread encrypts counter blocks into a ring buffer until it
+// holds `depth` bytes of keystream. Applying the keystream to a message then
+// only xors bytes taken from the front of the ring. Keystream is produced and
+// consumed strictly in order, so byte `n` of the stream always belongs to
+// counter `iv + n / 16`, and messages of any length continue where the
+// previous one ended. Consumed keystream is overwritten with zeros. If the
+// generator thread is gone, waiting for keystream fails instead of blocking.
+
+// bytes the generator produces at once
+const BATCH_SIZE: usize = PARALLEL_BLOCKS * 16;
+
+/// Error returned when the generator thread ended before producing the
+/// keystream a message needs
+#[derive(Debug, PartialEq)]
+pub struct GeneratorStopped;
+
+impl fmt::Display for GeneratorStopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "keystream generator thread stopped")
+    }
+}
+
+impl std::error::Error for GeneratorStopped {}
+
+/// CTR keystream generated ahead by a background thread
+pub struct KeystreamPool {
+    shared: Arc<Shared>,
+    generator: Option<JoinHandle<()>>,
+    // keystream bytes consumed so far
+    position: u128,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    changed: Condvar,
+}
+
+struct PoolState {
+    ring: Vec<u8>,
+    // index of the oldest unused keystream byte and number of unused bytes
+    start: usize,
+    filled: usize,
+    stop: bool,
+    // cleared when the generator thread ends, also if it panics
+    generating: bool,
+}
+
+impl KeystreamPool {
+
+    // Function to start generating the keystream of the given IV, keeping at
+    // least `depth` bytes (rounded up to whole batches of blocks) ready, the
+    // generator thread shares the key schedule instead of copying it
+    pub fn new(key: Arc<ExpandedKey>, iv: &[u8; 16], depth: usize) -> KeystreamPool {
+
+        let depth = depth.max(1).next_multiple_of(BATCH_SIZE);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PoolState { ring: vec![0u8; depth], start: 0, filled: 0, stop: false, generating: true }),
+            changed: Condvar::new(),
+        });
+
+        let counter = u128::from_be_bytes(*iv);
+        let generator_shared = Arc::clone(&shared);
+        let generator = thread::spawn(move || generate(key.as_ref(), counter, &generator_shared));
+
+        KeystreamPool { shared, generator: Some(generator), position: 0 }
+    }
+
+    // Function to encrypt or decrypt the next message in place, waits for the
+    // generator only if fewer than `data.len()` bytes are ready, fails if the
+    // generator thread ended before producing them; the message is then only
+    // processed up to the new `position`
+    pub fn apply_keystream(&mut self, data: &mut [u8]) -> Result<(), GeneratorStopped> {
+
+        let mut done = 0;
+        while done < data.len() {
+
+            let mut state = self.shared.changed.wait_while(self.shared.state.lock().unwrap(),
+                                                          |state| state.filled == 0 && state.generating).unwrap();
+            if state.filled == 0 {
+                self.position += done as u128;
+                return Err(GeneratorStopped);
+            }
+
+            // xor the ready keystream, in two parts if it wraps around the ring
+            let count = state.filled.min(data.len() - done);
+            let ring_length = state.ring.len();
+            let mut taken = 0;
+            while taken < count {
+                let start = (state.start + taken) % ring_length;
+                let part = (count - taken).min(ring_length - start);
+                let keystream = &mut state.ring[start..start + part];
+                for (d, k) in data[done + taken..done + taken + part].iter_mut().zip(keystream.iter()) {
+                    *d ^= *k;
+                }
+                wipe(keystream);
+                taken += part;
+            }
+
+            state.start = (state.start + count) % ring_length;
+            state.filled -= count;
+            drop(state);
+            self.shared.changed.notify_all();
+
+            done += count;
+        }
+
+        self.position += data.len() as u128;
+        Ok(())
+    }
+
+    // Function to return the number of keystream bytes consumed so far
+    pub fn position(&self) -> u128 {
+        self.position
+    }
+
+    // Function to return the number of keystream bytes ready right now
+    pub fn available(&self) -> usize {
+        self.shared.state.lock().unwrap().filled
+    }
+}
+
+impl Drop for KeystreamPool {
+
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.changed.notify_all();
+        if let Some(generator) = self.generator.take() {
+            let _ = generator.join();
+        }
+        wipe(&mut self.shared.state.lock().unwrap_or_else(PoisonError::into_inner).ring);
+    }
+}
+
+// Guard of the generator thread, wakes up waiting consumers when the
+// generator ends for whatever reason
+struct GeneratorExit<'a>(&'a Shared);
+
+impl Drop for GeneratorExit<'_> {
+
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap_or_else(PoisonError::into_inner).generating = false;
+        self.0.changed.notify_all();
+    }
+}
+
+// Function run by the generator thread, fills the ring one batch at a time
+// until the pool is dropped
+fn generate(cipher: &dyn BlockCipher, mut counter: u128, shared: &Shared) {
+
+    let _exit = GeneratorExit(shared);
+    let mut blocks = [0u128; PARALLEL_BLOCKS];
+    let mut keystream = [0u8; BATCH_SIZE];
+
+    loop {
+
+        // encrypt the next batch without holding the lock
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = counter.wrapping_add(i as u128);
+        }
+        cipher.encrypt_blocks(&mut blocks);
+        for (bytes, block) in keystream.chunks_exact_mut(16).zip(blocks.iter()) {
+            bytes.copy_from_slice(&block.to_be_bytes());
+        }
+        counter = counter.wrapping_add(PARALLEL_BLOCKS as u128);
+
+        let mut state = shared.changed.wait_while(shared.state.lock().unwrap(),
+                                                  |state| !state.stop && state.ring.len() - state.filled < BATCH_SIZE).unwrap();
+        if state.stop {
+            break;
+        }
+
+        // the ring length is a multiple of the batch size, so a batch never wraps
+        let end = (state.start + state.filled) % state.ring.len();
+        state.ring[end..end + BATCH_SIZE].copy_from_slice(&keystream);
+        state.filled += BATCH_SIZE;
+        drop(state);
+        shared.changed.notify_all();
+    }
+
+    wipe(&mut keystream);
+    wipe_value(&mut blocks);
+}
+
+#[test]
+fn test_keystream_pool_matches_aes_ctr() {
+
+    let key = Arc::new(ExpandedKey::new(&[0x42; 32]));
+    let iv = [0xff; 16];
+
+    // packets of odd sizes, some longer than the pool
+    let packets = [1, 15, 16, 17, 100, 0, 300, 1000, 3];
+    let total: usize = packets.iter().sum();
+    let mut expected: Vec<u8> = (0..total).map(|i| i as u8).collect();
+    let data = expected.clone();
+    crate::aes_ctr_optimized::AesCtr::new(&key, &iv).apply_keystream(&mut expected);
+
+    for depth in [1, 128, 4096].iter() {
+        let mut pool = KeystreamPool::new(Arc::clone(&key), &iv, *depth);
+        let mut output = data.clone();
+        let mut offset = 0;
+        for length in packets.iter() {
+            pool.apply_keystream(&mut output[offset..offset + length]).unwrap();
+            offset += length;
+        }
+        assert_eq!(output, expected, "pool depth {}", depth);
+        assert_eq!(pool.position(), total as u128);
+    }
+}
+
+#[test]
+fn test_keystream_pool_wipes_used_keystream() {
+
+    let mut pool = KeystreamPool::new(Arc::new(ExpandedKey::new(&[0x42; 16])), &[0; 16], 256);
+
+    // wait until the pool is full and stop the generator, so it does not
+    // refill the ring, then take half of the keystream
+    while pool.available() < 256 {
+        thread::yield_now();
+    }
+    pool.shared.state.lock().unwrap().stop = true;
+    pool.shared.changed.notify_all();
+    pool.generator.take().unwrap().join().unwrap();
+    pool.apply_keystream(&mut [0u8; 128]).unwrap();
+
+    let state = pool.shared.state.lock().unwrap();
+    let used_start = (state.start + state.ring.len() - 128) % state.ring.len();
+    assert!(state.ring[used_start..used_start + 128].iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn test_keystream_pool_fails_without_generator() {
+
+    let mut pool = KeystreamPool::new(Arc::new(ExpandedKey::new(&[0x42; 16])), &[0; 16], 256);
+
+    // the generator ends after at most one more batch, the ready keystream
+    // is still used, waiting for more fails instead of blocking forever
+    pool.shared.state.lock().unwrap().stop = true;
+    pool.shared.changed.notify_all();
+    pool.generator.take().unwrap().join().unwrap();
+    let available = pool.available();
+    pool.apply_keystream(&mut vec![0u8; available]).unwrap();
+
+    // a message longer than the rest is processed as far as keystream was left
+    assert_eq!(pool.apply_keystream(&mut [0u8; 1]), Err(GeneratorStopped));
+    assert_eq!(pool.position(), available as u128);
+}
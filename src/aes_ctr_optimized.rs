@@ -1,330 +1,870 @@
+use std::error::Error;
 use std::io::prelude::*;
-use std::fs::File;
-use std::iter::FromIterator;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::char::from_u32;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::Path;
 use std::convert::TryInto;
 
-// Lookup Tables
-const SUBSTITUTION: [u8; 256] = [
-    0x63, 0x7C, 0x77, 0x7B, 0xF2, 0x6B, 0x6F, 0xC5, 0x30, 0x01, 0x67, 0x2B, 0xFE, 0xD7, 0xAB, 0x76,
-0xCA, 0x82, 0xC9, 0x7D, 0xFA, 0x59, 0x47, 0xF0, 0xAD, 0xD4, 0xA2, 0xAF, 0x9C, 0xA4, 0x72, 0xC0,
-0xB7, 0xFD, 0x93, 0x26, 0x36, 0x3F, 0xF7, 0xCC, 0x34, 0xA5, 0xE5, 0xF1, 0x71, 0xD8, 0x31, 0x15,
-0x04, 0xC7, 0x23, 0xC3, 0x18, 0x96, 0x05, 0x9A, 0x07, 0x12, 0x80, 0xE2, 0xEB, 0x27, 0xB2, 0x75,
-0x09, 0x83, 0x2C, 0x1A, 0x1B, 0x6E, 0x5A, 0xA0, 0x52, 0x3B, 0xD6, 0xB3, 0x29, 0xE3, 0x2F, 0x84,
-0x53, 0xD1, 0x00, 0xED, 0x20, 0xFC, 0xB1, 0x5B, 0x6A, 0xCB, 0xBE, 0x39, 0x4A, 0x4C, 0x58, 0xCF,
-0xD0, 0xEF, 0xAA, 0xFB, 0x43, 0x4D, 0x33, 0x85, 0x45, 0xF9, 0x02, 0x7F, 0x50, 0x3C, 0x9F, 0xA8,
-0x51, 0xA3, 0x40, 0x8F, 0x92, 0x9D, 0x38, 0xF5, 0xBC, 0xB6, 0xDA, 0x21, 0x10, 0xFF, 0xF3, 0xD2,
-0xCD, 0x0C, 0x13, 0xEC, 0x5F, 0x97, 0x44, 0x17, 0xC4, 0xA7, 0x7E, 0x3D, 0x64, 0x5D, 0x19, 0x73,
-0x60, 0x81, 0x4F, 0xDC, 0x22, 0x2A, 0x90, 0x88, 0x46, 0xEE, 0xB8, 0x14, 0xDE, 0x5E, 0x0B, 0xDB,
-0xE0, 0x32, 0x3A, 0x0A, 0x49, 0x06, 0x24, 0x5C, 0xC2, 0xD3, 0xAC, 0x62, 0x91, 0x95, 0xE4, 0x79,
-0xE7, 0xC8, 0x37, 0x6D, 0x8D, 0xD5, 0x4E, 0xA9, 0x6C, 0x56, 0xF4, 0xEA, 0x65, 0x7A, 0xAE, 0x08,
-0xBA, 0x78, 0x25, 0x2E, 0x1C, 0xA6, 0xB4, 0xC6, 0xE8, 0xDD, 0x74, 0x1F, 0x4B, 0xBD, 0x8B, 0x8A,
-0x70, 0x3E, 0xB5, 0x66, 0x48, 0x03, 0xF6, 0x0E, 0x61, 0x35, 0x57, 0xB9, 0x86, 0xC1, 0x1D, 0x9E,
-0xE1, 0xF8, 0x98, 0x11, 0x69, 0xD9, 0x8E, 0x94, 0x9B, 0x1E, 0x87, 0xE9, 0xCE, 0x55, 0x28, 0xDF,
-0x8C, 0xA1, 0x89, 0x0D, 0xBF, 0xE6, 0x42, 0x68, 0x41, 0x99, 0x2D, 0x0F, 0xB0, 0x54, 0xBB, 0x16
-];
-
-const MULTIPLY_2: [u8; 256] =
-[
-0x00,0x02,0x04,0x06,0x08,0x0a,0x0c,0x0e,0x10,0x12,0x14,0x16,0x18,0x1a,0x1c,0x1e,
-0x20,0x22,0x24,0x26,0x28,0x2a,0x2c,0x2e,0x30,0x32,0x34,0x36,0x38,0x3a,0x3c,0x3e,
-0x40,0x42,0x44,0x46,0x48,0x4a,0x4c,0x4e,0x50,0x52,0x54,0x56,0x58,0x5a,0x5c,0x5e,
-0x60,0x62,0x64,0x66,0x68,0x6a,0x6c,0x6e,0x70,0x72,0x74,0x76,0x78,0x7a,0x7c,0x7e,
-0x80,0x82,0x84,0x86,0x88,0x8a,0x8c,0x8e,0x90,0x92,0x94,0x96,0x98,0x9a,0x9c,0x9e,
-0xa0,0xa2,0xa4,0xa6,0xa8,0xaa,0xac,0xae,0xb0,0xb2,0xb4,0xb6,0xb8,0xba,0xbc,0xbe,
-0xc0,0xc2,0xc4,0xc6,0xc8,0xca,0xcc,0xce,0xd0,0xd2,0xd4,0xd6,0xd8,0xda,0xdc,0xde,
-0xe0,0xe2,0xe4,0xe6,0xe8,0xea,0xec,0xee,0xf0,0xf2,0xf4,0xf6,0xf8,0xfa,0xfc,0xfe,
-0x1b,0x19,0x1f,0x1d,0x13,0x11,0x17,0x15,0x0b,0x09,0x0f,0x0d,0x03,0x01,0x07,0x05,
-0x3b,0x39,0x3f,0x3d,0x33,0x31,0x37,0x35,0x2b,0x29,0x2f,0x2d,0x23,0x21,0x27,0x25,
-0x5b,0x59,0x5f,0x5d,0x53,0x51,0x57,0x55,0x4b,0x49,0x4f,0x4d,0x43,0x41,0x47,0x45,
-0x7b,0x79,0x7f,0x7d,0x73,0x71,0x77,0x75,0x6b,0x69,0x6f,0x6d,0x63,0x61,0x67,0x65,
-0x9b,0x99,0x9f,0x9d,0x93,0x91,0x97,0x95,0x8b,0x89,0x8f,0x8d,0x83,0x81,0x87,0x85,
-0xbb,0xb9,0xbf,0xbd,0xb3,0xb1,0xb7,0xb5,0xab,0xa9,0xaf,0xad,0xa3,0xa1,0xa7,0xa5,
-0xdb,0xd9,0xdf,0xdd,0xd3,0xd1,0xd7,0xd5,0xcb,0xc9,0xcf,0xcd,0xc3,0xc1,0xc7,0xc5,
-0xfb,0xf9,0xff,0xfd,0xf3,0xf1,0xf7,0xf5,0xeb,0xe9,0xef,0xed,0xe3,0xe1,0xe7,0xe5
-];
-
-const MULTIPLY_3: [u8; 256] =
-[
-0x00,0x03,0x06,0x05,0x0c,0x0f,0x0a,0x09,0x18,0x1b,0x1e,0x1d,0x14,0x17,0x12,0x11,
-0x30,0x33,0x36,0x35,0x3c,0x3f,0x3a,0x39,0x28,0x2b,0x2e,0x2d,0x24,0x27,0x22,0x21,
-0x60,0x63,0x66,0x65,0x6c,0x6f,0x6a,0x69,0x78,0x7b,0x7e,0x7d,0x74,0x77,0x72,0x71,
-0x50,0x53,0x56,0x55,0x5c,0x5f,0x5a,0x59,0x48,0x4b,0x4e,0x4d,0x44,0x47,0x42,0x41,
-0xc0,0xc3,0xc6,0xc5,0xcc,0xcf,0xca,0xc9,0xd8,0xdb,0xde,0xdd,0xd4,0xd7,0xd2,0xd1,
-0xf0,0xf3,0xf6,0xf5,0xfc,0xff,0xfa,0xf9,0xe8,0xeb,0xee,0xed,0xe4,0xe7,0xe2,0xe1,
-0xa0,0xa3,0xa6,0xa5,0xac,0xaf,0xaa,0xa9,0xb8,0xbb,0xbe,0xbd,0xb4,0xb7,0xb2,0xb1,
-0x90,0x93,0x96,0x95,0x9c,0x9f,0x9a,0x99,0x88,0x8b,0x8e,0x8d,0x84,0x87,0x82,0x81,
-0x9b,0x98,0x9d,0x9e,0x97,0x94,0x91,0x92,0x83,0x80,0x85,0x86,0x8f,0x8c,0x89,0x8a,
-0xab,0xa8,0xad,0xae,0xa7,0xa4,0xa1,0xa2,0xb3,0xb0,0xb5,0xb6,0xbf,0xbc,0xb9,0xba,
-0xfb,0xf8,0xfd,0xfe,0xf7,0xf4,0xf1,0xf2,0xe3,0xe0,0xe5,0xe6,0xef,0xec,0xe9,0xea,
-0xcb,0xc8,0xcd,0xce,0xc7,0xc4,0xc1,0xc2,0xd3,0xd0,0xd5,0xd6,0xdf,0xdc,0xd9,0xda,
-0x5b,0x58,0x5d,0x5e,0x57,0x54,0x51,0x52,0x43,0x40,0x45,0x46,0x4f,0x4c,0x49,0x4a,
-0x6b,0x68,0x6d,0x6e,0x67,0x64,0x61,0x62,0x73,0x70,0x75,0x76,0x7f,0x7c,0x79,0x7a,
-0x3b,0x38,0x3d,0x3e,0x37,0x34,0x31,0x32,0x23,0x20,0x25,0x26,0x2f,0x2c,0x29,0x2a,
-0x0b,0x08,0x0d,0x0e,0x07,0x04,0x01,0x02,0x13,0x10,0x15,0x16,0x1f,0x1c,0x19,0x1a
-];
-
-const RCON: [u8; 21] =
-[
-0x8d, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36, 0x6c,	0xd8, 0xab, 0x4d, 0x9a,	0x2f, 0x5e, 0xbc, 0x63, 0xc6
-];
-
-const T_0: [u32; 256] = [3328402341, 4168907908, 4000806809, 4135287693, 4294111757, 3597364157, 3731845041, 2445657428, 1613770832, 33620227,
-    3462883241, 1445669757, 3892248089, 3050821474, 1303096294, 3967186586, 2412431941, 528646813, 2311702848, 4202528135,
-    4026202645, 2992200171, 2387036105, 4226871307, 1101901292, 3017069671, 1604494077, 1169141738, 597466303, 1403299063,
-    3832705686, 2613100635, 1974974402, 3791519004, 1033081774, 1277568618, 1815492186, 2118074177, 4126668546, 2211236943,
-    1748251740, 1369810420, 3521504564, 4193382664, 3799085459, 2883115123, 1647391059, 706024767, 134480908, 2512897874,
-    1176707941, 2646852446, 806885416, 932615841, 168101135, 798661301, 235341577, 605164086, 461406363, 3756188221,
-    3454790438, 1311188841, 2142417613, 3933566367, 302582043, 495158174, 1479289972, 874125870, 907746093, 3698224818,
-    3025820398, 1537253627, 2756858614, 1983593293, 3084310113, 2108928974, 1378429307, 3722699582, 1580150641, 327451799,
-    2790478837, 3117535592, 0, 3253595436, 1075847264, 3825007647, 2041688520, 3059440621, 3563743934, 2378943302,
-    1740553945, 1916352843, 2487896798, 2555137236, 2958579944, 2244988746, 3151024235, 3320835882, 1336584933, 3992714006,
-    2252555205, 2588757463, 1714631509, 293963156, 2319795663, 3925473552, 67240454, 4269768577, 2689618160, 2017213508,
-    631218106, 1269344483, 2723238387, 1571005438, 2151694528, 93294474, 1066570413, 563977660, 1882732616, 4059428100,
-    1673313503, 2008463041, 2950355573, 1109467491, 537923632, 3858759450, 4260623118, 3218264685, 2177748300, 403442708,
-    638784309, 3287084079, 3193921505, 899127202, 2286175436, 773265209, 2479146071, 1437050866, 4236148354, 2050833735,
-    3362022572, 3126681063, 840505643, 3866325909, 3227541664, 427917720, 2655997905, 2749160575, 1143087718, 1412049534,
-    999329963, 193497219, 2353415882, 3354324521, 1807268051, 672404540, 2816401017, 3160301282, 369822493, 2916866934,
-    3688947771, 1681011286, 1949973070, 336202270, 2454276571, 201721354, 1210328172, 3093060836, 2680341085, 3184776046,
-    1135389935, 3294782118, 965841320, 831886756, 3554993207, 4068047243, 3588745010, 2345191491, 1849112409, 3664604599,
-    26054028, 2983581028, 2622377682, 1235855840, 3630984372, 2891339514, 4092916743, 3488279077, 3395642799, 4101667470,
-    1202630377, 268961816, 1874508501, 4034427016, 1243948399, 1546530418, 941366308, 1470539505, 1941222599, 2546386513,
-    3421038627, 2715671932, 3899946140, 1042226977, 2521517021, 1639824860, 227249030, 260737669, 3765465232, 2084453954,
-    1907733956, 3429263018, 2420656344, 100860677, 4160157185, 470683154, 3261161891, 1781871967, 2924959737, 1773779408,
-    394692241, 2579611992, 974986535, 664706745, 3655459128, 3958962195, 731420851, 571543859, 3530123707, 2849626480,
-    126783113, 865375399, 765172662, 1008606754, 361203602, 3387549984, 2278477385, 2857719295, 1344809080, 2782912378,
-    59542671, 1503764984, 160008576, 437062935, 1707065306, 3622233649, 2218934982, 3496503480, 2185314755, 697932208,
-    1512910199, 504303377, 2075177163, 2824099068, 1841019862, 739644986 ];
-
-const T_1: [u32; 256] = [2781242211, 2230877308, 2582542199, 2381740923, 234877682, 3184946027, 2984144751, 1418839493, 1348481072, 50462977,
-    2848876391, 2102799147, 434634494, 1656084439, 3863849899, 2599188086, 1167051466, 2636087938, 1082771913, 2281340285,
-    368048890, 3954334041, 3381544775, 201060592, 3963727277, 1739838676, 4250903202, 3930435503, 3206782108, 4149453988,
-    2531553906, 1536934080, 3262494647, 484572669, 2923271059, 1783375398, 1517041206, 1098792767, 49674231, 1334037708,
-    1550332980, 4098991525, 886171109, 150598129, 2481090929, 1940642008, 1398944049, 1059722517, 201851908, 1385547719,
-    1699095331, 1587397571, 674240536, 2704774806, 252314885, 3039795866, 151914247, 908333586, 2602270848, 1038082786,
-    651029483, 1766729511, 3447698098, 2682942837, 454166793, 2652734339, 1951935532, 775166490, 758520603, 3000790638,
-    4004797018, 4217086112, 4137964114, 1299594043, 1639438038, 3464344499, 2068982057, 1054729187, 1901997871, 2534638724,
-    4121318227, 1757008337, 0, 750906861, 1614815264, 535035132, 3363418545, 3988151131, 3201591914, 1183697867,
-    3647454910, 1265776953, 3734260298, 3566750796, 3903871064, 1250283471, 1807470800, 717615087, 3847203498, 384695291,
-    3313910595, 3617213773, 1432761139, 2484176261, 3481945413, 283769337, 100925954, 2180939647, 4037038160, 1148730428,
-    3123027871, 3813386408, 4087501137, 4267549603, 3229630528, 2315620239, 2906624658, 3156319645, 1215313976, 82966005,
-    3747855548, 3245848246, 1974459098, 1665278241, 807407632, 451280895, 251524083, 1841287890, 1283575245, 337120268,
-    891687699, 801369324, 3787349855, 2721421207, 3431482436, 959321879, 1469301956, 4065699751, 2197585534, 1199193405,
-    2898814052, 3887750493, 724703513, 2514908019, 2696962144, 2551808385, 3516813135, 2141445340, 1715741218, 2119445034,
-    2872807568, 2198571144, 3398190662, 700968686, 3547052216, 1009259540, 2041044702, 3803995742, 487983883, 1991105499,
-    1004265696, 1449407026, 1316239930, 504629770, 3683797321, 168560134, 1816667172, 3837287516, 1570751170, 1857934291,
-    4014189740, 2797888098, 2822345105, 2754712981, 936633572, 2347923833, 852879335, 1133234376, 1500395319, 3084545389,
-    2348912013, 1689376213, 3533459022, 3762923945, 3034082412, 4205598294, 133428468, 634383082, 2949277029, 2398386810,
-    3913789102, 403703816, 3580869306, 2297460856, 1867130149, 1918643758, 607656988, 4049053350, 3346248884, 1368901318,
-    600565992, 2090982877, 2632479860, 557719327, 3717614411, 3697393085, 2249034635, 2232388234, 2430627952, 1115438654,
-    3295786421, 2865522278, 3633334344, 84280067, 33027830, 303828494, 2747425121, 1600795957, 4188952407, 3496589753,
-    2434238086, 1486471617, 658119965, 3106381470, 953803233, 334231800, 3005978776, 857870609, 3151128937, 1890179545,
-    2298973838, 2805175444, 3056442267, 574365214, 2450884487, 550103529, 1233637070, 4289353045, 2018519080, 2057691103,
-    2399374476, 4166623649, 2148108681, 387583245, 3664101311, 836232934, 3330556482, 3100665960, 3280093505, 2955516313,
-    2002398509, 287182607, 3413881008, 4238890068, 3597515707, 975967766];
-
-const T_2: [u32; 256] = [1671808611, 2089089148, 2006576759, 2072901243, 4061003762, 1807603307, 1873927791, 3310653893, 810573872, 16974337,
-    1739181671, 729634347, 4263110654, 3613570519, 2883997099, 1989864566, 3393556426, 2191335298, 3376449993, 2106063485,
-    4195741690, 1508618841, 1204391495, 4027317232, 2917941677, 3563566036, 2734514082, 2951366063, 2629772188, 2767672228,
-    1922491506, 3227229120, 3082974647, 4246528509, 2477669779, 644500518, 911895606, 1061256767, 4144166391, 3427763148,
-    878471220, 2784252325, 3845444069, 4043897329, 1905517169, 3631459288, 827548209, 356461077, 67897348, 3344078279,
-    593839651, 3277757891, 405286936, 2527147926, 84871685, 2595565466, 118033927, 305538066, 2157648768, 3795705826,
-    3945188843, 661212711, 2999812018, 1973414517, 152769033, 2208177539, 745822252, 439235610, 455947803, 1857215598,
-    1525593178, 2700827552, 1391895634, 994932283, 3596728278, 3016654259, 695947817, 3812548067, 795958831, 2224493444,
-    1408607827, 3513301457, 0, 3979133421, 543178784, 4229948412, 2982705585, 1542305371, 1790891114, 3410398667,
-    3201918910, 961245753, 1256100938, 1289001036, 1491644504, 3477767631, 3496721360, 4012557807, 2867154858, 4212583931,
-    1137018435, 1305975373, 861234739, 2241073541, 1171229253, 4178635257, 33948674, 2139225727, 1357946960, 1011120188,
-    2679776671, 2833468328, 1374921297, 2751356323, 1086357568, 2408187279, 2460827538, 2646352285, 944271416, 4110742005,
-    3168756668, 3066132406, 3665145818, 560153121, 271589392, 4279952895, 4077846003, 3530407890, 3444343245, 202643468,
-    322250259, 3962553324, 1608629855, 2543990167, 1154254916, 389623319, 3294073796, 2817676711, 2122513534, 1028094525,
-    1689045092, 1575467613, 422261273, 1939203699, 1621147744, 2174228865, 1339137615, 3699352540, 577127458, 712922154,
-    2427141008, 2290289544, 1187679302, 3995715566, 3100863416, 339486740, 3732514782, 1591917662, 186455563, 3681988059,
-    3762019296, 844522546, 978220090, 169743370, 1239126601, 101321734, 611076132, 1558493276, 3260915650, 3547250131,
-    2901361580, 1655096418, 2443721105, 2510565781, 3828863972, 2039214713, 3878868455, 3359869896, 928607799, 1840765549,
-    2374762893, 3580146133, 1322425422, 2850048425, 1823791212, 1459268694, 4094161908, 3928346602, 1706019429, 2056189050,
-    2934523822, 135794696, 3134549946, 2022240376, 628050469, 779246638, 472135708, 2800834470, 3032970164, 3327236038,
-    3894660072, 3715932637, 1956440180, 522272287, 1272813131, 3185336765, 2340818315, 2323976074, 1888542832, 1044544574,
-    3049550261, 1722469478, 1222152264, 50660867, 4127324150, 236067854, 1638122081, 895445557, 1475980887, 3117443513,
-    2257655686, 3243809217, 489110045, 2662934430, 3778599393, 4162055160, 2561878936, 288563729, 1773916777, 3648039385,
-    2391345038, 2493985684, 2612407707, 505560094, 2274497927, 3911240169, 3460925390, 1442818645, 678973480, 3749357023,
-    2358182796, 2717407649, 2306869641, 219617805, 3218761151, 3862026214, 1120306242, 1756942440, 1103331905, 2578459033,
-    762796589, 252780047, 2966125488, 1425844308, 3151392187, 372911126];
-
-const T_3: [u32; 256] = [1667474886, 2088535288, 2004326894, 2071694838, 4075949567, 1802223062, 1869591006, 3318043793, 808472672, 16843522,
-    1734846926, 724270422, 4278065639, 3621216949, 2880169549, 1987484396, 3402253711, 2189597983, 3385409673, 2105378810,
-    4210693615, 1499065266, 1195886990, 4042263547, 2913856577, 3570689971, 2728590687, 2947541573, 2627518243, 2762274643,
-    1920112356, 3233831835, 3082273397, 4261223649, 2475929149, 640051788, 909531756, 1061110142, 4160160501, 3435941763,
-    875846760, 2779116625, 3857003729, 4059105529, 1903268834, 3638064043, 825316194, 353713962, 67374088, 3351728789,
-    589522246, 3284360861, 404236336, 2526454071, 84217610, 2593830191, 117901582, 303183396, 2155911963, 3806477791,
-    3958056653, 656894286, 2998062463, 1970642922, 151591698, 2206440989, 741110872, 437923380, 454765878, 1852748508,
-    1515908788, 2694904667, 1381168804, 993742198, 3604373943, 3014905469, 690584402, 3823320797, 791638366, 2223281939,
-    1398011302, 3520161977, 0, 3991743681, 538992704, 4244381667, 2981218425, 1532751286, 1785380564, 3419096717,
-    3200178535, 960056178, 1246420628, 1280103576, 1482221744, 3486468741, 3503319995, 4025428677, 2863326543, 4227536621,
-    1128514950, 1296947098, 859002214, 2240123921, 1162203018, 4193849577, 33687044, 2139062782, 1347481760, 1010582648,
-    2678045221, 2829640523, 1364325282, 2745433693, 1077985408, 2408548869, 2459086143, 2644360225, 943212656, 4126475505,
-    3166494563, 3065430391, 3671750063, 555836226, 269496352, 4294908645, 4092792573, 3537006015, 3452783745, 202118168,
-    320025894, 3974901699, 1600119230, 2543297077, 1145359496, 387397934, 3301201811, 2812801621, 2122220284, 1027426170,
-    1684319432, 1566435258, 421079858, 1936954854, 1616945344, 2172753945, 1330631070, 3705438115, 572679748, 707427924,
-    2425400123, 2290647819, 1179044492, 4008585671, 3099120491, 336870440, 3739122087, 1583276732, 185277718, 3688593069,
-    3772791771, 842159716, 976899700, 168435220, 1229577106, 101059084, 606366792, 1549591736, 3267517855, 3553849021,
-    2897014595, 1650632388, 2442242105, 2509612081, 3840161747, 2038008818, 3890688725, 3368567691, 926374254, 1835907034,
-    2374863873, 3587531953, 1313788572, 2846482505, 1819063512, 1448540844, 4109633523, 3941213647, 1701162954, 2054852340,
-    2930698567, 134748176, 3132806511, 2021165296, 623210314, 774795868, 471606328, 2795958615, 3031746419, 3334885783,
-    3907527627, 3722280097, 1953799400, 522133822, 1263263126, 3183336545, 2341176845, 2324333839, 1886425312, 1044267644,
-    3048588401, 1718004428, 1212733584, 50529542, 4143317495, 235803164, 1633788866, 892690282, 1465383342, 3115962473,
-    2256965911, 3250673817, 488449850, 2661202215, 3789633753, 4177007595, 2560144171, 286339874, 1768537042, 3654906025,
-    2391705863, 2492770099, 2610673197, 505291324, 2273808917, 3924369609, 3469625735, 1431699370, 673740880, 3755965093,
-    2358021891, 2711746649, 2307489801, 218961690, 3217021541, 3873845719, 1111672452, 1751693520, 1094828930, 2576986153,
-    757954394, 252645662, 2964376443, 1414855848, 3149649517, 370555436];
-
-
-// generate tables for lookup in aes encrypt loop
-#[test]
-pub fn create_t_tables() {
+use memmap2::{Mmap, MmapMut};
 
-    let mut w: u32 = 0;
+use crate::aes_backend::{is_aes_key_length, BlockCipher, ExpandedKey, PARALLEL_BLOCKS};
+use crate::aes_tables::{MULTIPLY_2, MULTIPLY_3, RCON, SUBSTITUTION, T_0, T_1, T_2, T_3};
+use crate::aes_ctr_hmac::IV_LENGTH;
+use crate::header_file::InvalidHeader;
+use crate::iv_ledger::{IvLedger, LedgerError};
+use crate::secret::{wipe, wipe_value, SecretBytes};
+use crate::aes_ctr_pipeline::{apply_keystream_pipelined, DEFAULT_BUFFER_COUNT, DEFAULT_BUFFER_SIZE};
+#[cfg(target_os = "linux")]
+use crate::aes_ctr_direct::{apply_keystream_direct, DIRECT_ALIGNMENT};
+use crate::worker_pool::{Task, WorkerPool};
 
-    let mut res0: [u32; 256] = [0u32; 256];
-    let mut res1: [u32; 256] = [0u32; 256];
-    let mut res2: [u32; 256] = [0u32; 256];
-    let mut res3: [u32; 256] = [0u32; 256];
+const BUFFER_SIZE: usize = 64 * 1024;
 
-    for i in 0..256 {
-        w = ((MULTIPLY_2[SUBSTITUTION[i] as usize] as u32) << 24)
-            | ((SUBSTITUTION[i] as u32) << 16)
-            | ((SUBSTITUTION[i] as u32) << 8)
-            | ((MULTIPLY_3[SUBSTITUTION[i] as usize]) as u32);
+// bytes every thread encrypts per read when running in parallel
+const THREAD_CHUNK_SIZE: usize = 1024 * 1024;
 
-        res0[i] = w;
-        res1[i] = w << 24 | w >> 8;
-        res2[i] = w << 16 | w >> 16;
-        res3[i] = w << 8 | w >> 24;
+/// Maximum number of threads applying the keystream, larger thread counts are
+/// reduced to it (and the read buffer to `MAX_THREAD_COUNT` chunks)
+pub const MAX_THREAD_COUNT: usize = 64;
 
+// Function to properly print bytes
+#[allow(dead_code, clippy::ptr_arg, clippy::print_with_newline)]
+fn println_bytes(name_str: &str, bytes: &Vec<u8>) {
+    print!("{}", name_str);
+    for b in bytes {
+        print!("{:02x}", b);
     }
+    print!("\n");
+}
 
-    // print t0
-    let mut c = 1;
-    print!("const T_0: [u32; 256] = [");
-    for r in res0.iter() {
+/// Options for the file encryption of `handle_aes_ctr_command`
+#[derive(Clone, Copy, Debug)]
+pub struct CtrOptions<'a> {
+    // number of threads applying the keystream
+    pub thread_count: usize,
+    // encrypt between memory mappings if both files are regular files
+    pub mmap: bool,
+    // overlap reading, encryption and writing in separate threads
+    pub pipeline: bool,
+    // bypass the page cache with O_DIRECT on Linux if the filesystem allows it;
+    // a stored IV header is then padded to ALIGNED_STORED_IV_HEADER_LENGTH
+    // bytes, so the ciphertext stays aligned
+    pub direct: bool,
+    // number and size of the buffers cycling through the pipeline
+    pub buffer_count: usize,
+    pub buffer_size: usize,
+    // ledger to reserve the counter range in before encrypting, so a key is
+    // never used twice with the same counter blocks (not used for decryption)
+    pub ledger: Option<&'a IvLedger>,
+}
 
-        if c % 10 == 0 {
-            print!("{:?}, \n", r);
-        } else {
-            print!("{:?}, ", r);
+impl Default for CtrOptions<'_> {
+    fn default() -> Self {
+        CtrOptions {
+            thread_count: 1,
+            mmap: false,
+            pipeline: false,
+            direct: false,
+            buffer_count: DEFAULT_BUFFER_COUNT,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            ledger: None,
         }
+    }
+}
 
-        c += 1;
+/// Errors of the file encryption and decryption
+#[derive(Debug)]
+pub enum CtrFileError {
+    // the input can not be read or the output not written
+    Io(io::Error),
+    // the ledger can not be accessed or refuses the counter range
+    Ledger(LedgerError),
+    // the input does not start with a stored IV header
+    InvalidHeader(InvalidHeader),
+}
 
-    } print!("]\n\n");
+impl std::fmt::Display for CtrFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CtrFileError::Io(e) => e.fmt(f),
+            CtrFileError::Ledger(e) => e.fmt(f),
+            CtrFileError::InvalidHeader(e) => e.fmt(f),
+        }
+    }
+}
 
-    // print t1
-    c = 1;
-    print!("const T_1: [u32; 256] = [");
-    for r in res1.iter() {
+impl Error for CtrFileError {}
 
-        if c % 10 == 0 {
-            print!("{:?}, \n", r);
-        } else {
-            print!("{:?}, ", r);
-        }
+impl From<io::Error> for CtrFileError {
+    fn from(e: io::Error) -> CtrFileError {
+        CtrFileError::Io(e)
+    }
+}
 
-        c += 1;
+impl From<LedgerError> for CtrFileError {
+    fn from(e: LedgerError) -> CtrFileError {
+        CtrFileError::Ledger(e)
+    }
+}
 
-    } print!("]\n\n");
+impl From<InvalidHeader> for CtrFileError {
+    fn from(e: InvalidHeader) -> CtrFileError {
+        CtrFileError::InvalidHeader(e)
+    }
+}
 
-    // print t2
-    c = 1;
-    print!("const T_2: [u32; 256] = [");
-    for r in res2.iter() {
+// Header of files with stored IV, magic || IV, so decrypting a file without
+// it (e.g. one encrypted with an explicit IV) fails instead of yielding garbage
+const STORED_IV_MAGIC: &[u8; 8] = b"AESCTRIV";
+pub const STORED_IV_HEADER_LENGTH: usize = STORED_IV_MAGIC.len() + IV_LENGTH;
+
+// Magic of the same header padded with zeros to ALIGNED_STORED_IV_HEADER_LENGTH
+// bytes, written for direct I/O so the ciphertext starts at an aligned offset
+const ALIGNED_STORED_IV_MAGIC: &[u8; 8] = b"AESCTRIA";
+pub const ALIGNED_STORED_IV_HEADER_LENGTH: usize = 4096;
+
+const INVALID_HEADER: InvalidHeader = InvalidHeader("stored IV");
+
+// Where the IV of a file comes from
+enum IvPlacement {
+    // chosen by the caller and not stored
+    Given([u8; 16]),
+    // written in front of the ciphertext with the magic
+    Prepend([u8; 16]),
+    // read from the header in front of the input
+    ReadFromInput,
+}
 
-        if c % 10 == 0 {
-            print!("{:?}, \n", r);
-        } else {
-            print!("{:?}, ", r);
-        }
+// Function to handle encryption/decryption command with given parameters
+//
+// Without IV, encryption writes a random IV in front of the ciphertext and
+// decryption reads it back; an explicitly given IV is not stored. With a
+// ledger, encryption fails if the counter range was used with the key before.
+pub fn handle_aes_ctr_command(command: String,
+                              key_size: u16,
+                              key_bytes: SecretBytes,
+                              mut iv_bytes: Vec<u8>,
+                              input_file_path: std::path::PathBuf,
+                              output_file_path: std::path::PathBuf,
+                              options: CtrOptions) -> Result<(), Box<dyn Error>> {
 
-        c += 1;
+    // counter for counter mode
+    let mut iv_bytes_array = [0u8; 16];
+    let given_iv = !iv_bytes.is_empty();
+    if given_iv {
+        iv_bytes_array.clone_from_slice(&iv_bytes[0..16]);
+    }
+    wipe(&mut iv_bytes);
 
-    } print!("]\n\n");
+    // expand keys for the default backend, the round keys are wiped on drop
+    assert_eq!(key_bytes.len() * 8, key_size as usize);
+    let key = ExpandedKey::new(key_bytes.as_slice());
+    drop(key_bytes);
 
-    // print t3
-    c = 1;
-    print!("const T_3: [u32; 256] = [");
-    for r in res3.iter() {
+    if command == "encrypt" && !given_iv {
+        iv_bytes_array = random_iv();
+    }
 
-        if c % 10 == 0 {
-            print!("{:?}, \n", r);
-        } else {
-            print!("{:?}, ", r);
-        }
+    // decrypting with an explicit IV applies the same keystream again
+    let options = if command == "encrypt" { options } else { CtrOptions { ledger: None, ..options } };
 
-        c += 1;
+    if given_iv {
+        encrypt_file(&key, &iv_bytes_array, &input_file_path, &output_file_path, options)?;
+    } else if command == "encrypt" {
+        encrypt_file_with_stored_iv(&key, &iv_bytes_array, &input_file_path, &output_file_path, options)?;
+    } else {
+        decrypt_file_with_stored_iv(&key, &input_file_path, &output_file_path, options)?;
+    }
+    Ok(())
+}
 
-    } print!("]\n\n");
+// Function to draw a fresh IV from the operating system's random number
+// generator
+pub fn random_iv() -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    getrandom::getrandom(&mut iv).unwrap();
+    iv
+}
 
+/// AES-CTR position within one message, using a shared key schedule
+///
+/// Any number of these can run on the same `ExpandedKey`, also from several
+/// threads at once.
+pub struct AesCtr<'a> {
+    key: &'a ExpandedKey,
+    counter: u128,
 }
 
-// Function to properly print bytes
-fn println_bytes(name_str: &str, bytes: &Vec<u8>) {
-    print!("{}", name_str);
-    for b in bytes {
-        print!("{:02x}", b);
+impl<'a> AesCtr<'a> {
+
+    pub fn new(key: &'a ExpandedKey, iv: &[u8; 16]) -> AesCtr<'a> {
+        AesCtr { key, counter: u128::from_be_bytes(*iv) }
+    }
+
+    // Function to encrypt or decrypt the next part of the message in place,
+    // every part except the last one has to be a multiple of 16 bytes long
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        self.counter = apply_keystream(self.key, self.counter, data);
     }
-    print!("\n");
 }
 
-// Function to handle encryption/decryption command with given parameters
-pub fn handle_aes_ctr_command(_command: String,
-                              key_size: u16,
-                              key_bytes: Vec<u8>,
-                              iv_bytes: Vec<u8>,
-                              input_file_path: std::path::PathBuf,
-                              output_file_path: std::path::PathBuf) {
+// Function to encrypt or decrypt a caller-owned buffer in place with a
+// 128-bit, 192-bit or 256-bit key, the round keys only live on the stack, so
+// neither this nor anything it calls allocates on the heap, other key lengths
+// do not compile
+pub fn ctr_in_place<const N: usize>(key: &[u8; N], iv: &[u8; 16], data: &mut [u8]) {
+    const { assert!(is_aes_key_length(N), "AES key has to be 128-bit, 192-bit or 256-bit") };
+    let key = ExpandedKey::new(key);
+    apply_keystream(&key, u128::from_be_bytes(*iv), data);
+}
 
-    // definitions
-    let mut end_of_file = false;
-    let mut counter: u128 = 0;
+// Function to encrypt or decrypt a file with an already expanded key, fails
+// if the counter range was used before in the ledger of the options
+pub fn encrypt_file(key: &ExpandedKey,
+                    iv: &[u8; 16],
+                    input_file_path: &Path,
+                    output_file_path: &Path,
+                    options: CtrOptions) -> Result<(), CtrFileError> {
+    let max_length = reserve_input(key, iv, input_file_path, &options)?;
+    apply_keystream_file(key, IvPlacement::Given(*iv), input_file_path, output_file_path, options, max_length)
+}
 
-    // counter for counter mode
-    let mut iv_bytes_array = [0u8; 16];
-    iv_bytes_array.clone_from_slice(&iv_bytes[0..16]);
-    counter = u128::from_be_bytes(iv_bytes_array);
+// Function to encrypt a file under a random IV, which is written in front of
+// the ciphertext and returned
+pub fn encrypt_file_with_random_iv(key: &ExpandedKey,
+                                   input_file_path: &Path,
+                                   output_file_path: &Path,
+                                   options: CtrOptions) -> Result<[u8; 16], CtrFileError> {
+    let iv = random_iv();
+    encrypt_file_with_stored_iv(key, &iv, input_file_path, output_file_path, options)?;
+    Ok(iv)
+}
+
+// Function to encrypt a file with the IV written in front of the ciphertext,
+// after the magic
+pub fn encrypt_file_with_stored_iv(key: &ExpandedKey,
+                                   iv: &[u8; 16],
+                                   input_file_path: &Path,
+                                   output_file_path: &Path,
+                                   options: CtrOptions) -> Result<(), CtrFileError> {
+    let max_length = reserve_input(key, iv, input_file_path, &options)?;
+    apply_keystream_file(key, IvPlacement::Prepend(*iv), input_file_path, output_file_path, options, max_length)
+}
+
+// Function to decrypt a file written by `encrypt_file_with_random_iv`
+pub fn decrypt_file_with_stored_iv(key: &ExpandedKey,
+                                   input_file_path: &Path,
+                                   output_file_path: &Path,
+                                   options: CtrOptions) -> Result<(), CtrFileError> {
+    apply_keystream_file(key, IvPlacement::ReadFromInput, input_file_path, output_file_path, options, u64::MAX)
+}
+
+// Function to reserve the counter range of the input in the ledger of the
+// options, returns the number of input bytes the range covers
+fn reserve_input(key: &ExpandedKey, iv: &[u8; 16], input_file_path: &Path, options: &CtrOptions) -> Result<u64, LedgerError> {
+    match options.ledger {
+        Some(ledger) => ledger.reserve_file(key, u128::from_be_bytes(*iv), input_file_path),
+        None => Ok(u64::MAX),
+    }
+}
 
-    // expand keys
-    let key_count = if key_size == 128 { 11 } else { 15 };
-    let expanded_keys = key_expansion(key_bytes, key_count);
+// Function to apply the keystream to a file with the I/O path chosen by the
+// options, the output file is only created once the IV is known; at most
+// `max_length` bytes after the header are read, so a growing input never
+// uses counter blocks beyond the reserved range
+fn apply_keystream_file(key: &ExpandedKey,
+                        iv: IvPlacement,
+                        input_file_path: &Path,
+                        output_file_path: &Path,
+                        options: CtrOptions,
+                        max_length: u64) -> Result<(), CtrFileError> {
+
+    let thread_count = options.thread_count;
+    let buffer_size = stream_buffer_size(thread_count);
 
     // input file
-    let input_file = File::open(input_file_path).unwrap();
-    let mut reader = BufReader::new(input_file); //with_capacity(1048576, input_file); //148576
-    let mut read_count= 0;
-    let mut data_enc = 0u128;
-    let mut buffer: [u8; 16] = [0; 16];
+    let mut input_file = File::open(input_file_path)?;
+
+    // the header is skipped in the input or written in front of the output,
+    // padded for direct I/O
+    let mut header = vec![0u8; STORED_IV_HEADER_LENGTH];
+    let (counter, input_offset) = match iv {
+        IvPlacement::Given(iv) => {
+            header.clear();
+            (u128::from_be_bytes(iv), 0)
+        }
+        IvPlacement::Prepend(iv) => {
+            let magic = if options.direct { ALIGNED_STORED_IV_MAGIC } else { STORED_IV_MAGIC };
+            header[..magic.len()].copy_from_slice(magic);
+            header[magic.len()..].copy_from_slice(&iv);
+            if options.direct {
+                header.resize(ALIGNED_STORED_IV_HEADER_LENGTH, 0);
+            }
+            (u128::from_be_bytes(iv), 0)
+        }
+        IvPlacement::ReadFromInput => {
+            read_header_exact(&mut input_file, &mut header)?;
+            let header_length = match &header[..STORED_IV_MAGIC.len()] {
+                magic if magic == STORED_IV_MAGIC => STORED_IV_HEADER_LENGTH,
+                magic if magic == ALIGNED_STORED_IV_MAGIC => {
+                    read_header_exact(&mut input_file, &mut [0u8; ALIGNED_STORED_IV_HEADER_LENGTH - STORED_IV_HEADER_LENGTH])?;
+                    ALIGNED_STORED_IV_HEADER_LENGTH
+                }
+                _ => return Err(INVALID_HEADER.into()),
+            };
+            let iv = header[STORED_IV_MAGIC.len()..].try_into().unwrap();
+            header.clear();
+            (u128::from_be_bytes(iv), header_length)
+        }
+    };
+    let header = &header[..];
+
+    // memory mapped path for regular files, pipes and devices are streamed
+    if options.mmap && both_regular_files(&input_file, output_file_path) {
+        let output_file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                                            .open(output_file_path)?;
+        apply_keystream_mapped(key, counter, &input_file, input_offset, max_length, header, &output_file, buffer_size, thread_count)?;
+        return Ok(());
+    }
+
+    // direct I/O for regular files, falls back to buffered I/O below if the
+    // filesystem rejects O_DIRECT (which rewrites the whole output, the input
+    // file here was not read beyond the header); files with an unpadded
+    // stored IV header are not aligned and always use buffered I/O
+    #[cfg(target_os = "linux")]
+    if options.direct && input_offset.is_multiple_of(DIRECT_ALIGNMENT) && header.len().is_multiple_of(DIRECT_ALIGNMENT)
+       && both_regular_files(&input_file, output_file_path) {
+        match apply_keystream_direct(key, counter, input_file_path, input_offset as u64, header, output_file_path,
+                                     max_length, buffer_size, thread_count) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
 
     // output file
-    let output_file = File::create(output_file_path).unwrap();
-    let mut writer = BufWriter::new(output_file); //with_capacity(1048576,output_file); //148576
+    let mut output_file = File::create(output_file_path)?;
+    let input_file = input_file.take(max_length);
+
+    // the pipeline buffers are large enough to do without BufReader/BufWriter
+    if options.pipeline {
+        output_file.write_all(header)?;
+        apply_keystream_pipelined(key, counter, input_file, output_file,
+                                  options.buffer_count, options.buffer_size, thread_count)?;
+        return Ok(());
+    }
+
+    let mut reader = BufReader::new(input_file);
+    let mut writer = BufWriter::new(output_file);
+    writer.write_all(header)?;
+    apply_keystream_streamed(key, counter, &mut reader, &mut writer, buffer_size, thread_count)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Function to read a part of a stored IV header, a too short input has no
+// valid header
+fn read_header_exact(input_file: &mut File, buffer: &mut [u8]) -> Result<(), CtrFileError> {
+    match input_file.read_exact(buffer) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(INVALID_HEADER.into()),
+        result => Ok(result?),
+    }
+}
+
+// Function to apply the keystream to everything from the reader and write it
+// to the writer one buffer at a time, returns the counter of the next unused
+// keystream block
+pub(crate) fn apply_keystream_streamed<R: Read, W: Write>(cipher: &dyn BlockCipher,
+                                                          mut counter: u128,
+                                                          reader: &mut R,
+                                                          writer: &mut W,
+                                                          buffer_size: usize,
+                                                          thread_count: usize) -> io::Result<u128> {
+
+    let mut buffer = SecretBytes::zeroed(buffer_size);
+    let workers = keystream_workers(thread_count);
 
     loop {
 
-        // read one block of data
-        read_count = reader.read(&mut buffer).unwrap();
-        end_of_file = read_count != 16;
+        // read a full buffer (only the last one may be shorter)
+        let read_count = read_full(reader, buffer.as_mut_slice())?;
 
         // encrypt stuff
-        data_enc = encrypt_aes(counter, &expanded_keys) ^ u128::from_be_bytes(buffer);
-        counter += 1;
+        counter = apply_keystream_parallel(cipher, counter, &mut buffer.as_mut_slice()[..read_count], &workers);
+        writer.write_all(&buffer.as_slice()[..read_count])?;
 
         // end loop if end of file
-        if !end_of_file {
-            writer.write(&data_enc.to_be_bytes()).unwrap();
-        } else {
-            writer.write(&data_enc.to_be_bytes()[0..read_count]).unwrap();
+        if read_count < buffer.len() {
             break;
         }
     }
+
+    Ok(counter)
+}
+
+// Function to choose the size of the read buffer, large enough to give every
+// thread a chunk of its own
+pub(crate) fn stream_buffer_size(thread_count: usize) -> usize {
+    if thread_count > 1 { THREAD_CHUNK_SIZE * thread_count.min(MAX_THREAD_COUNT) } else { BUFFER_SIZE }
+}
+
+// Function to start the threads for `apply_keystream_parallel`, started once
+// per file and reused for every buffer
+pub(crate) fn keystream_workers(thread_count: usize) -> WorkerPool {
+    WorkerPool::new(thread_count.min(MAX_THREAD_COUNT))
+}
+
+// Function to check whether the input is a regular file and the output either
+// a regular file or not existing yet
+fn both_regular_files(input_file: &File, output_file_path: &Path) -> bool {
+
+    let input_is_file = input_file.metadata().map(|metadata| metadata.is_file()).unwrap_or(false);
+    let output_is_file = match std::fs::metadata(output_file_path) {
+        Ok(metadata) => metadata.is_file(),
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    };
+    input_is_file && output_is_file
+}
+
+// Function to size the output file like the input (without its first
+// `input_offset` bytes, and at most `max_length` bytes) plus the header, map
+// both and write the header followed by the input with the keystream applied,
+// one buffer at a time
+#[allow(clippy::too_many_arguments)]
+fn apply_keystream_mapped(cipher: &dyn BlockCipher,
+                          mut counter: u128,
+                          input_file: &File,
+                          input_offset: usize,
+                          max_length: u64,
+                          header: &[u8],
+                          output_file: &File,
+                          buffer_size: usize,
+                          thread_count: usize) -> io::Result<()> {
+
+    let input_length = input_file.metadata()?.len().min((input_offset as u64).saturating_add(max_length));
+    output_file.set_len(input_length - input_offset as u64 + header.len() as u64)?;
+
+    // empty files can not be mapped, leaving at most the header to write
+    if input_length == input_offset as u64 {
+        let mut writer = output_file;
+        return writer.write_all(header);
+    }
+
+    // the files are not expected to be modified by other processes meanwhile
+    let input_map = unsafe { Mmap::map(input_file) }?;
+    let mut output_map = unsafe { MmapMut::map_mut(output_file) }?;
+    output_map[..header.len()].copy_from_slice(header);
+    let workers = keystream_workers(thread_count);
+
+    for (input, output) in input_map[input_offset..input_length as usize].chunks(buffer_size).zip(output_map[header.len()..].chunks_mut(buffer_size)) {
+        counter = apply_keystream_parallel_into(cipher, counter, input, output, &workers);
+    }
+
+    output_map.flush()
+}
+
+// Function to read until the buffer is full or the end of the input is reached
+pub(crate) fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Function to xor the CTR keystream starting at the given counter onto a buffer,
+// returns the counter of the next unused keystream block
+//
+// The keystream is generated for PARALLEL_BLOCKS counters at a time and xored
+// word by word. Only the last call for a stream may end in a partial block.
+pub(crate) fn apply_keystream(cipher: &dyn BlockCipher, mut counter: u128, data: &mut [u8]) -> u128 {
+
+    let mut blocks = [0u128; PARALLEL_BLOCKS];
+    let mut keystream = [0u8; PARALLEL_BLOCKS * 16];
+
+    for chunk in data.chunks_mut(PARALLEL_BLOCKS * 16) {
+        generate_keystream(cipher, counter, &mut blocks, &mut keystream);
+        xor_in_place(chunk, &keystream[..chunk.len()]);
+        counter = counter.wrapping_add(chunk.len().div_ceil(16) as u128);
+    }
+
+    wipe(&mut keystream);
+    wipe_value(&mut blocks);
+    counter
+}
+
+// Function to write the input with the CTR keystream applied to an output of
+// the same length, like `apply_keystream` on a copy of the input without
+// writing the output twice
+pub(crate) fn apply_keystream_into(cipher: &dyn BlockCipher, mut counter: u128, input: &[u8], output: &mut [u8]) -> u128 {
+
+    assert_eq!(input.len(), output.len());
+
+    let mut blocks = [0u128; PARALLEL_BLOCKS];
+    let mut keystream = [0u8; PARALLEL_BLOCKS * 16];
+
+    for (input_chunk, output_chunk) in input.chunks(PARALLEL_BLOCKS * 16).zip(output.chunks_mut(PARALLEL_BLOCKS * 16)) {
+        generate_keystream(cipher, counter, &mut blocks, &mut keystream);
+        xor_into(output_chunk, input_chunk, &keystream[..input_chunk.len()]);
+        counter = counter.wrapping_add(input_chunk.len().div_ceil(16) as u128);
+    }
+
+    wipe(&mut keystream);
+    wipe_value(&mut blocks);
+    counter
+}
+
+// Function to encrypt the next PARALLEL_BLOCKS counters into the keystream
+fn generate_keystream(cipher: &dyn BlockCipher, counter: u128, blocks: &mut [u128; PARALLEL_BLOCKS],
+                      keystream: &mut [u8; PARALLEL_BLOCKS * 16]) {
+
+    for (i, block) in blocks.iter_mut().enumerate() {
+        *block = counter.wrapping_add(i as u128);
+    }
+    cipher.encrypt_blocks(blocks);
+
+    for (bytes, block) in keystream.chunks_exact_mut(16).zip(blocks.iter()) {
+        bytes.copy_from_slice(&block.to_be_bytes());
+    }
+}
+
+// Function to split a buffer into one contiguous range per worker thread and
+// apply the keystream to all ranges concurrently, each starting at its own
+// counter
+//
+// The result is identical to `apply_keystream` on the whole buffer.
+pub(crate) fn apply_keystream_parallel(cipher: &dyn BlockCipher, counter: u128, data: &mut [u8], workers: &WorkerPool) -> u128 {
+
+    let blocks_per_thread = blocks_per_thread(data.len(), workers);
+    if blocks_per_thread == 0 {
+        return apply_keystream(cipher, counter, data);
+    }
+
+    let tasks: Vec<Task> = data.chunks_mut(blocks_per_thread * 16).enumerate().map(|(i, range)| {
+        let start = counter.wrapping_add((i * blocks_per_thread) as u128);
+        Box::new(move || { apply_keystream(cipher, start, range); }) as Task
+    }).collect();
+    workers.run(tasks);
+
+    counter.wrapping_add(data.len().div_ceil(16) as u128)
+}
+
+// Function to run `apply_keystream_into` on one range per worker thread, the
+// result is identical to `apply_keystream_into` on the whole input
+pub(crate) fn apply_keystream_parallel_into(cipher: &dyn BlockCipher, counter: u128, input: &[u8], output: &mut [u8],
+                                            workers: &WorkerPool) -> u128 {
+
+    let blocks_per_thread = blocks_per_thread(input.len(), workers);
+    if blocks_per_thread == 0 {
+        return apply_keystream_into(cipher, counter, input, output);
+    }
+
+    let ranges = input.chunks(blocks_per_thread * 16).zip(output.chunks_mut(blocks_per_thread * 16));
+    let tasks: Vec<Task> = ranges.enumerate().map(|(i, (input_range, output_range))| {
+        let start = counter.wrapping_add((i * blocks_per_thread) as u128);
+        Box::new(move || { apply_keystream_into(cipher, start, input_range, output_range); }) as Task
+    }).collect();
+    workers.run(tasks);
+
+    counter.wrapping_add(input.len().div_ceil(16) as u128)
+}
+
+// Function to return the number of blocks every worker thread gets from a
+// buffer, zero if the buffer is not worth splitting
+fn blocks_per_thread(length: usize, workers: &WorkerPool) -> usize {
+
+    let thread_count = workers.thread_count();
+    if thread_count == 1 {
+        return 0;
+    }
+    length.div_ceil(16).div_ceil(thread_count)
+}
+
+// Function to xor a keystream onto a buffer of the same length, 64 bits at a time
+pub(crate) fn xor_in_place(data: &mut [u8], keystream: &[u8]) {
+
+    let mut data_words = data.chunks_exact_mut(8);
+    let mut keystream_words = keystream.chunks_exact(8);
+
+    for (d, k) in (&mut data_words).zip(&mut keystream_words) {
+        let word = u64::from_ne_bytes((*d).try_into().unwrap()) ^ u64::from_ne_bytes(k.try_into().unwrap());
+        d.copy_from_slice(&word.to_ne_bytes());
+    }
+
+    for (d, k) in data_words.into_remainder().iter_mut().zip(keystream_words.remainder()) {
+        *d ^= *k;
+    }
+}
+
+// Function to write the xor of an input and a keystream of the same length to
+// the output, 64 bits at a time
+fn xor_into(output: &mut [u8], input: &[u8], keystream: &[u8]) {
+
+    let mut output_words = output.chunks_exact_mut(8);
+    let mut input_words = input.chunks_exact(8);
+    let mut keystream_words = keystream.chunks_exact(8);
+
+    for ((o, i), k) in (&mut output_words).zip(&mut input_words).zip(&mut keystream_words) {
+        let word = u64::from_ne_bytes(i.try_into().unwrap()) ^ u64::from_ne_bytes(k.try_into().unwrap());
+        o.copy_from_slice(&word.to_ne_bytes());
+    }
+
+    for ((o, i), k) in output_words.into_remainder().iter_mut().zip(input_words.remainder()).zip(keystream_words.remainder()) {
+        *o = *i ^ *k;
+    }
+}
+
+#[test]
+fn test_apply_keystream_matches_single_blocks() {
+
+    // counter close to the wrap-around and lengths around the batch size
+    let key: Vec<u8> = (0..16).collect();
+    let expanded_keys = key_expansion(key.clone(), 11);
+    let start = u128::MAX - 5;
+
+    for backend in crate::aes_backend::ALL_BACKENDS.iter().filter(|backend| backend.is_available()) {
+        let cipher = ExpandedKey::with_backend(*backend, &key);
+        for length in [0, 1, 15, 16, 17, 127, 128, 129, 1000].iter() {
+            let mut data = vec![0x3c; *length];
+            let next = apply_keystream(&cipher, start, &mut data);
+            assert_eq!(next, start.wrapping_add(length.div_ceil(16) as u128));
+
+            for (i, chunk) in data.chunks(16).enumerate() {
+                let keystream = encrypt_aes(start.wrapping_add(i as u128), &expanded_keys).to_be_bytes();
+                for (d, k) in chunk.iter().zip(keystream.iter()) {
+                    assert_eq!(*d, 0x3c ^ *k);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_apply_keystream_parallel_matches_sequential() {
+
+    let key: Vec<u8> = (0..32).collect();
+    let cipher = ExpandedKey::new(&key);
+    let start = u128::MAX - 100;
+
+    // the same workers for every buffer
+    for thread_count in [1, 2, 3, 8].iter() {
+        let workers = keystream_workers(*thread_count);
+        for length in [0, 15, 16, 100, 4095, 4096, 70001].iter() {
+            let mut expected: Vec<u8> = (0..*length).map(|i| i as u8).collect();
+            let mut parallel = expected.clone();
+            let next = apply_keystream(&cipher, start, &mut expected);
+            let mut output = vec![0u8; *length];
+            assert_eq!(apply_keystream_parallel_into(&cipher, start, &parallel, &mut output, &workers), next);
+            assert_eq!(output, expected, "{} bytes on {} threads", length, thread_count);
+            assert_eq!(apply_keystream_parallel(&cipher, start, &mut parallel, &workers), next);
+            assert_eq!(parallel, expected, "{} bytes on {} threads", length, thread_count);
+        }
+    }
+
+    // huge thread counts do not size the buffer beyond the thread limit
+    assert_eq!(stream_buffer_size(usize::MAX), THREAD_CHUNK_SIZE * MAX_THREAD_COUNT);
+}
+
+#[test]
+fn test_expanded_key_shared_between_threads() {
+
+    let key: Vec<u8> = (0..32).collect();
+    let shared = ExpandedKey::new(&key);
+
+    // one message per thread, all on the same key schedule
+    let messages: Vec<Vec<u8>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..4u8).map(|i| {
+            let shared = &shared;
+            scope.spawn(move || {
+                let mut message = vec![i; 100];
+                let mut ctr = AesCtr::new(shared, &[i; 16]);
+                ctr.apply_keystream(&mut message[..64]);
+                ctr.apply_keystream(&mut message[64..]);
+                message
+            })
+        }).collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+
+    for (i, message) in messages.iter().enumerate() {
+        let mut expected = vec![i as u8; 100];
+        apply_keystream(&ExpandedKey::new(&key), u128::from_be_bytes([i as u8; 16]), &mut expected);
+        assert_eq!(*message, expected);
+    }
+}
+
+// allocation counter of the current thread, as the tests run in parallel
+#[cfg(test)]
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+struct CountingAllocator;
+
+#[cfg(test)]
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_in_place_encryption_does_not_allocate() {
+
+    let key128 = [0x2b; 16];
+    let key256 = [0x60; 32];
+    let iv = [0xf0; 16];
+    let mut data = [0x5au8; 1000];
+
+    // first calls run the CPU feature detection
+    ctr_in_place(&key128, &iv, &mut data);
+
+    let before = ALLOCATIONS.with(|count| count.get());
+    ctr_in_place(&key128, &iv, &mut data);
+    ctr_in_place(&key256, &iv, &mut data[..999]);
+    ctr_in_place(&[0x8e; 24], &iv, &mut data);
+    for backend in crate::aes_backend::ALL_BACKENDS.iter().filter(|backend| backend.is_available()) {
+        let key = ExpandedKey::with_backend(*backend, &key256);
+        AesCtr::new(&key, &iv).apply_keystream(&mut data);
+    }
+    assert_eq!(ALLOCATIONS.with(|count| count.get()), before);
+
+    // the allocator does see allocations
+    let vector = vec![0u8; 16];
+    assert_eq!(ALLOCATIONS.with(|count| count.get()), before + 1);
+    drop(vector);
+}
+
+#[test]
+fn test_mmap_matches_streaming() {
+
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("mmap");
+    let input = directory.join("input");
+    let streamed = directory.join("streamed");
+    let mapped = directory.join("mapped");
+    let key: Vec<u8> = (0..16).collect();
+    let iv = vec![0xfe; 16];
+
+    for length in [0, 1, 100_003].iter() {
+        let data: Vec<u8> = (0..*length).map(|i| (i * 7) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        handle_aes_ctr_command(String::from("encrypt"), 128, SecretBytes::from_slice(&key), iv.clone(),
+                               input.clone(), streamed.clone(), CtrOptions::default()).unwrap();
+        handle_aes_ctr_command(String::from("encrypt"), 128, SecretBytes::from_slice(&key), iv.clone(),
+                               input.clone(), mapped.clone(), CtrOptions { thread_count: 2, mmap: true, ..CtrOptions::default() }).unwrap();
+
+        assert_eq!(std::fs::read(&streamed).unwrap(), std::fs::read(&mapped).unwrap());
+        assert_eq!(std::fs::read(&mapped).unwrap().len(), *length);
+    }
+}
+
+#[test]
+fn test_stored_iv_round_trip() {
+
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("stored-iv");
+    let input = directory.join("input");
+    let encrypted = directory.join("encrypted");
+    let decrypted = directory.join("decrypted");
+    let key = ExpandedKey::new(&[0x24u8; 32]);
+    let paths = [CtrOptions::default(),
+                 CtrOptions { mmap: true, ..CtrOptions::default() },
+                 CtrOptions { pipeline: true, buffer_count: 2, buffer_size: 4096, ..CtrOptions::default() },
+                 CtrOptions { direct: true, ..CtrOptions::default() }];
+
+    for length in [0, 1, 100_003].iter() {
+        let data: Vec<u8> = (0..*length).map(|i| (i * 11) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        // every I/O path reads the IV written by every other one
+        for encrypt_options in paths.iter() {
+            let iv = encrypt_file_with_random_iv(&key, &input, &encrypted, *encrypt_options).unwrap();
+            let ciphertext = std::fs::read(&encrypted).unwrap();
+            let (magic, header_length) = if encrypt_options.direct { (b"AESCTRIA", ALIGNED_STORED_IV_HEADER_LENGTH) }
+                                         else { (b"AESCTRIV", STORED_IV_HEADER_LENGTH) };
+            assert_eq!(ciphertext[..8], *magic);
+            assert_eq!(ciphertext[8..STORED_IV_HEADER_LENGTH], iv);
+            assert_eq!(ciphertext.len(), header_length + *length);
+
+            for decrypt_options in paths.iter() {
+                decrypt_file_with_stored_iv(&key, &encrypted, &decrypted, *decrypt_options).unwrap();
+                assert_eq!(std::fs::read(&decrypted).unwrap(), data);
+            }
+        }
+    }
+
+    // fresh IVs for every file, and inputs without the header are rejected
+    // before the output file is touched
+    assert_ne!(random_iv(), random_iv());
+    std::fs::write(&decrypted, b"existing").unwrap();
+    encrypt_file(&key, &[0u8; 16], &input, &encrypted, CtrOptions::default()).unwrap();
+    assert!(matches!(decrypt_file_with_stored_iv(&key, &encrypted, &decrypted, CtrOptions::default()),
+                     Err(CtrFileError::InvalidHeader(INVALID_HEADER))));
+    std::fs::write(&input, b"AESCTRIV").unwrap();
+    assert!(matches!(decrypt_file_with_stored_iv(&key, &input, &decrypted, CtrOptions::default()),
+                     Err(CtrFileError::InvalidHeader(INVALID_HEADER))));
+    assert_eq!(std::fs::read(&decrypted).unwrap(), b"existing");
+
+    // a missing input or an output that can not be created is an error, not a panic
+    let missing = directory.join("missing");
+    assert!(matches!(encrypt_file_with_random_iv(&key, &missing, &encrypted, CtrOptions::default()),
+                     Err(CtrFileError::Io(_))));
+    assert!(matches!(encrypt_file_with_random_iv(&key, &input, &missing.join("output"), CtrOptions::default()),
+                     Err(CtrFileError::Io(_))));
+}
+
+#[test]
+fn test_ledger_limits_encrypted_length() {
+
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("ctr-ledger");
+    let input = directory.join("input");
+    let encrypted = directory.join("encrypted");
+    let ledger = IvLedger::new(&directory.join("ledger"));
+    let key = ExpandedKey::new(&[0x42u8; 16]);
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 3) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+
+    // a second encryption with the same key and IV is refused
+    let options = CtrOptions { ledger: Some(&ledger), ..CtrOptions::default() };
+    encrypt_file(&key, &[7u8; 16], &input, &encrypted, options).unwrap();
+    assert!(matches!(encrypt_file(&key, &[7u8; 16], &input, &encrypted, options),
+                     Err(CtrFileError::Ledger(LedgerError::Reuse { .. }))));
+    assert!(matches!(encrypt_file_with_stored_iv(&key, &[7u8; 16], &input, &encrypted, options),
+                     Err(CtrFileError::Ledger(LedgerError::Reuse { .. }))));
+
+    // every I/O path stops at the reserved length, also if the input is longer
+    let mut expected = data[..1000].to_vec();
+    apply_keystream(&key, 7, &mut expected);
+    for options in [CtrOptions::default(),
+                    CtrOptions { mmap: true, ..CtrOptions::default() },
+                    CtrOptions { pipeline: true, buffer_count: 2, buffer_size: 4096, ..CtrOptions::default() },
+                    CtrOptions { direct: true, ..CtrOptions::default() }].iter() {
+        apply_keystream_file(&key, IvPlacement::Given(7u128.to_be_bytes()), &input, &encrypted, *options, 1000).unwrap();
+        assert_eq!(std::fs::read(&encrypted).unwrap(), expected);
+    }
 }
 
 #[test]
@@ -388,81 +928,50 @@ fn test_key_expand_128_vector() {
     assert_eq!(generated_keys[7], 0x2a6c7605 as u32);
 }
 
-fn key_expansion(input_key: Vec<u8>, key_count: usize) -> Vec<u32> {
+// Function to expand a key into `key_count` round keys of four words each
+pub(crate) fn key_expansion(input_key: Vec<u8>, key_count: usize) -> Vec<u32> {
+    expand_key_words(&input_key)[..key_count * 4].to_vec()
+}
 
-    let mut return_keys = Vec::new();
-    let n = if key_count == 11 {16} else { 32 };
+// expanded key words, AES-128 only uses the first 44 of them
+pub(crate) type RoundKeyWords = [u32; 60];
 
-    let mut iteration = 1;
-    let mut generated_count = 0;
-    let mut temp: [u8; 4];
-    let mut holder = 0u8;
+// Function to run the key expansion of a 128-bit, 192-bit or 256-bit key into
+// a fixed size array, without any heap allocation
+pub(crate) fn expand_key_words(key: &[u8]) -> RoundKeyWords {
 
-    // copy input key to output as first 16 bytes
-    for byte in input_key.iter() {
-        return_keys.push(*byte);
-        generated_count += 1;
-    }
+    let n = key.len() / 4;
+    let mut words = [0u32; 60];
 
-    // generate new key bytes
-    while generated_count < 16 * key_count {
+    // copy input key to output as first words
+    for (word, bytes) in words.iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
 
-        // copy last 4 bytes of previous key to temp
-        temp = [*return_keys.get(return_keys.len() -4).unwrap(),
-                *return_keys.get(return_keys.len() -3).unwrap(),
-                *return_keys.get(return_keys.len() -2).unwrap(),
-                *return_keys.get(return_keys.len() -1).unwrap()];
+    for i in n..4 * (n + 7) {
+        let mut temp = words[i - 1];
 
         // run the core method if a complete key was generated in last iteration
-        if generated_count % n == 0 {
-            holder = temp[0];
-
-            // sbox four bytes rotatet left for one bit
-            temp[0] = SUBSTITUTION[temp[1] as usize];
-            temp[1] = SUBSTITUTION[temp[2] as usize];
-            temp[2] = SUBSTITUTION[temp[3] as usize];
-            temp[3] = SUBSTITUTION[holder as usize];
-
-            // rcon
-            temp[0] ^= RCON[iteration as usize];
-
-            iteration += 1;
-        } else if key_count > 11 && generated_count % 16 == 0 {
-            temp[0] = SUBSTITUTION[temp[0] as usize];
-            temp[1] = SUBSTITUTION[temp[1] as usize];
-            temp[2] = SUBSTITUTION[temp[2] as usize];
-            temp[3] = SUBSTITUTION[temp[3] as usize];
+        if i % n == 0 {
+            temp = substitute_word(temp.rotate_left(8)) ^ (RCON[i / n] as u32) << 24;
+        } else if n > 6 && i % n == 4 {
+            temp = substitute_word(temp);
         }
 
-        // xor 4 new bytes to first 4 bytes of the last generated key
-        temp[0] ^= return_keys[return_keys.len() - n];
-        temp[1] ^= return_keys[return_keys.len() - n + 1];
-        temp[2] ^= return_keys[return_keys.len() - n + 2];
-        temp[3] ^= return_keys[return_keys.len() - n + 3];
-
-        // append buffer
-        return_keys.push(temp[0]);
-        return_keys.push(temp[1]);
-        return_keys.push(temp[2]);
-        return_keys.push(temp[3]);
-
-        // count up generated byte number
-        generated_count += 4;
-
-    }
-
-    // cast to u32 vec
-    let mut return_u32 = Vec::new();
-    for i in 0..key_count*4 {
-        return_u32.push(
-            ((return_keys[(i * 4)] as u32) << 24) | ((return_keys[(i*4) + 1] as u32) << 16) | ((return_keys[(i*4) + 2] as u32) << 8) | (return_keys[(i*4) + 3] as u32)
-        );
+        // xor to the word one key length back
+        words[i] = words[i - n] ^ temp;
     }
 
-    return_u32
+    words
+}
 
+fn substitute_word(word: u32) -> u32 {
+    let bytes = word.to_be_bytes();
+    u32::from_be_bytes([SUBSTITUTION[bytes[0] as usize], SUBSTITUTION[bytes[1] as usize],
+                        SUBSTITUTION[bytes[2] as usize], SUBSTITUTION[bytes[3] as usize]])
 }
 
+#[allow(dead_code)]
 fn add_round_key(word: &mut [u8], key: &[u8]) {
 
     for (w, k) in word.iter_mut().zip(key.iter()) {
@@ -472,6 +981,7 @@ fn add_round_key(word: &mut [u8], key: &[u8]) {
 
 }
 
+#[allow(dead_code)]
 fn substitute_bytes(word: &mut [u8]) {
 
     for w in word.iter_mut() {
@@ -480,6 +990,7 @@ fn substitute_bytes(word: &mut [u8]) {
 
 }
 
+#[allow(dead_code)]
 fn shift_rows(word: &mut [u8]) {
 
     let mut temp: [u8; 16] = [0; 16];
@@ -510,6 +1021,7 @@ fn shift_rows(word: &mut [u8]) {
 
 }
 
+#[allow(dead_code, clippy::manual_memcpy)]
 fn mix_columns(word: &mut [u8]) {
     let mut temp = [0; 16];
 
@@ -539,11 +1051,11 @@ fn mix_columns(word: &mut [u8]) {
 
 }
 
-fn encrypt_aes(word_num: u128, keys_vector: &[u32]) -> u128 {
+pub(crate) fn encrypt_aes(word_num: u128, keys_vector: &[u32]) -> u128 {
 
     // init
     let mut round_counter = 0;
-    let mut word = word_num.to_be_bytes();
+    let word = word_num.to_be_bytes();
 
     let mut s0: u32 = u32::from_be_bytes(word[0..4].try_into().unwrap());
     let mut s1: u32 = u32::from_be_bytes(word[4..8].try_into().unwrap());
@@ -614,3 +1126,81 @@ fn encrypt_aes(word_num: u128, keys_vector: &[u32]) -> u128 {
     (s0 as u128) << 96 | (s1 as u128) << 64 | (s2 as u128) << 32 | (s3 as u128)
 
 }
+
+// Function to compute a single AES encryption round (SubBytes, ShiftRows,
+// MixColumns and AddRoundKey) on one block, as used by e.g. AEGIS
+pub fn aes_round(block: u128, round_key: u128) -> u128 {
+
+    let s0 = (block >> 96) as u32;
+    let s1 = (block >> 64) as u32;
+    let s2 = (block >> 32) as u32;
+    let s3 = block as u32;
+
+    let tmp0 = T_0[((s0 >> 24) as u8) as usize]
+             ^ T_1[((s1 >> 16) as u8) as usize]
+             ^ T_2[((s2 >> 8) as u8) as usize]
+             ^ T_3[((s3) as u8) as usize];
+
+    let tmp1 = T_0[((s1 >> 24) as u8) as usize]
+             ^ T_1[((s2 >> 16) as u8) as usize]
+             ^ T_2[((s3 >> 8) as u8) as usize]
+             ^ T_3[((s0) as u8) as usize];
+
+    let tmp2 = T_0[((s2 >> 24) as u8) as usize]
+             ^ T_1[((s3 >> 16) as u8) as usize]
+             ^ T_2[((s0 >> 8) as u8) as usize]
+             ^ T_3[((s1) as u8) as usize];
+
+    let tmp3 = T_0[((s3 >> 24) as u8) as usize]
+             ^ T_1[((s0 >> 16) as u8) as usize]
+             ^ T_2[((s1 >> 8) as u8) as usize]
+             ^ T_3[((s2) as u8) as usize];
+
+    ((tmp0 as u128) << 96 | (tmp1 as u128) << 64 | (tmp2 as u128) << 32 | (tmp3 as u128)) ^ round_key
+}
+
+// Function to encrypt four independent blocks with interleaved rounds, so the
+// table lookups of neighbouring counter blocks can overlap in the CPU
+pub(crate) fn encrypt_aes_x4(words: [u128; 4], keys_vector: &[u32]) -> [u128; 4] {
+
+    let mut s = [[0u32; 4]; 4];
+    let mut tmp = [[0u32; 4]; 4];
+
+    // init and first add round key
+    for (state, word) in s.iter_mut().zip(words.iter()) {
+        for (i, column) in state.iter_mut().enumerate() {
+            *column = (*word >> (96 - 32 * i)) as u32 ^ keys_vector[i];
+        }
+    }
+
+    // rounds
+    let last_round = keys_vector.len() / 4 - 1;
+    for round_counter in 1..last_round {
+
+        let k = &keys_vector[round_counter * 4..round_counter * 4 + 4];
+
+        for (t, s) in tmp.iter_mut().zip(s.iter()) {
+            t[0] = T_0[(s[0] >> 24) as usize] ^ T_1[((s[1] >> 16) as u8) as usize] ^ T_2[((s[2] >> 8) as u8) as usize] ^ T_3[(s[3] as u8) as usize] ^ k[0];
+            t[1] = T_0[(s[1] >> 24) as usize] ^ T_1[((s[2] >> 16) as u8) as usize] ^ T_2[((s[3] >> 8) as u8) as usize] ^ T_3[(s[0] as u8) as usize] ^ k[1];
+            t[2] = T_0[(s[2] >> 24) as usize] ^ T_1[((s[3] >> 16) as u8) as usize] ^ T_2[((s[0] >> 8) as u8) as usize] ^ T_3[(s[1] as u8) as usize] ^ k[2];
+            t[3] = T_0[(s[3] >> 24) as usize] ^ T_1[((s[0] >> 16) as u8) as usize] ^ T_2[((s[1] >> 8) as u8) as usize] ^ T_3[(s[2] as u8) as usize] ^ k[3];
+        }
+
+        s = tmp;
+    }
+
+    // sbox, shift and last add round key
+    let k = &keys_vector[last_round * 4..last_round * 4 + 4];
+    let mut output = [0u128; 4];
+
+    for (out, s) in output.iter_mut().zip(s.iter()) {
+        let sub = |word: u32, shift: u32| (SUBSTITUTION[((word >> shift) as u8) as usize] as u32) << shift;
+        let c0 = (sub(s[0], 24) | sub(s[1], 16) | sub(s[2], 8) | sub(s[3], 0)) ^ k[0];
+        let c1 = (sub(s[1], 24) | sub(s[2], 16) | sub(s[3], 8) | sub(s[0], 0)) ^ k[1];
+        let c2 = (sub(s[2], 24) | sub(s[3], 16) | sub(s[0], 8) | sub(s[1], 0)) ^ k[2];
+        let c3 = (sub(s[3], 24) | sub(s[0], 16) | sub(s[1], 8) | sub(s[2], 0)) ^ k[3];
+        *out = (c0 as u128) << 96 | (c1 as u128) << 64 | (c2 as u128) << 32 | (c3 as u128);
+    }
+
+    output
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::aes_backend::{assert_aes_key_length, is_aes_key_length};
+use crate::header_file::{self, apply_header_keystream};
+use crate::kdf::{CostLimitExceeded, KdfLimits, KdfParams, SALT_LENGTH};
+use crate::secret::SecretBytes;
+
+// Passphrase-based AES-CTR file encryption
+//
+// File layout:
+//
+//     magic (8 bytes) || KDF id and parameters || salt (16 bytes)
+//     || key size in bytes (1 byte) || IV flag (1 byte) || IV (16 bytes, only if the flag is 1)
+//     || ciphertext
+//
+// The KDF output is the AES key, followed by the IV if no IV is stored. The
+// random salt makes every key unique, so deriving the IV as well is safe.
+// Like plain AES-CTR this mode has no integrity protection: a wrong
+// passphrase is not detected and yields garbage. Decryption refuses KDF
+// parameters above the given limits before deriving anything.
+
+const MAGIC: &[u8; 8] = b"AESCTRPW";
+
+pub use crate::header_file::InvalidHeader;
+
+const INVALID_HEADER: InvalidHeader = InvalidHeader("passphrase");
+
+/// Errors of the decryption, all but I/O errors detected before any output is written
+#[derive(Debug)]
+pub enum DecryptionError {
+    InvalidHeader,
+    CostLimitExceeded(KdfParams),
+    Io(io::Error),
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecryptionError::InvalidHeader => INVALID_HEADER.fmt(f),
+            DecryptionError::CostLimitExceeded(params) => CostLimitExceeded(*params).fmt(f),
+            DecryptionError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DecryptionError {}
+
+impl From<InvalidHeader> for DecryptionError {
+    fn from(_: InvalidHeader) -> DecryptionError {
+        DecryptionError::InvalidHeader
+    }
+}
+
+impl From<io::Error> for DecryptionError {
+    fn from(e: io::Error) -> DecryptionError {
+        DecryptionError::Io(e)
+    }
+}
+
+impl From<CostLimitExceeded> for DecryptionError {
+    fn from(e: CostLimitExceeded) -> DecryptionError {
+        DecryptionError::CostLimitExceeded(e.0)
+    }
+}
+
+/// Parameters needed to derive key and IV from the passphrase again
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassphraseHeader {
+    pub kdf: KdfParams,
+    pub salt: [u8; SALT_LENGTH],
+    // AES key length in bytes (16, 24 or 32)
+    pub key_length: usize,
+    // explicitly chosen IV, derived from the passphrase if not given
+    pub iv: Option<[u8; 16]>,
+}
+
+impl PassphraseHeader {
+
+    // Function to set up a header with a fresh random salt
+    pub fn new(kdf: KdfParams, key_length: usize, iv: Option<[u8; 16]>) -> PassphraseHeader {
+
+        assert_aes_key_length(key_length);
+
+        let mut salt = [0u8; SALT_LENGTH];
+        getrandom::getrandom(&mut salt).unwrap();
+        PassphraseHeader { kdf, salt, key_length, iv }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.kdf.to_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.push(self.key_length as u8);
+        match &self.iv {
+            Some(iv) => {
+                bytes.push(1);
+                bytes.extend_from_slice(iv);
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    // Function to read a header written by `to_bytes`
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<PassphraseHeader, InvalidHeader> {
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(|_| INVALID_HEADER)?;
+        if &magic != MAGIC {
+            return Err(INVALID_HEADER);
+        }
+
+        let kdf = KdfParams::read_from(reader).map_err(|_| INVALID_HEADER)?;
+        let mut salt = [0u8; SALT_LENGTH];
+        let mut flags = [0u8; 2];
+        reader.read_exact(&mut salt).map_err(|_| INVALID_HEADER)?;
+        reader.read_exact(&mut flags).map_err(|_| INVALID_HEADER)?;
+
+        let key_length = flags[0] as usize;
+        let iv = match flags[1] {
+            0 => None,
+            1 => {
+                let mut iv = [0u8; 16];
+                reader.read_exact(&mut iv).map_err(|_| INVALID_HEADER)?;
+                Some(iv)
+            }
+            _ => return Err(INVALID_HEADER),
+        };
+        if !is_aes_key_length(key_length) {
+            return Err(INVALID_HEADER);
+        }
+
+        Ok(PassphraseHeader { kdf, salt, key_length, iv })
+    }
+
+    // Function to run the KDF and return the AES key and the IV
+    pub fn derive(&self, passphrase: &[u8]) -> (SecretBytes, [u8; 16]) {
+
+        let derived_length = self.key_length + if self.iv.is_some() { 0 } else { 16 };
+        let mut derived = SecretBytes::zeroed(derived_length);
+        self.kdf.derive(passphrase, &self.salt, derived.as_mut_slice());
+
+        let key = SecretBytes::from_slice(&derived.as_slice()[..self.key_length]);
+        let iv = match self.iv {
+            Some(iv) => iv,
+            None => {
+                let mut iv = [0u8; 16];
+                iv.copy_from_slice(&derived.as_slice()[self.key_length..]);
+                iv
+            }
+        };
+        (key, iv)
+    }
+}
+
+// Function to encrypt from a reader to a writer, header first
+pub fn encrypt<R: Read, W: Write>(passphrase: &[u8], header: &PassphraseHeader, reader: &mut R, writer: &mut W,
+                                  thread_count: usize) -> io::Result<()> {
+
+    let (key, iv) = header.derive(passphrase);
+    writer.write_all(&header.to_bytes())?;
+    apply_header_keystream(key, iv, reader, writer, thread_count)
+}
+
+// Function to read the header from a reader and decrypt the rest into a writer,
+// if the KDF of the header stays within the limits
+pub fn decrypt<R: Read, W: Write>(passphrase: &[u8], limits: &KdfLimits, reader: &mut R, writer: &mut W,
+                                  thread_count: usize) -> Result<(), DecryptionError> {
+
+    let header = read_header_within(reader, limits)?;
+    decrypt_body(passphrase, &header, reader, writer, thread_count)?;
+    Ok(())
+}
+
+// Function to read the header and check its KDF against the limits
+fn read_header_within<R: Read>(reader: &mut R, limits: &KdfLimits) -> Result<PassphraseHeader, DecryptionError> {
+    let header = PassphraseHeader::read_from(reader)?;
+    header.kdf.check_limits(limits)?;
+    Ok(header)
+}
+
+// Function to decrypt everything after the header
+fn decrypt_body<R: Read, W: Write>(passphrase: &[u8], header: &PassphraseHeader, reader: &mut R, writer: &mut W,
+                                   thread_count: usize) -> io::Result<()> {
+    let (key, iv) = header.derive(passphrase);
+    apply_header_keystream(key, iv, reader, writer, thread_count)
+}
+
+// Function to encrypt a file with a key derived from the passphrase
+pub fn encrypt_file(passphrase: &[u8], header: &PassphraseHeader, input_file_path: &Path, output_file_path: &Path,
+                    thread_count: usize) -> io::Result<()> {
+    header_file::encrypt_file(input_file_path, output_file_path,
+                              |reader, writer| encrypt(passphrase, header, reader, writer, thread_count))
+}
+
+// Function to decrypt a file written by `encrypt_file`, the output file is
+// only created once the header was read successfully
+pub fn decrypt_file(passphrase: &[u8], limits: &KdfLimits, input_file_path: &Path, output_file_path: &Path,
+                    thread_count: usize) -> Result<(), DecryptionError> {
+    header_file::decrypt_file(input_file_path, output_file_path, |reader| read_header_within(reader, limits),
+                              |header, reader, writer| decrypt_body(passphrase, &header, reader, writer, thread_count))
+}
+
+#[test]
+fn test_passphrase_round_trip() {
+
+    use crate::header_file::check_round_trip;
+
+    let plaintext: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+    let kdf = KdfParams::Pbkdf2 { iterations: 10 };
+
+    for header in [PassphraseHeader::new(kdf, 16, None), PassphraseHeader::new(kdf, 24, None),
+                   PassphraseHeader::new(kdf, 32, Some([0xab; 16]))].iter() {
+        let header_length = header.to_bytes().len();
+        let encrypted = check_round_trip(&plaintext, header_length,
+                                         |reader, writer| encrypt(b"correct horse", header, reader, writer, 2),
+                                         |passphrase, reader, writer| decrypt(passphrase, &KdfLimits::DEFAULT, reader, writer, 1),
+                                         b"correct horse", b"wrong horse");
+
+        // the header is stored in front of the ciphertext
+        assert_eq!(&encrypted[..header_length], &header.to_bytes()[..]);
+        assert_eq!(PassphraseHeader::read_from(&mut &encrypted[..]).unwrap(), *header);
+
+        // a header asking for more work than allowed is refused before deriving
+        let limits = KdfLimits { pbkdf2_iterations: 9, ..KdfLimits::DEFAULT };
+        assert!(matches!(decrypt(b"correct horse", &limits, &mut &encrypted[..], &mut Vec::new(), 1),
+                         Err(DecryptionError::CostLimitExceeded(params)) if params == kdf));
+    }
+
+    // fresh salts give different keys for the same passphrase
+    let first = PassphraseHeader::new(kdf, 16, None);
+    let second = PassphraseHeader::new(kdf, 16, None);
+    assert_ne!(first.derive(b"pw").0.as_slice(), second.derive(b"pw").0.as_slice());
+
+    assert!(matches!(decrypt(b"pw", &KdfLimits::NONE, &mut &b"AESCTRPX"[..], &mut Vec::new(), 1),
+                     Err(DecryptionError::InvalidHeader)));
+}
@@ -0,0 +1,140 @@
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use crate::aes_backend::BlockCipher;
+use crate::aes_ctr_optimized::{apply_keystream_parallel, keystream_workers, read_full};
+use crate::secret::SecretBytes;
+
+// Three-stage AES-CTR pipeline: a reader thread, a cipher thread and a writer
+// thread pass a fixed set of buffers around, so reading, encrypting and
+// writing overlap. Buffers are handed back to the reader after they were
+// written, which bounds the memory use to `buffer_count * buffer_size`.
+// Every buffer is wiped when it is dropped, wherever the pipeline stops.
+
+pub const DEFAULT_BUFFER_COUNT: usize = 4;
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Function to apply the keystream to everything from the reader and write it
+// to the writer, returns the counter of the next unused keystream block
+//
+// The buffer size has to be a multiple of the block size, as every buffer
+// except the last one continues the keystream of the previous one. The first
+// read or write error stops all stages and is returned.
+pub(crate) fn apply_keystream_pipelined<R: Read + Send, W: Write + Send>(cipher: &dyn BlockCipher,
+                                                                        counter: u128,
+                                                                        mut reader: R,
+                                                                        mut writer: W,
+                                                                        buffer_count: usize,
+                                                                        buffer_size: usize,
+                                                                        thread_count: usize) -> io::Result<u128> {
+
+    assert!(buffer_count > 0, "pipeline needs at least one buffer");
+    assert!(buffer_size > 0 && buffer_size.is_multiple_of(16), "buffer size has to be a multiple of 16 bytes");
+
+    // every channel can hold all buffers, so no stage ever blocks on sending
+    let (free_sender, free_receiver) = sync_channel::<SecretBytes>(buffer_count);
+    let (read_sender, read_receiver) = sync_channel::<(SecretBytes, usize)>(buffer_count);
+    let (encrypted_sender, encrypted_receiver) = sync_channel::<(SecretBytes, usize)>(buffer_count);
+
+    for _ in 0..buffer_count {
+        free_sender.send(SecretBytes::zeroed(buffer_size)).unwrap();
+    }
+
+    thread::scope(|scope| {
+
+        // reader: fill free buffers until a short read marks the end of the
+        // input, stops early once the writer is gone
+        let reader_stage = scope.spawn(move || -> io::Result<()> {
+            while let Ok(mut buffer) = free_receiver.recv() {
+                let read_count = read_full(&mut reader, buffer.as_mut_slice())?;
+                if read_sender.send((buffer, read_count)).is_err() || read_count < buffer_size {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        // cipher: the only stage that knows the counter
+        let cipher_stage = scope.spawn(move || {
+            let mut counter = counter;
+            let workers = keystream_workers(thread_count);
+            for (mut buffer, length) in read_receiver.iter() {
+                counter = apply_keystream_parallel(cipher, counter, &mut buffer.as_mut_slice()[..length], &workers);
+                if encrypted_sender.send((buffer, length)).is_err() {
+                    break;
+                }
+            }
+            counter
+        });
+
+        // writer: write in order and recycle the buffer, the reader may
+        // already be gone after the last one; owning the free sender lets the
+        // reader stop as soon as the writer exits, even after an error
+        let writer_stage = scope.spawn(move || -> io::Result<()> {
+            let free_sender = free_sender;
+            for (buffer, length) in encrypted_receiver.iter() {
+                writer.write_all(&buffer.as_slice()[..length])?;
+                let _ = free_sender.send(buffer);
+            }
+            writer.flush()
+        });
+
+        let read_result = reader_stage.join().unwrap();
+        let counter = cipher_stage.join().unwrap();
+        let write_result = writer_stage.join().unwrap();
+        read_result.and(write_result).map(|_| counter)
+    })
+}
+
+#[test]
+fn test_pipeline_matches_sequential() {
+
+    let key: Vec<u8> = (0..16).collect();
+    let cipher = crate::aes_backend::new_default_cipher(&key);
+    let start = u128::MAX - 3;
+
+    for length in [0, 15, 64, 1000, 10_000].iter() {
+        let data: Vec<u8> = (0..*length).map(|i| (i * 13) as u8).collect();
+        let mut expected = data.clone();
+        let next = crate::aes_ctr_optimized::apply_keystream(cipher.as_ref(), start, &mut expected);
+
+        for (buffer_count, buffer_size) in [(1, 16), (2, 64), (3, 1024)].iter() {
+            let mut output = Vec::new();
+            let counter = apply_keystream_pipelined(cipher.as_ref(), start, &data[..], &mut output,
+                                                    *buffer_count, *buffer_size, 1).unwrap();
+            assert_eq!(output, expected, "{} bytes with {} buffers of {}", length, buffer_count, buffer_size);
+            assert_eq!(counter, next);
+        }
+    }
+}
+
+#[test]
+fn test_pipeline_returns_write_error() {
+
+    // accepts a few bytes, then fails like a full disk
+    struct FullWriter(usize);
+
+    impl Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("no space left"));
+            }
+            let count = buf.len().min(self.0);
+            self.0 -= count;
+            Ok(count)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let key: Vec<u8> = (0..16).collect();
+    let cipher = crate::aes_backend::new_default_cipher(&key);
+    let data = vec![7u8; 100_000];
+
+    // fewer buffers than the input needs, so the reader has to wait for recycled ones
+    let result = apply_keystream_pipelined(cipher.as_ref(), 0, &data[..], FullWriter(100), 2, 64, 1);
+    assert_eq!(result.unwrap_err().to_string(), "no space left");
+}
@@ -0,0 +1,409 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use hmac::Mac;
+
+use crate::aes_ctr_hmac::{AuthenticationError, EtmKeys, HmacSha256, TAG_LENGTH};
+use crate::aes_backend::BlockCipher;
+use crate::aes_ctr_optimized::{apply_keystream, read_full};
+
+// Online chunked AEAD following the STREAM construction (Hoang, Reyhanitabar,
+// Rogaway, Vizár, "Online Authenticated-Encryption and its Nonce-Reuse
+// Misuse-Resistance", 2015) with AES-CTR + HMAC-SHA-256 as the per-chunk AEAD
+//
+// File layout:
+//
+//     header:  magic (8 bytes) || chunk size (u32, big endian) || nonce prefix (7 bytes)
+//     chunks:  ciphertext (chunk size bytes, the last chunk may be shorter) || tag (32 bytes)
+//
+// The nonce of chunk i is prefix || i (u32, big endian) || last chunk flag (1 byte).
+// It is used as the upper 96 bits of the CTR counter block and is authenticated
+// together with the header, so truncated, reordered or swapped chunks fail to verify.
+// The chunk size has to be read from the header before the first tag can be
+// checked, so it is bounded by MAX_CHUNK_SIZE before any buffer is allocated.
+
+pub const HEADER_LENGTH: usize = 19;
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+const MAGIC: &[u8; 8] = b"AESSTRM1";
+const NONCE_PREFIX_LENGTH: usize = 7;
+
+// Function to build the 96-bit nonce of a chunk
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LENGTH], index: u32, last: bool) -> [u8; 12] {
+
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(prefix);
+    nonce[7..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+// shared state of the encryptor and decryptor
+struct StreamState {
+    keys: EtmKeys,
+    cipher: Box<dyn BlockCipher>,
+    header: [u8; HEADER_LENGTH],
+    prefix: [u8; NONCE_PREFIX_LENGTH],
+    next_index: u32,
+    finished: bool,
+}
+
+impl StreamState {
+
+    fn new(keys: EtmKeys, header: [u8; HEADER_LENGTH]) -> StreamState {
+
+        let cipher = keys.cipher();
+        let prefix = header[12..].try_into().unwrap();
+        StreamState { keys, cipher, header, prefix, next_index: 0, finished: false }
+    }
+
+    // Function to advance to the next chunk and return its nonce
+    fn next_nonce(&mut self, last: bool) -> [u8; 12] {
+
+        assert!(!self.finished, "STREAM already processed its last chunk");
+        let nonce = chunk_nonce(&self.prefix, self.next_index, last);
+        self.next_index = self.next_index.checked_add(1).expect("STREAM chunk counter overflow");
+        self.finished = last;
+        nonce
+    }
+
+    // Function to set up the chunk MAC over header, nonce and ciphertext
+    fn tag(&self, nonce: &[u8; 12], ciphertext: &[u8]) -> HmacSha256 {
+
+        let mut mac = self.keys.new_mac();
+        mac.update(&self.header);
+        mac.update(nonce);
+        mac.update(ciphertext);
+        mac
+    }
+
+    // Function to en- or decrypt a chunk with the nonce as upper counter bits
+    fn apply_keystream(&self, nonce: &[u8; 12], data: &mut [u8]) {
+
+        let mut counter_block = [0u8; 16];
+        counter_block[..12].copy_from_slice(nonce);
+        apply_keystream(self.cipher.as_ref(), u128::from_be_bytes(counter_block), data);
+    }
+}
+
+/// Encrypting side of a STREAM, producing one header and a sequence of chunks
+pub struct StreamEncryptor {
+    state: StreamState,
+    chunk_size: u32,
+}
+
+impl StreamEncryptor {
+
+    // Function to start a new stream with a fresh random nonce prefix
+    pub fn new(keys: EtmKeys, chunk_size: u32) -> StreamEncryptor {
+
+        let mut prefix = [0u8; NONCE_PREFIX_LENGTH];
+        getrandom::getrandom(&mut prefix).unwrap();
+        StreamEncryptor::with_nonce_prefix(keys, chunk_size, prefix)
+    }
+
+    // Function to start a new stream with a caller-chosen nonce prefix,
+    // which must never be repeated under the same key
+    pub fn with_nonce_prefix(keys: EtmKeys, chunk_size: u32, prefix: [u8; NONCE_PREFIX_LENGTH]) -> StreamEncryptor {
+
+        assert!(chunk_size > 0 && chunk_size <= MAX_CHUNK_SIZE, "STREAM chunk size has to be between 1 and MAX_CHUNK_SIZE");
+
+        let mut header = [0u8; HEADER_LENGTH];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&chunk_size.to_be_bytes());
+        header[12..].copy_from_slice(&prefix);
+
+        StreamEncryptor { state: StreamState::new(keys, header), chunk_size }
+    }
+
+    pub fn header(&self) -> &[u8; HEADER_LENGTH] {
+        &self.state.header
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size as usize
+    }
+
+    // Function to encrypt the next chunk in place and return its tag; all
+    // chunks but the last one have to be exactly `chunk_size` bytes long
+    pub fn encrypt_chunk(&mut self, chunk: &mut [u8], last: bool) -> [u8; TAG_LENGTH] {
+
+        assert!(chunk.len() <= self.chunk_size(), "STREAM chunk is longer than the chunk size");
+        assert!(last || chunk.len() == self.chunk_size(), "only the last STREAM chunk may be shorter");
+
+        let nonce = self.state.next_nonce(last);
+        self.state.apply_keystream(&nonce, chunk);
+        self.state.tag(&nonce, chunk).finalize().into_bytes().into()
+    }
+}
+
+/// Decrypting side of a STREAM, verifying every chunk before releasing it
+pub struct StreamDecryptor {
+    state: StreamState,
+    chunk_size: u32,
+}
+
+impl StreamDecryptor {
+
+    // Function to start decrypting a stream from its header
+    pub fn new(keys: EtmKeys, header: &[u8]) -> Result<StreamDecryptor, AuthenticationError> {
+
+        if header.len() != HEADER_LENGTH || &header[..8] != MAGIC {
+            return Err(AuthenticationError);
+        }
+        let chunk_size = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(AuthenticationError);
+        }
+
+        Ok(StreamDecryptor { state: StreamState::new(keys, header.try_into().unwrap()), chunk_size })
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size as usize
+    }
+
+    // Function to verify the next chunk and decrypt it in place; nothing is
+    // decrypted if the tag does not match
+    pub fn decrypt_chunk(&mut self, chunk: &mut [u8], tag: &[u8], last: bool) -> Result<(), AuthenticationError> {
+
+        if chunk.len() > self.chunk_size() || (!last && chunk.len() != self.chunk_size()) || self.state.finished {
+            return Err(AuthenticationError);
+        }
+
+        let nonce = chunk_nonce(&self.state.prefix, self.state.next_index, last);
+        self.state.tag(&nonce, chunk).verify_slice(tag).map_err(|_| AuthenticationError)?;
+
+        self.state.next_nonce(last);
+        self.state.apply_keystream(&nonce, chunk);
+        Ok(())
+    }
+
+    // Function to check that the stream was not truncated
+    pub fn is_finished(&self) -> bool {
+        self.state.finished
+    }
+}
+
+/// Error returned by `decrypt_file`
+#[derive(Debug)]
+pub enum StreamFileError {
+    // a chunk does not verify or the stream is truncated
+    Authentication,
+    // reading the input or writing the output failed
+    Io(io::Error),
+}
+
+impl fmt::Display for StreamFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamFileError::Authentication => AuthenticationError.fmt(f),
+            StreamFileError::Io(e) => write!(f, "decryption failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StreamFileError {}
+
+impl From<AuthenticationError> for StreamFileError {
+    fn from(_: AuthenticationError) -> StreamFileError {
+        StreamFileError::Authentication
+    }
+}
+
+impl From<io::Error> for StreamFileError {
+    fn from(e: io::Error) -> StreamFileError {
+        StreamFileError::Io(e)
+    }
+}
+
+// Function to check whether a buffered reader has reached the end of its input
+fn at_end_of_input<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.is_empty())
+}
+
+// Function to encrypt a file into the STREAM format
+pub fn encrypt_file(keys: EtmKeys, chunk_size: u32, input_file_path: &Path, output_file_path: &Path) -> io::Result<()> {
+
+    let mut encryptor = StreamEncryptor::new(keys, chunk_size);
+    let mut reader = BufReader::new(File::open(input_file_path)?);
+    let mut writer = BufWriter::new(File::create(output_file_path)?);
+    let mut buffer = vec![0u8; encryptor.chunk_size()];
+
+    writer.write_all(encryptor.header())?;
+
+    loop {
+        let read_count = read_full(&mut reader, &mut buffer)?;
+        let last = read_count < buffer.len() || at_end_of_input(&mut reader)?;
+
+        let tag = encryptor.encrypt_chunk(&mut buffer[..read_count], last);
+        writer.write_all(&buffer[..read_count])?;
+        writer.write_all(&tag)?;
+
+        if last {
+            break;
+        }
+    }
+
+    writer.flush()
+}
+
+// Function to decrypt a file in the STREAM format with constant memory
+//
+// Every chunk is verified before its plaintext is written. If any chunk fails
+// to verify, the stream turns out to be truncated or reading or writing
+// fails, the partially written output file is removed again.
+pub fn decrypt_file(keys: EtmKeys, input_file_path: &Path, output_file_path: &Path) -> Result<(), StreamFileError> {
+
+    let mut reader = BufReader::new(File::open(input_file_path)?);
+
+    let mut header = [0u8; HEADER_LENGTH];
+    if read_full(&mut reader, &mut header)? != HEADER_LENGTH {
+        return Err(StreamFileError::Authentication);
+    }
+    let mut decryptor = StreamDecryptor::new(keys, &header)?;
+
+    let mut writer = BufWriter::new(File::create(output_file_path)?);
+    let result = decrypt_chunks(&mut decryptor, &mut reader, &mut writer);
+
+    if result.is_err() {
+        drop(writer);
+        let _ = fs::remove_file(output_file_path);
+    }
+    result
+}
+
+// Function to decrypt and write every chunk after the header and flush the writer
+fn decrypt_chunks<R: BufRead, W: Write>(decryptor: &mut StreamDecryptor, reader: &mut R, writer: &mut W) -> Result<(), StreamFileError> {
+
+    let mut buffer = vec![0u8; decryptor.chunk_size() + TAG_LENGTH];
+
+    loop {
+        let read_count = read_full(reader, &mut buffer)?;
+        if read_count < TAG_LENGTH {
+            return Err(StreamFileError::Authentication);
+        }
+        let last = read_count < buffer.len() || at_end_of_input(reader)?;
+
+        let (chunk, tag) = buffer[..read_count].split_at_mut(read_count - TAG_LENGTH);
+        decryptor.decrypt_chunk(chunk, tag, last)?;
+        writer.write_all(chunk)?;
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+type SealedChunk = (Vec<u8>, [u8; TAG_LENGTH]);
+
+#[cfg(test)]
+fn encrypt_chunks(keys: EtmKeys, chunk_size: u32, plaintext: &[u8]) -> (Vec<u8>, Vec<SealedChunk>) {
+
+    let mut encryptor = StreamEncryptor::with_nonce_prefix(keys, chunk_size, [9; NONCE_PREFIX_LENGTH]);
+    let mut chunks: Vec<Vec<u8>> = plaintext.chunks(chunk_size as usize).map(|c| c.to_vec()).collect();
+    if chunks.is_empty() {
+        chunks.push(Vec::new());
+    }
+
+    let count = chunks.len();
+    let sealed = chunks.into_iter().enumerate().map(|(i, mut chunk)| {
+        let tag = encryptor.encrypt_chunk(&mut chunk, i + 1 == count);
+        (chunk, tag)
+    }).collect();
+
+    (encryptor.header().to_vec(), sealed)
+}
+
+#[test]
+fn test_stream_round_trip() {
+
+    let plaintext: Vec<u8> = (0..1000u32).map(|i| (i * 31) as u8).collect();
+
+    for length in [0, 1, 99, 100, 101, 1000].iter() {
+        let (header, chunks) = encrypt_chunks(EtmKeys::derive(&[3u8; 32]), 100, &plaintext[..*length]);
+        let mut decryptor = StreamDecryptor::new(EtmKeys::derive(&[3u8; 32]), &header).unwrap();
+        let mut decrypted = Vec::new();
+
+        for (i, (mut chunk, tag)) in chunks.iter().cloned().enumerate() {
+            decryptor.decrypt_chunk(&mut chunk, &tag, i + 1 == chunks.len()).unwrap();
+            decrypted.extend_from_slice(&chunk);
+        }
+
+        assert!(decryptor.is_finished());
+        assert_eq!(decrypted, &plaintext[..*length]);
+    }
+}
+
+#[test]
+fn test_stream_detects_truncation_reordering_and_swapping() {
+
+    let plaintext = [0x5a; 350];
+    let (header, chunks) = encrypt_chunks(EtmKeys::derive(&[3u8; 16]), 100, &plaintext);
+    let new_decryptor = || StreamDecryptor::new(EtmKeys::derive(&[3u8; 16]), &header).unwrap();
+
+    // truncation: dropping the last chunk turns chunk 2 into a claimed last chunk
+    let mut decryptor = new_decryptor();
+    for (i, (chunk, tag)) in chunks[..3].iter().enumerate() {
+        let result = decryptor.decrypt_chunk(&mut chunk.clone(), tag, i == 2);
+        assert_eq!(result.is_err(), i == 2);
+    }
+
+    // reordering: chunk 1 in place of chunk 0
+    let mut decryptor = new_decryptor();
+    assert_eq!(decryptor.decrypt_chunk(&mut chunks[1].0.clone(), &chunks[1].1, false), Err(AuthenticationError));
+
+    // swapping: a chunk from another stream with a different nonce prefix
+    let mut other = StreamEncryptor::with_nonce_prefix(EtmKeys::derive(&[3u8; 16]), 100, [8; NONCE_PREFIX_LENGTH]);
+    let mut foreign_chunk = [0x5a; 100];
+    let foreign_tag = other.encrypt_chunk(&mut foreign_chunk, false);
+    let mut decryptor = new_decryptor();
+    assert_eq!(decryptor.decrypt_chunk(&mut foreign_chunk, &foreign_tag, false), Err(AuthenticationError));
+
+    // a modified chunk size is refused up front if it is out of range, and
+    // otherwise by the first tag, which covers the header
+    let mut huge = header.clone();
+    huge[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(StreamDecryptor::new(EtmKeys::derive(&[3u8; 16]), &huge).is_err());
+
+    let (mut single_header, single_chunks) = encrypt_chunks(EtmKeys::derive(&[3u8; 16]), 100, &plaintext[..50]);
+    single_header[8..12].copy_from_slice(&60u32.to_be_bytes());
+    let mut decryptor = StreamDecryptor::new(EtmKeys::derive(&[3u8; 16]), &single_header).unwrap();
+    let (mut chunk, tag) = single_chunks[0].clone();
+    assert_eq!(decryptor.decrypt_chunk(&mut chunk, &tag, true), Err(AuthenticationError));
+}
+
+#[test]
+fn test_stream_file_round_trip_and_errors() {
+
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("stream-file");
+    let (input, encrypted, decrypted) = (directory.join("input"), directory.join("encrypted"), directory.join("decrypted"));
+    let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+
+    encrypt_file(EtmKeys::derive(&[5u8; 16]), 100, &input, &encrypted).unwrap();
+    decrypt_file(EtmKeys::derive(&[5u8; 16]), &encrypted, &decrypted).unwrap();
+    assert_eq!(std::fs::read(&decrypted).unwrap(), data);
+
+    // a modified chunk removes the output again
+    let mut modified = std::fs::read(&encrypted).unwrap();
+    modified[HEADER_LENGTH + 150] ^= 1;
+    std::fs::write(&encrypted, &modified).unwrap();
+    assert!(matches!(decrypt_file(EtmKeys::derive(&[5u8; 16]), &encrypted, &decrypted), Err(StreamFileError::Authentication)));
+    assert!(!decrypted.exists());
+
+    // missing files are errors, not panics
+    let missing = directory.join("missing");
+    assert!(encrypt_file(EtmKeys::derive(&[5u8; 16]), 100, &missing, &encrypted).is_err());
+    assert!(matches!(decrypt_file(EtmKeys::derive(&[5u8; 16]), &missing, &decrypted), Err(StreamFileError::Io(_))));
+}
@@ -0,0 +1,245 @@
+use std::arch::x86_64::*;
+
+use crate::aes_backend::{Backend, BlockCipher, PARALLEL_BLOCKS};
+use crate::secret::wipe_value;
+
+// AES-NI backend for x86-64, including key expansion with `aeskeygenassist`
+//
+// Only constructed through `aes_backend::new_cipher` after the CPU was
+// checked for the `aes` feature, which makes the intrinsics below safe to call.
+
+/// Round keys for the AES-NI instructions
+pub struct AesNi {
+    round_keys: [__m128i; 15],
+    rounds: usize,
+}
+
+// the round keys are plain data and never modified after the expansion
+unsafe impl Send for AesNi {}
+unsafe impl Sync for AesNi {}
+
+// next four round key words of AES-128 and of the even AES-256 round keys
+#[target_feature(enable = "aes,sse2")]
+unsafe fn expand_assist(key: __m128i, assist: __m128i) -> __m128i {
+
+    let mut key = key;
+    let mut shifted = _mm_slli_si128(key, 4);
+    key = _mm_xor_si128(key, shifted);
+    shifted = _mm_slli_si128(shifted, 4);
+    key = _mm_xor_si128(key, shifted);
+    shifted = _mm_slli_si128(shifted, 4);
+    key = _mm_xor_si128(key, shifted);
+    _mm_xor_si128(key, assist)
+}
+
+macro_rules! expand_128 {
+    ($keys:ident, $i:expr, $rcon:expr) => {
+        $keys[$i] = expand_assist($keys[$i - 1], _mm_shuffle_epi32(_mm_aeskeygenassist_si128($keys[$i - 1], $rcon), 0xff));
+    };
+}
+
+macro_rules! expand_256 {
+    ($keys:ident, $i:expr, $rcon:expr) => {
+        // even round key: RotWord + SubWord + Rcon of the previous odd key
+        $keys[$i] = expand_assist($keys[$i - 2], _mm_shuffle_epi32(_mm_aeskeygenassist_si128($keys[$i - 1], $rcon), 0xff));
+        // odd round key: SubWord only of the new even key
+        if $i + 1 < 15 {
+            $keys[$i + 1] = expand_assist($keys[$i - 1], _mm_shuffle_epi32(_mm_aeskeygenassist_si128($keys[$i], 0x00), 0xaa));
+        }
+    };
+}
+
+#[target_feature(enable = "aes,sse2")]
+unsafe fn expand_key_128(key: &[u8]) -> [__m128i; 15] {
+
+    let mut keys = [_mm_setzero_si128(); 15];
+    keys[0] = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+
+    expand_128!(keys, 1, 0x01);
+    expand_128!(keys, 2, 0x02);
+    expand_128!(keys, 3, 0x04);
+    expand_128!(keys, 4, 0x08);
+    expand_128!(keys, 5, 0x10);
+    expand_128!(keys, 6, 0x20);
+    expand_128!(keys, 7, 0x40);
+    expand_128!(keys, 8, 0x80);
+    expand_128!(keys, 9, 0x1b);
+    expand_128!(keys, 10, 0x36);
+
+    keys
+}
+
+// next six round key words of AES-192, `low` holds four of the previous six
+// words and the low half of `high` the other two
+#[target_feature(enable = "aes,sse2")]
+unsafe fn expand_step_192<const RCON: i32>(low: __m128i, high: __m128i) -> (__m128i, __m128i) {
+
+    let low = expand_assist(low, _mm_shuffle_epi32(_mm_aeskeygenassist_si128::<RCON>(high), 0x55));
+    let high = _mm_xor_si128(_mm_xor_si128(high, _mm_slli_si128(high, 4)), _mm_shuffle_epi32(low, 0xff));
+    (low, high)
+}
+
+macro_rules! expand_192 {
+    ($keys:ident, $state:ident, $i:expr, $rcon:expr, $next_rcon:expr) => {
+        // the two words left over from the last step complete round key i
+        let carried = $state.1;
+        $state = expand_step_192::<{ $rcon }>($state.0, $state.1);
+        $keys[$i] = _mm_unpacklo_epi64(carried, $state.0);
+        $keys[$i + 1] = _mm_unpacklo_epi64(_mm_unpackhi_epi64($state.0, $state.0), $state.1);
+        $state = expand_step_192::<{ $next_rcon }>($state.0, $state.1);
+        $keys[$i + 2] = $state.0;
+    };
+}
+
+#[target_feature(enable = "aes,sse2")]
+unsafe fn expand_key_192(key: &[u8]) -> [__m128i; 15] {
+
+    let mut keys = [_mm_setzero_si128(); 15];
+    let mut state = (_mm_loadu_si128(key.as_ptr() as *const __m128i),
+                     _mm_loadl_epi64(key[16..].as_ptr() as *const __m128i));
+    keys[0] = state.0;
+
+    expand_192!(keys, state, 1, 0x01, 0x02);
+    expand_192!(keys, state, 4, 0x04, 0x08);
+    expand_192!(keys, state, 7, 0x10, 0x20);
+    expand_192!(keys, state, 10, 0x40, 0x80);
+
+    keys
+}
+
+#[target_feature(enable = "aes,sse2")]
+unsafe fn expand_key_256(key: &[u8]) -> [__m128i; 15] {
+
+    let mut keys = [_mm_setzero_si128(); 15];
+    keys[0] = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+    keys[1] = _mm_loadu_si128(key[16..].as_ptr() as *const __m128i);
+
+    expand_256!(keys, 2, 0x01);
+    expand_256!(keys, 4, 0x02);
+    expand_256!(keys, 6, 0x04);
+    expand_256!(keys, 8, 0x08);
+    expand_256!(keys, 10, 0x10);
+    expand_256!(keys, 12, 0x20);
+    expand_256!(keys, 14, 0x40);
+
+    keys
+}
+
+// Function to run all rounds on a loaded block
+#[target_feature(enable = "aes,sse2")]
+pub(crate) unsafe fn encrypt_m128(round_keys: &[__m128i; 15], rounds: usize, block: __m128i) -> __m128i {
+
+    let mut state = _mm_xor_si128(block, round_keys[0]);
+    for round_key in round_keys[1..rounds].iter() {
+        state = _mm_aesenc_si128(state, *round_key);
+    }
+    _mm_aesenclast_si128(state, round_keys[rounds])
+}
+
+#[target_feature(enable = "aes,sse2")]
+unsafe fn encrypt_u128(round_keys: &[__m128i; 15], rounds: usize, block: u128) -> u128 {
+
+    let bytes = block.to_be_bytes();
+    let state = encrypt_m128(round_keys, rounds, _mm_loadu_si128(bytes.as_ptr() as *const __m128i));
+
+    let mut output = [0u8; 16];
+    _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, state);
+    u128::from_be_bytes(output)
+}
+
+// Function to encrypt eight blocks with interleaved rounds, so the latency of
+// `aesenc` is hidden behind the independent neighbouring blocks
+#[target_feature(enable = "aes,sse2")]
+unsafe fn encrypt_u128_x8(round_keys: &[__m128i; 15], rounds: usize, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+
+    let mut state = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+    for (s, block) in state.iter_mut().zip(blocks.iter()) {
+        let bytes = block.to_be_bytes();
+        *s = _mm_xor_si128(_mm_loadu_si128(bytes.as_ptr() as *const __m128i), round_keys[0]);
+    }
+
+    for round_key in round_keys[1..rounds].iter() {
+        for s in state.iter_mut() {
+            *s = _mm_aesenc_si128(*s, *round_key);
+        }
+    }
+
+    for (s, block) in state.iter().zip(blocks.iter_mut()) {
+        let mut output = [0u8; 16];
+        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, _mm_aesenclast_si128(*s, round_keys[rounds]));
+        *block = u128::from_be_bytes(output);
+    }
+}
+
+// Function to encrypt eight blocks, each under its own key, with interleaved
+// rounds, keys with fewer rounds finish early
+#[target_feature(enable = "aes,sse2")]
+unsafe fn encrypt_u128_x8_multi_key(keys: &[&AesNi; PARALLEL_BLOCKS], blocks: &mut [u128; PARALLEL_BLOCKS]) {
+
+    let mut state = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+    for ((s, block), key) in state.iter_mut().zip(blocks.iter()).zip(keys.iter()) {
+        let bytes = block.to_be_bytes();
+        *s = _mm_xor_si128(_mm_loadu_si128(bytes.as_ptr() as *const __m128i), key.round_keys[0]);
+    }
+
+    let rounds = keys.iter().map(|key| key.rounds).max().unwrap();
+    for round in 1..=rounds {
+        for (s, key) in state.iter_mut().zip(keys.iter()) {
+            if round < key.rounds {
+                *s = _mm_aesenc_si128(*s, key.round_keys[round]);
+            } else if round == key.rounds {
+                *s = _mm_aesenclast_si128(*s, key.round_keys[round]);
+            }
+        }
+    }
+
+    for (s, block) in state.iter().zip(blocks.iter_mut()) {
+        let mut output = [0u8; 16];
+        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, *s);
+        *block = u128::from_be_bytes(output);
+    }
+}
+
+// Function to encrypt a batch of blocks, every block under its own key
+pub(crate) fn encrypt_blocks_multi_key(keys: &[&AesNi; PARALLEL_BLOCKS], blocks: &mut [u128; PARALLEL_BLOCKS]) {
+    unsafe { encrypt_u128_x8_multi_key(keys, blocks) }
+}
+
+impl AesNi {
+
+    // Function to expand a 128-bit, 192-bit or 256-bit key, the caller has to
+    // make sure that the CPU supports AES-NI
+    pub fn new(key: &[u8]) -> AesNi {
+
+        assert!(Backend::AesNi.is_available());
+        unsafe {
+            match key.len() {
+                16 => AesNi { round_keys: expand_key_128(key), rounds: 10 },
+                24 => AesNi { round_keys: expand_key_192(key), rounds: 12 },
+                _ => AesNi { round_keys: expand_key_256(key), rounds: 14 },
+            }
+        }
+    }
+}
+
+impl Drop for AesNi {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.round_keys);
+    }
+}
+
+impl BlockCipher for AesNi {
+
+    fn encrypt_block(&self, block: u128) -> u128 {
+        unsafe { encrypt_u128(&self.round_keys, self.rounds, block) }
+    }
+
+    fn encrypt_blocks(&self, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+        unsafe { encrypt_u128_x8(&self.round_keys, self.rounds, blocks) }
+    }
+
+    fn backend(&self) -> Backend {
+        Backend::AesNi
+    }
+}
@@ -0,0 +1,189 @@
+// AES lookup tables, computed at compile time from GF(2^8) arithmetic
+//
+// Elements of GF(2^8) are bytes, multiplication is modulo the AES polynomial
+// x^8 + x^4 + x^3 + x + 1. The T-tables combine SubBytes and MixColumns of
+// one input byte into a column word, with the first row in the most
+// significant byte; the inverse T-tables do the same for InvSubBytes and
+// InvMixColumns.
+
+// Function to multiply by x in GF(2^8)
+const fn xtime(a: u8) -> u8 {
+    (a << 1) ^ (((a >> 7) & 1) * 0x1b)
+}
+
+// Function to multiply two elements of GF(2^8)
+pub const fn gf_multiply(a: u8, b: u8) -> u8 {
+
+    let mut a = a;
+    let mut b = b;
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+// Function to invert an element of GF(2^8) as a^254, with 0 mapped to 0
+const fn gf_inverse(a: u8) -> u8 {
+
+    // a^254 = a^(2 + 4 + 8 + ... + 128)
+    let mut square = gf_multiply(a, a);
+    let mut inverse = 1;
+    let mut i = 1;
+    while i < 8 {
+        inverse = gf_multiply(inverse, square);
+        square = gf_multiply(square, square);
+        i += 1;
+    }
+    inverse
+}
+
+// Function to evaluate the S-box: inversion followed by the affine map
+const fn sbox(a: u8) -> u8 {
+    let b = gf_inverse(a);
+    b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63
+}
+
+const fn create_substitution() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = sbox(i as u8);
+        i += 1;
+    }
+    table
+}
+
+const fn create_inverse_substitution() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[SUBSTITUTION[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+const fn create_multiply(factor: u8) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = gf_multiply(i as u8, factor);
+        i += 1;
+    }
+    table
+}
+
+// RCON[i] = x^(i - 1), starting with x^-1 so the key expansion can index it
+// with its one-based iteration count
+const fn create_rcon() -> [u8; 21] {
+    let mut table = [0u8; 21];
+    table[0] = 0x8d;
+    let mut i = 1;
+    while i < 21 {
+        table[i] = xtime(table[i - 1]);
+        i += 1;
+    }
+    table
+}
+
+// Function to build the T-table for the first row, the other rows are
+// rotations of it
+const fn create_t_table(rotation: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let s = SUBSTITUTION[i];
+        let word = (MULTIPLY_2[s as usize] as u32) << 24 | (s as u32) << 16 | (s as u32) << 8 | MULTIPLY_3[s as usize] as u32;
+        table[i] = word.rotate_right(rotation);
+        i += 1;
+    }
+    table
+}
+
+const fn create_inverse_t_table(rotation: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let s = INVERSE_SUBSTITUTION[i] as usize;
+        let word = (MULTIPLY_14[s] as u32) << 24 | (MULTIPLY_9[s] as u32) << 16 | (MULTIPLY_13[s] as u32) << 8 | MULTIPLY_11[s] as u32;
+        table[i] = word.rotate_right(rotation);
+        i += 1;
+    }
+    table
+}
+
+pub const SUBSTITUTION: [u8; 256] = create_substitution();
+pub const INVERSE_SUBSTITUTION: [u8; 256] = create_inverse_substitution();
+
+pub const MULTIPLY_2: [u8; 256] = create_multiply(2);
+pub const MULTIPLY_3: [u8; 256] = create_multiply(3);
+pub const MULTIPLY_9: [u8; 256] = create_multiply(9);
+pub const MULTIPLY_11: [u8; 256] = create_multiply(11);
+pub const MULTIPLY_13: [u8; 256] = create_multiply(13);
+pub const MULTIPLY_14: [u8; 256] = create_multiply(14);
+
+pub const RCON: [u8; 21] = create_rcon();
+
+// encryption rounds
+pub const T_0: [u32; 256] = create_t_table(0);
+pub const T_1: [u32; 256] = create_t_table(8);
+pub const T_2: [u32; 256] = create_t_table(16);
+pub const T_3: [u32; 256] = create_t_table(24);
+
+// decryption rounds
+pub const INVERSE_T_0: [u32; 256] = create_inverse_t_table(0);
+pub const INVERSE_T_1: [u32; 256] = create_inverse_t_table(8);
+pub const INVERSE_T_2: [u32; 256] = create_inverse_t_table(16);
+pub const INVERSE_T_3: [u32; 256] = create_inverse_t_table(24);
+
+#[test]
+fn test_substitution_equals_fips_197() {
+
+    // FIPS-197 figure 7
+    let expected: [u8; 256] = [
+        0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+        0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+        0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+        0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+        0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+        0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+        0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+        0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+        0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+        0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+        0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+        0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+        0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+        0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+        0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+        0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+    ];
+    assert_eq!(SUBSTITUTION[..], expected[..]);
+
+    // FIPS-197 figure 14 starts with 52 09 6a d5
+    assert_eq!(INVERSE_SUBSTITUTION[..4], [0x52, 0x09, 0x6a, 0xd5]);
+    for i in 0..256 {
+        assert_eq!(INVERSE_SUBSTITUTION[SUBSTITUTION[i] as usize] as usize, i);
+    }
+}
+
+#[test]
+fn test_t_tables() {
+
+    // S(0x00) = 0x63: MixColumns column (2, 1, 1, 3) * 0x63
+    assert_eq!(T_0[0], 0xc66363a5);
+    assert_eq!(T_1[0], 0xa5c66363);
+    assert_eq!(T_2[0], 0x63a5c663);
+    assert_eq!(T_3[0], 0x6363a5c6);
+
+    // InvS(0x00) = 0x52: InvMixColumns column (14, 9, 13, 11) * 0x52
+    assert_eq!(INVERSE_T_0[0], 0x51f4a750);
+    assert_eq!(INVERSE_T_1[0], 0x5051f4a7);
+
+    assert_eq!(RCON[1..11], [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36]);
+}
@@ -0,0 +1,338 @@
+use std::arch::x86_64::*;
+
+use crate::aes_backend::{Backend, BlockCipher, PARALLEL_BLOCKS};
+use crate::secret::wipe_value;
+
+// Vector permute AES for x86-64 with SSSE3 (Mike Hamburg, "Accelerating AES
+// with Vector Permute Instructions", 2009)
+//
+// The state is kept in a different basis in which the S-box splits into
+// inversions in GF(2^4), so every table has 16 entries and is looked up for
+// all 16 bytes at once with `pshufb`. The tables stay in registers and the
+// lookups do not touch memory, which makes the code constant time without
+// hardware AES. The constants and the key schedule follow the public domain
+// reference implementation (vpaes-x86_64.pl).
+//
+// Only constructed through `aes_backend::new_cipher` after the CPU was
+// checked for the `ssse3` feature.
+
+// 128-bit constant from its low and high quad word
+macro_rules! constant {
+    ($lo:expr, $hi:expr) => {
+        _mm_set_epi64x($hi as i64, $lo as i64)
+    };
+}
+
+// lookup tables of the vector permute basis
+struct Tables {
+    s0f: __m128i,
+    inv: __m128i,
+    inva: __m128i,
+    ipt_lo: __m128i,
+    ipt_hi: __m128i,
+    sb1u: __m128i,
+    sb1t: __m128i,
+    sb2u: __m128i,
+    sb2t: __m128i,
+    sbou: __m128i,
+    sbot: __m128i,
+    opt_lo: __m128i,
+    opt_hi: __m128i,
+    s63: __m128i,
+    rcon: __m128i,
+    mc_forward: [__m128i; 4],
+    mc_backward: [__m128i; 4],
+    sr: [__m128i; 4],
+}
+
+#[target_feature(enable = "ssse3")]
+unsafe fn tables() -> Tables {
+    Tables {
+        s0f: constant!(0x0F0F0F0F0F0F0F0Fu64, 0x0F0F0F0F0F0F0F0Fu64),
+        inv: constant!(0x0E05060F0D080180u64, 0x040703090A0B0C02u64),
+        inva: constant!(0x01040A060F0B0780u64, 0x030D0E0C02050809u64),
+        ipt_lo: constant!(0xC2B2E8985A2A7000u64, 0xCABAE09052227808u64),
+        ipt_hi: constant!(0x4C01307D317C4D00u64, 0xCD80B1FCB0FDCC81u64),
+        sb1u: constant!(0xB19BE18FCB503E00u64, 0xA5DF7A6E142AF544u64),
+        sb1t: constant!(0x3618D415FAE22300u64, 0x3BF7CCC10D2ED9EFu64),
+        sb2u: constant!(0xE27A93C60B712400u64, 0x5EB7E955BC982FCDu64),
+        sb2t: constant!(0x69EB88400AE12900u64, 0xC2A163C8AB82234Au64),
+        sbou: constant!(0xD0D26D176FBDC700u64, 0x15AABF7AC502A878u64),
+        sbot: constant!(0xCFE474A55FBB6A00u64, 0x8E1E90D1412B35FAu64),
+        opt_lo: constant!(0xFF9F4929D6B66000u64, 0xF7974121DEBE6808u64),
+        opt_hi: constant!(0x01EDBD5150BCEC00u64, 0xE10D5DB1B05C0CE0u64),
+        s63: constant!(0x5B5B5B5B5B5B5B5Bu64, 0x5B5B5B5B5B5B5B5Bu64),
+        rcon: constant!(0x1F8391B9AF9DEEB6u64, 0x702A98084D7C7D81u64),
+        mc_forward: [
+            constant!(0x0407060500030201u64, 0x0C0F0E0D080B0A09u64),
+            constant!(0x080B0A0904070605u64, 0x000302010C0F0E0Du64),
+            constant!(0x0C0F0E0D080B0A09u64, 0x0407060500030201u64),
+            constant!(0x000302010C0F0E0Du64, 0x080B0A0904070605u64),
+        ],
+        mc_backward: [
+            constant!(0x0605040702010003u64, 0x0E0D0C0F0A09080Bu64),
+            constant!(0x020100030E0D0C0Fu64, 0x0A09080B06050407u64),
+            constant!(0x0E0D0C0F0A09080Bu64, 0x0605040702010003u64),
+            constant!(0x0A09080B06050407u64, 0x020100030E0D0C0Fu64),
+        ],
+        sr: [
+            constant!(0x0706050403020100u64, 0x0F0E0D0C0B0A0908u64),
+            constant!(0x030E09040F0A0500u64, 0x0B06010C07020D08u64),
+            constant!(0x0F060D040B020900u64, 0x070E050C030A0108u64),
+            constant!(0x0B0E0104070A0D00u64, 0x0306090C0F020508u64),
+        ],
+    }
+}
+
+// Function to apply a linear transform given as two nibble tables
+#[target_feature(enable = "ssse3")]
+unsafe fn transform(t: &Tables, x: __m128i, lo: __m128i, hi: __m128i) -> __m128i {
+    let high = _mm_srli_epi32(_mm_andnot_si128(t.s0f, x), 4);
+    let low = _mm_and_si128(x, t.s0f);
+    _mm_xor_si128(_mm_shuffle_epi8(lo, low), _mm_shuffle_epi8(hi, high))
+}
+
+// Function to run the GF(2^4) inversion part of SubBytes, returns the two
+// index vectors for the output tables
+#[target_feature(enable = "ssse3")]
+unsafe fn invert(t: &Tables, x: __m128i) -> (__m128i, __m128i) {
+
+    let i = _mm_srli_epi32(_mm_andnot_si128(t.s0f, x), 4);
+    let k = _mm_and_si128(x, t.s0f);
+    let ak = _mm_shuffle_epi8(t.inva, k);
+    let j = _mm_xor_si128(i, k);
+    let iak = _mm_xor_si128(_mm_shuffle_epi8(t.inv, i), ak);
+    let jak = _mm_xor_si128(_mm_shuffle_epi8(t.inv, j), ak);
+    let io = _mm_xor_si128(_mm_shuffle_epi8(t.inv, iak), j);
+    let jo = _mm_xor_si128(_mm_shuffle_epi8(t.inv, jak), i);
+    (io, jo)
+}
+
+// Function to encrypt several blocks, the rounds of all blocks are interleaved
+#[target_feature(enable = "ssse3")]
+unsafe fn encrypt_core(round_keys: &[__m128i; 15], rounds: usize, blocks: &mut [__m128i]) {
+
+    let t = tables();
+
+    let mut io = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+    let mut jo = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+    for (n, block) in blocks.iter().enumerate() {
+        let state = _mm_xor_si128(transform(&t, *block, t.ipt_lo, t.ipt_hi), round_keys[0]);
+        let (i, j) = invert(&t, state);
+        io[n] = i;
+        jo[n] = j;
+    }
+
+    // the MixColumns rotations advance by one column per round, so the final
+    // ShiftRows only has to undo what is left of them
+    let mut mc = 1;
+    for round_key in round_keys[1..rounds].iter() {
+        for n in 0..blocks.len() {
+            let a = _mm_xor_si128(_mm_xor_si128(_mm_shuffle_epi8(t.sb1u, io[n]), *round_key),
+                                  _mm_shuffle_epi8(t.sb1t, jo[n]));
+            let a2 = _mm_xor_si128(_mm_shuffle_epi8(t.sb2u, io[n]), _mm_shuffle_epi8(t.sb2t, jo[n]));
+            let b = _mm_shuffle_epi8(a, t.mc_forward[mc]);
+            let d = _mm_shuffle_epi8(a, t.mc_backward[mc]);
+            let a2b = _mm_xor_si128(a2, b);
+            let a2bd = _mm_xor_si128(a2b, d);
+            let state = _mm_xor_si128(_mm_shuffle_epi8(a2b, t.mc_forward[mc]), a2bd);
+            let (i, j) = invert(&t, state);
+            io[n] = i;
+            jo[n] = j;
+        }
+        mc = (mc + 1) % 4;
+    }
+
+    for (n, block) in blocks.iter_mut().enumerate() {
+        let a = _mm_xor_si128(_mm_xor_si128(_mm_shuffle_epi8(t.sbou, io[n]), round_keys[rounds]),
+                              _mm_shuffle_epi8(t.sbot, jo[n]));
+        *block = _mm_shuffle_epi8(a, t.sr[mc]);
+    }
+}
+
+// Function to run SubBytes of the key schedule on the transformed word and
+// add the smeared previous round key
+#[target_feature(enable = "ssse3")]
+unsafe fn schedule_low_round(t: &Tables, x: __m128i, previous: __m128i) -> __m128i {
+
+    let mut smeared = _mm_xor_si128(previous, _mm_slli_si128(previous, 4));
+    smeared = _mm_xor_si128(smeared, _mm_slli_si128(smeared, 8));
+    smeared = _mm_xor_si128(smeared, t.s63);
+
+    let (io, jo) = invert(t, x);
+    let sbox = _mm_xor_si128(_mm_shuffle_epi8(t.sb1u, io), _mm_shuffle_epi8(t.sb1t, jo));
+    _mm_xor_si128(sbox, smeared)
+}
+
+// Function to run RotWord, SubWord and Rcon on the high word of `x`
+#[target_feature(enable = "ssse3")]
+unsafe fn schedule_round(t: &Tables, rcon: &mut __m128i, x: __m128i, previous: __m128i) -> __m128i {
+
+    let round_constant = _mm_alignr_epi8(_mm_setzero_si128(), *rcon, 15);
+    *rcon = _mm_alignr_epi8(*rcon, *rcon, 15);
+    let previous = _mm_xor_si128(previous, round_constant);
+
+    let rotated = _mm_shuffle_epi32(x, 0xff);
+    schedule_low_round(t, _mm_alignr_epi8(rotated, rotated, 1), previous)
+}
+
+// Function to bring a round key into the form `encrypt_core` expects
+#[target_feature(enable = "ssse3")]
+unsafe fn schedule_mangle(t: &Tables, x: __m128i, sr: &mut usize) -> __m128i {
+
+    let mut rotated = _mm_shuffle_epi8(_mm_xor_si128(x, t.s63), t.mc_forward[0]);
+    let mut key = rotated;
+    rotated = _mm_shuffle_epi8(rotated, t.mc_forward[0]);
+    key = _mm_xor_si128(key, rotated);
+    rotated = _mm_shuffle_epi8(rotated, t.mc_forward[0]);
+    key = _mm_xor_si128(key, rotated);
+
+    let key = _mm_shuffle_epi8(key, t.sr[*sr]);
+    *sr = (*sr + 3) % 4;
+    key
+}
+
+#[target_feature(enable = "ssse3")]
+unsafe fn schedule_mangle_last(t: &Tables, x: __m128i, sr: usize) -> __m128i {
+    let x = _mm_xor_si128(_mm_shuffle_epi8(x, t.sr[sr]), t.s63);
+    transform(t, x, t.opt_lo, t.opt_hi)
+}
+
+// Function to derive the next round key of AES-192 from the two words carried
+// in the high half of `carried` and the last round, returns the round key and
+// its two high words for the next call
+#[target_feature(enable = "ssse3")]
+unsafe fn schedule_smear_192(carried: __m128i, previous: __m128i) -> (__m128i, __m128i) {
+
+    let mut smeared = _mm_xor_si128(carried, _mm_shuffle_epi32(carried, 0x80));
+    smeared = _mm_xor_si128(smeared, _mm_shuffle_epi32(previous, 0xfe));
+    (smeared, _mm_unpackhi_epi64(_mm_setzero_si128(), smeared))
+}
+
+#[target_feature(enable = "ssse3")]
+unsafe fn expand_key(key: &[u8]) -> [__m128i; 15] {
+
+    let t = tables();
+    let mut keys = [_mm_setzero_si128(); 15];
+    let mut rcon = t.rcon;
+    let mut sr = 3;
+
+    let mut x = transform(&t, _mm_loadu_si128(key.as_ptr() as *const __m128i), t.ipt_lo, t.ipt_hi);
+    let mut previous = x;
+    keys[0] = x;
+
+    if key.len() == 16 {
+        for round_key in keys[1..10].iter_mut() {
+            x = schedule_round(&t, &mut rcon, x, previous);
+            previous = x;
+            *round_key = schedule_mangle(&t, x, &mut sr);
+        }
+        x = schedule_round(&t, &mut rcon, x, previous);
+        keys[10] = schedule_mangle_last(&t, x, sr);
+    } else if key.len() == 24 {
+        // six key words do not fill whole round keys, the last two words of
+        // every step are carried in the high half of `carried`
+        x = transform(&t, _mm_loadu_si128(key[8..].as_ptr() as *const __m128i), t.ipt_lo, t.ipt_hi);
+        let mut carried = _mm_unpackhi_epi64(_mm_setzero_si128(), x);
+        let mut round = 1;
+        loop {
+            x = schedule_round(&t, &mut rcon, x, previous);
+            previous = x;
+            keys[round] = schedule_mangle(&t, _mm_alignr_epi8(x, carried, 8), &mut sr);
+            (x, carried) = schedule_smear_192(carried, previous);
+            keys[round + 1] = schedule_mangle(&t, x, &mut sr);
+            x = schedule_round(&t, &mut rcon, x, previous);
+            previous = x;
+            if round == 10 {
+                break;
+            }
+            keys[round + 2] = schedule_mangle(&t, x, &mut sr);
+            (x, carried) = schedule_smear_192(carried, previous);
+            round += 3;
+        }
+        keys[12] = schedule_mangle_last(&t, x, sr);
+    } else {
+        // the odd round keys come from a "low" round without RotWord and Rcon
+        x = transform(&t, _mm_loadu_si128(key[16..].as_ptr() as *const __m128i), t.ipt_lo, t.ipt_hi);
+        let mut round = 1;
+        loop {
+            keys[round] = schedule_mangle(&t, x, &mut sr);
+            let low = x;
+            x = schedule_round(&t, &mut rcon, x, previous);
+            previous = x;
+            if round == 13 {
+                break;
+            }
+            keys[round + 1] = schedule_mangle(&t, x, &mut sr);
+            x = schedule_low_round(&t, _mm_shuffle_epi32(x, 0xff), low);
+            round += 2;
+        }
+        keys[14] = schedule_mangle_last(&t, x, sr);
+    }
+
+    keys
+}
+
+#[target_feature(enable = "ssse3")]
+unsafe fn encrypt_u128s(round_keys: &[__m128i; 15], rounds: usize, blocks: &mut [u128]) {
+
+    let mut state = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+    for (s, block) in state.iter_mut().zip(blocks.iter()) {
+        let bytes = block.to_be_bytes();
+        *s = _mm_loadu_si128(bytes.as_ptr() as *const __m128i);
+    }
+
+    encrypt_core(round_keys, rounds, &mut state[..blocks.len()]);
+
+    for (s, block) in state.iter().zip(blocks.iter_mut()) {
+        let mut output = [0u8; 16];
+        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, *s);
+        *block = u128::from_be_bytes(output);
+    }
+}
+
+/// Round keys in the vector permute basis
+pub struct Vpaes {
+    round_keys: [__m128i; 15],
+    rounds: usize,
+}
+
+// the round keys are plain data and never modified after the expansion
+unsafe impl Send for Vpaes {}
+unsafe impl Sync for Vpaes {}
+
+impl Vpaes {
+
+    // Function to expand a 128-bit, 192-bit or 256-bit key, the caller has to
+    // make sure that the CPU supports SSSE3
+    pub fn new(key: &[u8]) -> Vpaes {
+
+        assert!(Backend::Vpaes.is_available());
+        let rounds = key.len() / 4 + 6;
+        Vpaes { round_keys: unsafe { expand_key(key) }, rounds }
+    }
+}
+
+impl Drop for Vpaes {
+
+    fn drop(&mut self) {
+        wipe_value(&mut self.round_keys);
+    }
+}
+
+impl BlockCipher for Vpaes {
+
+    fn encrypt_block(&self, block: u128) -> u128 {
+        let mut blocks = [block];
+        unsafe { encrypt_u128s(&self.round_keys, self.rounds, &mut blocks) };
+        blocks[0]
+    }
+
+    fn encrypt_blocks(&self, blocks: &mut [u128; PARALLEL_BLOCKS]) {
+        unsafe { encrypt_u128s(&self.round_keys, self.rounds, blocks) }
+    }
+
+    fn backend(&self) -> Backend {
+        Backend::Vpaes
+    }
+}
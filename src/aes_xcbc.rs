@@ -0,0 +1,122 @@
+use std::convert::TryInto;
+
+use crate::aes_ctr_optimized::{encrypt_aes, key_expansion};
+use crate::secret::{wipe, wipe_value};
+
+// AES-XCBC-MAC-96 (RFC 3566) and AES-XCBC-PRF-128 (RFC 4434)
+
+// derive the three XCBC keys from the user key
+// (K1 is returned expanded, K2 and K3 as plain blocks)
+fn derive_xcbc_keys(key: &[u8; 16]) -> (Vec<u32>, u128, u128) {
+
+    let mut expanded_key = key_expansion(key.to_vec(), 11);
+
+    let mut k1 = encrypt_aes(0x01010101010101010101010101010101, &expanded_key);
+    let k2 = encrypt_aes(0x02020202020202020202020202020202, &expanded_key);
+    let k3 = encrypt_aes(0x03030303030303030303030303030303, &expanded_key);
+    let k1_expanded = key_expansion(k1.to_be_bytes().to_vec(), 11);
+
+    wipe(&mut expanded_key);
+    wipe_value(&mut k1);
+    (k1_expanded, k2, k3)
+}
+
+// Function to compute the full 128-bit AES-XCBC-MAC of a message
+pub fn xcbc_mac(key: &[u8; 16], message: &[u8]) -> [u8; 16] {
+
+    let (mut k1_expanded, mut k2, mut k3) = derive_xcbc_keys(key);
+    let mut state = 0u128;
+
+    // all blocks except the last one are processed as in plain CBC-MAC
+    // (an empty message is treated as one incomplete block)
+    let full_blocks = if message.is_empty() { 0 } else { (message.len() - 1) / 16 };
+    for chunk in message[..full_blocks * 16].chunks_exact(16) {
+        state = encrypt_aes(state ^ u128::from_be_bytes(chunk.try_into().unwrap()), &k1_expanded);
+    }
+
+    // last block: xor K2 if it is complete, otherwise pad with 10* and xor K3
+    let last = &message[full_blocks * 16..];
+    let mut last_block = [0u8; 16];
+    last_block[..last.len()].copy_from_slice(last);
+    let tweak = if last.len() == 16 {
+        k2
+    } else {
+        last_block[last.len()] = 0x80;
+        k3
+    };
+
+    state = encrypt_aes(state ^ u128::from_be_bytes(last_block) ^ tweak, &k1_expanded);
+
+    wipe(&mut k1_expanded);
+    wipe_value(&mut k2);
+    wipe_value(&mut k3);
+    state.to_be_bytes()
+}
+
+// Function to compute AES-XCBC-MAC-96, i.e. the MAC truncated to 96 bits
+pub fn xcbc_mac_96(key: &[u8; 16], message: &[u8]) -> [u8; 12] {
+
+    let mut truncated = [0u8; 12];
+    truncated.copy_from_slice(&xcbc_mac(key, message)[..12]);
+    truncated
+}
+
+// Function to compute AES-XCBC-PRF-128 with a key of arbitrary length
+pub fn xcbc_prf_128(key: &[u8], message: &[u8]) -> [u8; 16] {
+
+    // keys shorter than 128 bits are zero padded, longer keys are
+    // compressed with XCBC under the all-zero key (RFC 4434, section 2)
+    let mut prf_key = [0u8; 16];
+    if key.len() <= 16 {
+        prf_key[..key.len()].copy_from_slice(key);
+    } else {
+        prf_key = xcbc_mac(&[0u8; 16], key);
+    }
+
+    xcbc_mac(&prf_key, message)
+}
+
+#[cfg(test)]
+fn test_message(length: usize) -> Vec<u8> {
+    (0..length).map(|i| i as u8).collect()
+}
+
+#[test]
+fn test_xcbc_mac_rfc3566_vectors() {
+
+    // test cases 1 to 6 from RFC 3566, section 4.6
+    let key: [u8; 16] = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
+    let vectors = [
+        (0, "75f0251d528ac01c4573dfd584d79f29"),
+        (3, "5b376580ae2f19afe7219ceef172756f"),
+        (16, "d2a246fa349b68a79998a4394ff7a263"),
+        (20, "47f51b4564966215b8985c63055ed308"),
+        (32, "f54f0ec8d2b9f3d36807734bd5283fd4"),
+        (34, "becbb3bccdb518a30677d5481fb6b4d8"),
+    ];
+
+    for (length, expected) in vectors.iter() {
+        assert_eq!(hex::encode(xcbc_mac(&key, &test_message(*length))), *expected);
+        assert_eq!(hex::encode(xcbc_mac_96(&key, &test_message(*length))), expected[..24]);
+    }
+
+    // test case 7: 1000 zero bytes
+    assert_eq!(hex::encode(xcbc_mac(&key, &[0u8; 1000])), "f0dafee895db30253761103b5d84528f");
+}
+
+#[test]
+fn test_xcbc_prf_rfc4434_vectors() {
+
+    // test cases from RFC 4434, section 5 (message is 0x00..0x13)
+    let message = test_message(20);
+
+    let key_16 = test_message(16);
+    assert_eq!(hex::encode(xcbc_prf_128(&key_16, &message)), "47f51b4564966215b8985c63055ed308");
+
+    let key_10 = test_message(10);
+    assert_eq!(hex::encode(xcbc_prf_128(&key_10, &message)), "0fa087af7d866e7653434e602fdde835");
+
+    let mut key_18 = test_message(16);
+    key_18.extend_from_slice(&[0xed, 0xcb]);
+    assert_eq!(hex::encode(xcbc_prf_128(&key_18, &message)), "8cd3c93ae598a9803006ffb67c40e9e4");
+}
@@ -0,0 +1,414 @@
+use std::convert::TryInto;
+
+use crate::secret::{wipe, wipe_value};
+use crate::worker_pool::{available_threads, Task, WorkerPool};
+
+// Argon2id version 1.3 (RFC 9106) with the BLAKE2b hash it is built on
+// (RFC 7693)
+//
+// The lanes of every slice are filled concurrently by at most as many threads
+// as the machine runs at once. Within a slice a lane only writes its own
+// segment and only reads blocks that are not written in that slice, which
+// makes the concurrent access sound.
+
+const BLAKE2B_IV: [u64; 8] = [
+    0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+];
+
+const BLAKE2B_SIGMA: [[usize; 16]; 12] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+];
+
+// Function to run the BLAKE2b compression function on one 128-byte block
+fn blake2b_compress(h: &mut [u64; 8], block: &[u8], counter: u128, last: bool) {
+
+    let mut m = [0u64; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks_exact(8)) {
+        *word = u64::from_le_bytes(bytes.try_into().unwrap());
+    }
+    let mut v = [0u64; 16];
+    v[..8].copy_from_slice(h);
+    v[8..].copy_from_slice(&BLAKE2B_IV);
+    v[12] ^= counter as u64;
+    v[13] ^= (counter >> 64) as u64;
+    if last {
+        v[14] = !v[14];
+    }
+
+    fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = v[c].wrapping_add(v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = v[c].wrapping_add(v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    }
+
+    for s in BLAKE2B_SIGMA.iter() {
+        g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+
+    for i in 0..8 {
+        h[i] ^= v[i] ^ v[i + 8];
+    }
+    wipe_value(&mut v);
+    wipe_value(&mut m);
+}
+
+// Function to compute unkeyed BLAKE2b with 1 to 64 output bytes over the
+// concatenation of several parts
+fn blake2b(output: &mut [u8], parts: &[&[u8]]) {
+
+    assert!(!output.is_empty() && output.len() <= 64);
+
+    let mut h = BLAKE2B_IV;
+    h[0] ^= 0x01010000 ^ output.len() as u64;
+
+    let mut message: Vec<u8> = parts.concat();
+    let block_count = message.len().div_ceil(128).max(1);
+    let message_length = message.len();
+    message.resize(block_count * 128, 0);
+
+    for (i, block) in message.chunks_exact(128).enumerate() {
+        let last = i + 1 == block_count;
+        let counter = if last { message_length } else { (i + 1) * 128 };
+        blake2b_compress(&mut h, block, counter as u128, last);
+    }
+
+    let mut digest = [0u8; 64];
+    for (bytes, word) in digest.chunks_exact_mut(8).zip(h.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    output.copy_from_slice(&digest[..output.len()]);
+
+    wipe(&mut message);
+    wipe_value(&mut digest);
+    wipe_value(&mut h);
+}
+
+// Function to compute the variable-length hash H' of Argon2
+fn blake2b_long(output: &mut [u8], parts: &[&[u8]]) {
+
+    let length_prefix = (output.len() as u32).to_le_bytes();
+    let mut prefixed = vec![&length_prefix[..]];
+    prefixed.extend_from_slice(parts);
+
+    if output.len() <= 64 {
+        blake2b(output, &prefixed);
+        return;
+    }
+
+    // 32 bytes of every intermediate 64-byte hash, then the remainder at once
+    let mut v = [0u8; 64];
+    blake2b(&mut v, &prefixed);
+    let mut written = 0;
+    loop {
+        output[written..written + 32].copy_from_slice(&v[..32]);
+        written += 32;
+        if output.len() - written <= 64 {
+            break;
+        }
+        let previous = v;
+        blake2b(&mut v, &[&previous]);
+    }
+    let previous = v;
+    blake2b(&mut output[written..], &[&previous]);
+    wipe_value(&mut v);
+}
+
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+const VERSION: u32 = 0x13;
+const ARGON2ID: u32 = 2;
+
+type Block = [u64; BLOCK_WORDS];
+
+// Function to run the BLAKE2b based round GB of Argon2
+#[inline(always)]
+fn gb(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize) {
+    let multiply = |x: u64, y: u64| 2u64.wrapping_mul(x & 0xffff_ffff).wrapping_mul(y & 0xffff_ffff);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(multiply(v[a], v[b]));
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]).wrapping_add(multiply(v[c], v[d]));
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(multiply(v[a], v[b]));
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]).wrapping_add(multiply(v[c], v[d]));
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+// Function to apply the permutation P to 16 words
+fn permute(v: &mut [u64; 16]) {
+    gb(v, 0, 4, 8, 12);
+    gb(v, 1, 5, 9, 13);
+    gb(v, 2, 6, 10, 14);
+    gb(v, 3, 7, 11, 15);
+    gb(v, 0, 5, 10, 15);
+    gb(v, 1, 6, 11, 12);
+    gb(v, 2, 7, 8, 13);
+    gb(v, 3, 4, 9, 14);
+}
+
+// Function to compute the compression G(x, y), xored onto the current content
+// of `output` if `with_xor` is set (passes after the first one)
+//
+// The temporaries are not wiped, they are derived from blocks that stay in
+// memory until the whole memory is wiped at the end.
+fn fill_block(x: &Block, y: &Block, output: &mut Block, with_xor: bool) {
+
+    let mut r = [0u64; BLOCK_WORDS];
+    for i in 0..BLOCK_WORDS {
+        r[i] = x[i] ^ y[i];
+    }
+    let mut q = r;
+    let mut v = [0u64; 16];
+
+    // rows of eight 16-byte registers
+    for row in q.chunks_exact_mut(16) {
+        v.copy_from_slice(row);
+        permute(&mut v);
+        row.copy_from_slice(&v);
+    }
+
+    // columns, made of two neighbouring words of every row
+    for column in 0..8 {
+        for j in 0..8 {
+            v[2 * j] = q[16 * j + 2 * column];
+            v[2 * j + 1] = q[16 * j + 2 * column + 1];
+        }
+        permute(&mut v);
+        for j in 0..8 {
+            q[16 * j + 2 * column] = v[2 * j];
+            q[16 * j + 2 * column + 1] = v[2 * j + 1];
+        }
+    }
+
+    for i in 0..BLOCK_WORDS {
+        output[i] = if with_xor { output[i] ^ q[i] ^ r[i] } else { q[i] ^ r[i] };
+    }
+}
+
+// Argon2 memory shared by the lane threads of one slice
+struct Memory {
+    blocks: *mut Block,
+    lane_length: usize,
+}
+
+// every lane thread only writes blocks of its own segment, see above
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+impl Memory {
+
+    unsafe fn block(&self, lane: usize, index: usize) -> &Block {
+        &*self.blocks.add(lane * self.lane_length + index)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn block_mut(&self, lane: usize, index: usize) -> &mut Block {
+        &mut *self.blocks.add(lane * self.lane_length + index)
+    }
+}
+
+// Argon2 instance parameters needed while filling the memory
+struct Instance {
+    passes: u32,
+    lanes: usize,
+    lane_length: usize,
+    segment_length: usize,
+}
+
+impl Instance {
+
+    // Function to map a pseudo-random value to the index of the reference
+    // block within its lane
+    fn reference_index(&self, pass: u32, slice: usize, index: usize, pseudo_random: u32, same_lane: bool) -> usize {
+
+        let finished = if pass == 0 { slice * self.segment_length } else { self.lane_length - self.segment_length };
+        let area_size = if same_lane {
+            finished + index - 1
+        } else if index == 0 {
+            finished - 1
+        } else {
+            finished
+        };
+
+        let x = (pseudo_random as u64 * pseudo_random as u64) >> 32;
+        let relative = area_size - 1 - ((area_size as u64 * x) >> 32) as usize;
+        let start = if pass == 0 || slice == SYNC_POINTS - 1 { 0 } else { (slice + 1) * self.segment_length };
+        (start + relative) % self.lane_length
+    }
+
+    // Function to fill one segment of a lane
+    fn fill_segment(&self, memory: &Memory, pass: u32, lane: usize, slice: usize) {
+
+        // Argon2id uses data-independent addresses in the first half of the first pass
+        let independent = pass == 0 && slice < SYNC_POINTS / 2;
+        let zero = [0u64; BLOCK_WORDS];
+        let mut input = [0u64; BLOCK_WORDS];
+        let mut addresses = [0u64; BLOCK_WORDS];
+        input[..6].copy_from_slice(&[pass as u64, lane as u64, slice as u64,
+                                     (self.lane_length * self.lanes) as u64, self.passes as u64, ARGON2ID as u64]);
+
+        let next_addresses = |input: &mut Block, addresses: &mut Block| {
+            input[6] += 1;
+            let mut temporary = [0u64; BLOCK_WORDS];
+            fill_block(&zero, input, &mut temporary, false);
+            fill_block(&zero, &temporary, addresses, false);
+        };
+
+        let start_index = if pass == 0 && slice == 0 { 2 } else { 0 };
+        if independent && start_index == 2 {
+            next_addresses(&mut input, &mut addresses);
+        }
+
+        for index in start_index..self.segment_length {
+            let current = slice * self.segment_length + index;
+            let previous = if current == 0 { self.lane_length - 1 } else { current - 1 };
+
+            let pseudo_random = if independent {
+                if index % BLOCK_WORDS == 0 {
+                    next_addresses(&mut input, &mut addresses);
+                }
+                addresses[index % BLOCK_WORDS]
+            } else {
+                unsafe { memory.block(lane, previous)[0] }
+            };
+
+            let reference_lane = if pass == 0 && slice == 0 { lane } else { (pseudo_random >> 32) as usize % self.lanes };
+            let reference = self.reference_index(pass, slice, index, pseudo_random as u32, reference_lane == lane);
+
+            unsafe {
+                let previous_block = memory.block(lane, previous);
+                let reference_block = memory.block(reference_lane, reference);
+                let output = memory.block_mut(lane, current);
+                debug_assert!(!std::ptr::eq(reference_block, output));
+                fill_block(previous_block, reference_block, output, pass > 0);
+            }
+        }
+    }
+}
+
+// Function to fill `output` with Argon2id version 1.3 of the password,
+// using `memory_kib` KiB of memory, `passes` passes over it and `lanes` lanes
+pub fn argon2id(password: &[u8], salt: &[u8], memory_kib: u32, passes: u32, lanes: u32, output: &mut [u8]) {
+    argon2id_with_secret(password, salt, &[], &[], memory_kib, passes, lanes, output);
+}
+
+// Function to compute Argon2id with the optional secret key and associated data
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn argon2id_with_secret(password: &[u8],
+                                   salt: &[u8],
+                                   secret: &[u8],
+                                   associated_data: &[u8],
+                                   memory_kib: u32,
+                                   passes: u32,
+                                   lanes: u32,
+                                   output: &mut [u8]) {
+
+    assert!((1..1 << 24).contains(&lanes), "Argon2 needs between 1 and 2^24 - 1 lanes");
+    assert!(memory_kib >= 8 * lanes, "Argon2 needs at least 8 KiB of memory per lane");
+    assert!(passes >= 1, "Argon2 needs at least one pass");
+    assert!(output.len() >= 4, "Argon2 output has to be at least 4 bytes long");
+
+    let lanes = lanes as usize;
+    let segment_length = memory_kib as usize / (SYNC_POINTS * lanes);
+    let lane_length = segment_length * SYNC_POINTS;
+    let instance = Instance { passes, lanes, lane_length, segment_length };
+
+    // H0 over all parameters and inputs
+    let le = |value: usize| (value as u32).to_le_bytes();
+    let mut h0 = [0u8; 64];
+    blake2b(&mut h0, &[&le(lanes), &le(output.len()), &memory_kib.to_le_bytes(), &passes.to_le_bytes(),
+                       &VERSION.to_le_bytes(), &ARGON2ID.to_le_bytes(),
+                       &le(password.len()), password, &le(salt.len()), salt,
+                       &le(secret.len()), secret, &le(associated_data.len()), associated_data]);
+
+    let mut blocks = vec![[0u64; BLOCK_WORDS]; lane_length * lanes];
+    let mut bytes = [0u8; BLOCK_WORDS * 8];
+
+    // the first two blocks of every lane
+    for lane in 0..lanes {
+        for index in 0..2 {
+            blake2b_long(&mut bytes, &[&h0, &le(index), &le(lane)]);
+            for (word, chunk) in blocks[lane * lane_length + index].iter_mut().zip(bytes.chunks_exact(8)) {
+                *word = u64::from_le_bytes(chunk.try_into().unwrap());
+            }
+        }
+    }
+
+    let memory = Memory { blocks: blocks.as_mut_ptr(), lane_length };
+    let workers = WorkerPool::new(available_threads().min(lanes));
+    for pass in 0..passes {
+        for slice in 0..SYNC_POINTS {
+            let (instance, memory) = (&instance, &memory);
+            workers.run((0..lanes).map(|lane| {
+                Box::new(move || instance.fill_segment(memory, pass, lane, slice)) as Task
+            }).collect());
+        }
+    }
+
+    // xor of the last block of every lane
+    let mut last = [0u64; BLOCK_WORDS];
+    for lane in 0..lanes {
+        for (l, b) in last.iter_mut().zip(blocks[(lane + 1) * lane_length - 1].iter()) {
+            *l ^= *b;
+        }
+    }
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(last.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    blake2b_long(output, &[&bytes]);
+
+    wipe(&mut blocks);
+    wipe_value(&mut last);
+    wipe_value(&mut bytes);
+    wipe_value(&mut h0);
+}
+
+#[test]
+fn test_blake2b_vectors() {
+
+    // RFC 7693, appendix A
+    let mut digest = [0u8; 64];
+    blake2b(&mut digest, &[b"abc"]);
+    assert_eq!(hex::encode(&digest[..]), concat!("ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1",
+                                                 "7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"));
+
+    // more than one block, split into parts
+    let long: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+    let mut split = [0u8; 64];
+    blake2b(&mut digest, &[&long]);
+    blake2b(&mut split, &[&long[..1], &long[1..200], &long[200..]]);
+    assert_eq!(digest, split);
+}
+
+#[test]
+fn test_argon2id_rfc9106_vector() {
+
+    // RFC 9106, section 5.3
+    let mut tag = [0u8; 32];
+    argon2id_with_secret(&[0x01; 32], &[0x02; 16], &[0x03; 8], &[0x04; 12], 32, 3, 4, &mut tag);
+    assert_eq!(hex::encode(tag), "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659");
+}
@@ -0,0 +1,165 @@
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::aes_backend::{new_cipher, Backend, ALL_BACKENDS};
+use crate::aes_ctr_hmac::hmac_sha256;
+use crate::aes_ctr_optimized::apply_keystream;
+
+// In-memory throughput measurements for every backend, key size and mode
+
+pub const DEFAULT_BUFFER_SIZES: [usize; 3] = [4 * 1024, 64 * 1024, 1024 * 1024];
+
+// bytes processed per measurement unless told otherwise
+pub const DEFAULT_TOTAL_BYTES: usize = 64 * 1024 * 1024;
+
+// measurements are repeated until at least this much time has passed, so
+// small sizes never divide by a zero duration
+const MIN_MEASURE_TIME: Duration = Duration::from_millis(10);
+
+/// Encryption modes covered by the benchmark
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BenchMode {
+    // plain AES-CTR keystream
+    Ctr,
+    // AES-CTR followed by HMAC-SHA-256 over the ciphertext
+    CtrHmac,
+}
+
+pub const ALL_MODES: [BenchMode; 2] = [BenchMode::Ctr, BenchMode::CtrHmac];
+
+impl BenchMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            BenchMode::Ctr => "ctr",
+            BenchMode::CtrHmac => "ctr-hmac",
+        }
+    }
+}
+
+/// Result of one measurement
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    pub backend: Backend,
+    pub key_bits: usize,
+    pub mode: BenchMode,
+    pub buffer_size: usize,
+    pub megabytes_per_second: f64,
+    // only known where a cycle counter can be read, 0 if it went backwards
+    // (the thread moved to a core with an unsynchronized counter)
+    pub cycles_per_byte: Option<f64>,
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_cycle_counter() -> Option<u64> {
+    Some(unsafe { std::arch::x86_64::_rdtsc() })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn read_cycle_counter() -> Option<u64> {
+    None
+}
+
+// Function to process `total_bytes` in buffers of the given size, at least one
+// and more until MIN_MEASURE_TIME has passed
+fn measure(backend: Backend, key_bits: usize, mode: BenchMode, buffer_size: usize, total_bytes: usize) -> BenchResult {
+
+    let key: Vec<u8> = (0..key_bits / 8).map(|i| i as u8).collect();
+    let mac_key = [0x5a; 32];
+    let cipher = new_cipher(backend, &key);
+    let mut buffer = vec![0u8; buffer_size];
+    let repetitions = (total_bytes / buffer_size.max(1)).max(1);
+
+    // one untimed round to warm up caches and page in the buffer
+    apply_keystream(cipher.as_ref(), 0, &mut buffer);
+
+    let start_cycles = read_cycle_counter();
+    let start = Instant::now();
+    let mut counter = 0u128;
+    let mut done = 0;
+    while done < repetitions || start.elapsed() < MIN_MEASURE_TIME {
+        counter = apply_keystream(cipher.as_ref(), counter, &mut buffer);
+        if mode == BenchMode::CtrHmac {
+            let tag = hmac_sha256(&mac_key, &[&buffer]);
+            buffer[0] ^= tag[0];
+        }
+        done += 1;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    let end_cycles = read_cycle_counter();
+
+    let bytes = (done * buffer_size) as f64;
+    BenchResult {
+        backend,
+        key_bits,
+        mode,
+        buffer_size,
+        megabytes_per_second: bytes / seconds / 1e6,
+        cycles_per_byte: start_cycles.zip(end_cycles).map(|(start, end)| end.saturating_sub(start) as f64 / bytes),
+    }
+}
+
+// Function to measure all combinations of available backends, key sizes,
+// modes and the given buffer sizes
+pub fn run_benchmarks(buffer_sizes: &[usize], total_bytes: usize) -> Vec<BenchResult> {
+
+    let mut results = Vec::new();
+    for backend in ALL_BACKENDS.iter().filter(|backend| backend.is_available()) {
+        for key_bits in [128, 192, 256].iter() {
+            for mode in ALL_MODES.iter() {
+                for buffer_size in buffer_sizes.iter() {
+                    results.push(measure(*backend, *key_bits, *mode, *buffer_size, total_bytes));
+                }
+            }
+        }
+    }
+    results
+}
+
+// Function to format results as an aligned text table
+pub fn format_table(results: &[BenchResult]) -> String {
+
+    let mut table = String::new();
+    writeln!(table, "{:<10} {:>4} {:<9} {:>12} {:>10} {:>12}",
+             "backend", "key", "mode", "buffer", "MB/s", "cycles/byte").unwrap();
+    for result in results {
+        let cycles = match result.cycles_per_byte {
+            Some(cycles) => format!("{:.2}", cycles),
+            None => String::from("-"),
+        };
+        writeln!(table, "{:<10} {:>4} {:<9} {:>12} {:>10.1} {:>12}",
+                 result.backend.name(), result.key_bits, result.mode.name(), result.buffer_size,
+                 result.megabytes_per_second, cycles).unwrap();
+    }
+    table
+}
+
+// Function to format results as a JSON array of objects
+pub fn format_json(results: &[BenchResult]) -> String {
+
+    let entries: Vec<String> = results.iter().map(|result| {
+        let cycles = match result.cycles_per_byte {
+            Some(cycles) => format!("{:.3}", cycles),
+            None => String::from("null"),
+        };
+        format!("  {{\"backend\": \"{}\", \"key_bits\": {}, \"mode\": \"{}\", \"buffer_size\": {}, \
+                 \"megabytes_per_second\": {:.3}, \"cycles_per_byte\": {}}}",
+                result.backend.name(), result.key_bits, result.mode.name(), result.buffer_size,
+                result.megabytes_per_second, cycles)
+    }).collect();
+
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+#[test]
+fn test_benchmarks_cover_all_combinations() {
+
+    let results = run_benchmarks(&[16, 100], 1024);
+    let backends = ALL_BACKENDS.iter().filter(|backend| backend.is_available()).count();
+    assert_eq!(results.len(), backends * 3 * ALL_MODES.len() * 2);
+    assert!(results.iter().all(|result| result.megabytes_per_second > 0.0 && result.megabytes_per_second.is_finite()));
+    assert!(results.iter().any(|result| result.key_bits == 192));
+
+    let json = format_json(&results);
+    assert!(json.starts_with("[\n  {\"backend\": \"t-table\", \"key_bits\": 128, \"mode\": \"ctr\", \"buffer_size\": 16,"));
+    assert_eq!(format_table(&results).lines().count(), results.len() + 1);
+}
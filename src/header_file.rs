@@ -0,0 +1,130 @@
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use crate::aes_backend::ExpandedKey;
+use crate::aes_ctr_optimized::{apply_keystream_streamed, stream_buffer_size};
+use crate::secret::SecretBytes;
+
+// Files made of a header followed by AES-CTR ciphertext
+//
+// The passphrase and the key derivation modes only differ in their header and
+// in how key and IV come out of it. Both share the error for a bad header, the
+// keystream over the body and the file handling here: decryption reads the
+// whole header before the output file is created, so a wrong input never
+// truncates an existing file. I/O errors are returned to the caller.
+
+/// Error returned when the input does not start with a valid header of the format
+#[derive(Debug, PartialEq)]
+pub struct InvalidHeader(pub &'static str);
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "input does not start with a valid {} header", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+// Function to encrypt or decrypt the body with the key and IV derived from the header
+pub(crate) fn apply_header_keystream<R: Read, W: Write>(key: SecretBytes, iv: [u8; 16], reader: &mut R, writer: &mut W,
+                                                        thread_count: usize) -> io::Result<()> {
+
+    let cipher = ExpandedKey::new(key.as_slice());
+    drop(key);
+
+    apply_keystream_streamed(&cipher, u128::from_be_bytes(iv), reader, writer,
+                             stream_buffer_size(thread_count), thread_count)?;
+    Ok(())
+}
+
+// Function to encrypt a file, `encrypt` writes the header and the ciphertext
+pub(crate) fn encrypt_file<F>(input_file_path: &Path, output_file_path: &Path, encrypt: F) -> io::Result<()>
+    where F: FnOnce(&mut BufReader<File>, &mut BufWriter<File>) -> io::Result<()> {
+
+    let mut reader = BufReader::new(File::open(input_file_path)?);
+    let mut writer = BufWriter::new(File::create(output_file_path)?);
+    encrypt(&mut reader, &mut writer)?;
+    writer.flush()
+}
+
+// Function to decrypt a file, the output file is only created once
+// `read_header` succeeded and `decrypt_body` then gets the rest of the input
+pub(crate) fn decrypt_file<H, E, F, G>(input_file_path: &Path, output_file_path: &Path, read_header: F,
+                                      decrypt_body: G) -> Result<(), E>
+    where E: From<io::Error>,
+          F: FnOnce(&mut BufReader<File>) -> Result<H, E>,
+          G: FnOnce(H, &mut BufReader<File>, &mut BufWriter<File>) -> io::Result<()> {
+
+    let mut reader = BufReader::new(File::open(input_file_path)?);
+    let header = read_header(&mut reader)?;
+
+    let mut writer = BufWriter::new(File::create(output_file_path)?);
+    decrypt_body(header, &mut reader, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Function to check a header format in memory: the ciphertext is
+// `header_length` bytes longer than the plaintext and decrypts to it, the
+// wrong secret gives a different plaintext and a foreign magic is refused.
+// Returns the ciphertext for further checks.
+#[cfg(test)]
+pub(crate) fn check_round_trip<E: fmt::Debug>(plaintext: &[u8], header_length: usize,
+                                              encrypt: impl FnOnce(&mut &[u8], &mut Vec<u8>) -> io::Result<()>,
+                                              decrypt: impl Fn(&[u8], &mut &[u8], &mut Vec<u8>) -> Result<(), E>,
+                                              secret: &[u8], wrong_secret: &[u8]) -> Vec<u8> {
+
+    let mut encrypted = Vec::new();
+    encrypt(&mut &plaintext[..], &mut encrypted).unwrap();
+    assert_eq!(encrypted.len(), header_length + plaintext.len());
+
+    let mut decrypted = Vec::new();
+    decrypt(secret, &mut &encrypted[..], &mut decrypted).unwrap();
+    assert_eq!(decrypted, plaintext);
+
+    let mut wrong = Vec::new();
+    decrypt(wrong_secret, &mut &encrypted[..], &mut wrong).unwrap();
+    assert_ne!(wrong, plaintext);
+
+    assert!(decrypt(secret, &mut &b"AESCTRXX"[..], &mut Vec::new()).is_err());
+    encrypted
+}
+
+#[test]
+fn test_decrypt_file_keeps_output_on_invalid_header() {
+
+    use crate::aes_ctr_optimized::CtrFileError;
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("header-file");
+    let input_path = directory.join("input.bin");
+    let output_path = directory.join("output.bin");
+    std::fs::write(&input_path, b"HEADERplaintext").unwrap();
+    std::fs::write(&output_path, b"existing").unwrap();
+
+    let read_header = |reader: &mut BufReader<File>| {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic).map_err(|_| InvalidHeader("test"))?;
+        if &magic == b"HEADER" { Ok(magic.len()) } else { Err(CtrFileError::InvalidHeader(InvalidHeader("test"))) }
+    };
+    let copy_body = |_, reader: &mut BufReader<File>, writer: &mut BufWriter<File>| {
+        io::copy(reader, writer).map(|_| ())
+    };
+
+    decrypt_file(&input_path, &output_path, read_header, copy_body).unwrap();
+    assert_eq!(std::fs::read(&output_path).unwrap(), b"plaintext");
+
+    std::fs::write(&input_path, b"HEADX").unwrap();
+    std::fs::write(&output_path, b"existing").unwrap();
+    assert!(matches!(decrypt_file(&input_path, &output_path, read_header, copy_body),
+                     Err(CtrFileError::InvalidHeader(InvalidHeader("test")))));
+    assert_eq!(std::fs::read(&output_path).unwrap(), b"existing");
+
+    // a missing input is an error before the header is read
+    assert!(matches!(decrypt_file(&directory.join("missing"), &output_path, read_header, copy_body), Err(CtrFileError::Io(_))));
+    assert!(encrypt_file(&directory.join("missing"), &output_path, |_, _| Ok(())).is_err());
+    assert_eq!(InvalidHeader("test").to_string(), "input does not start with a valid test header");
+}
@@ -0,0 +1,222 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::aes_backend::BlockCipher;
+use crate::aes_ctr_hmac::hmac_sha256;
+
+// Ledger of the AES-CTR counter ranges used with every key
+//
+// Text file, one entry per line after the header:
+//
+//     aes-ctr iv ledger 1 <salt>
+//     <key fingerprint> <first counter block> <number of blocks>
+//
+// Salt, fingerprint and first counter block are 32 hex characters, the number
+// of blocks is decimal. The fingerprint is HMAC-SHA-256 of E_K(salt) (the AES
+// key K encrypting the random salt of the ledger), truncated to 128 bits: it
+// identifies the key within one ledger without revealing the key or any
+// keystream block. Ranges wrap around like the counter itself.
+//
+// Every reservation holds an exclusive lock on the ledger file while it reads
+// all entries and appends its own, so concurrent processes never both reserve
+// the same range.
+
+const HEADER: &str = "aes-ctr iv ledger 1";
+const FINGERPRINT_LABEL: &[u8] = b"aes-ctr iv ledger key fingerprint";
+
+/// Errors of the ledger, a reused counter range refuses the encryption
+#[derive(Debug)]
+pub enum LedgerError {
+    // the ledger file can not be opened, locked, read or written
+    Io(io::Error),
+    // a line of the ledger file can not be parsed
+    Corrupt { line: usize },
+    // the range overlaps the one reserved earlier for the same key
+    Reuse { first: u128, block_count: u128 },
+    // the input is no regular file, so the number of blocks is not known up front
+    UnknownLength,
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::Io(e) => write!(f, "IV ledger can not be accessed: {}", e),
+            LedgerError::Corrupt { line } => write!(f, "IV ledger is corrupt in line {}", line),
+            LedgerError::Reuse { first, block_count } =>
+                write!(f, "counter range overlaps {} block(s) from {:032x} already used with this key", block_count, first),
+            LedgerError::UnknownLength => write!(f, "IV ledger needs a regular input file to know the counter range"),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+impl From<io::Error> for LedgerError {
+    fn from(e: io::Error) -> LedgerError {
+        LedgerError::Io(e)
+    }
+}
+
+/// Ledger file recording the counter ranges consumed per key
+#[derive(Clone, Debug)]
+pub struct IvLedger {
+    path: PathBuf,
+}
+
+impl IvLedger {
+
+    // Function to use the ledger at `path`, created with the first reservation
+    pub fn new(path: &Path) -> IvLedger {
+        IvLedger { path: path.to_path_buf() }
+    }
+
+    // Function to reserve the counter blocks for `byte_length` bytes starting
+    // at `counter`, fails if any of them was reserved with the same key before
+    pub fn reserve(&self, cipher: &dyn BlockCipher, counter: u128, byte_length: u64) -> Result<(), LedgerError> {
+
+        let block_count = (byte_length as u128).div_ceil(16);
+        if block_count == 0 {
+            return Ok(());
+        }
+
+        // the lock is released when the file is closed
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&self.path)?;
+        file.lock()?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let content = String::from_utf8(bytes).map_err(|e| {
+            let valid = &e.as_bytes()[..e.utf8_error().valid_up_to()];
+            LedgerError::Corrupt { line: valid.iter().filter(|byte| **byte == b'\n').count() + 1 }
+        })?;
+
+        let salt = match content.lines().next() {
+            Some(header) => parse_header(header).ok_or(LedgerError::Corrupt { line: 1 })?,
+            None => create_header(&mut file)?,
+        };
+        let fingerprint = key_fingerprint(cipher, salt);
+
+        for (i, line) in content.lines().enumerate().skip(1) {
+            let (entry_fingerprint, first, count) = parse_entry(line).ok_or(LedgerError::Corrupt { line: i + 1 })?;
+            if entry_fingerprint == fingerprint && ranges_overlap(first, count, counter, block_count) {
+                return Err(LedgerError::Reuse { first, block_count: count });
+            }
+        }
+
+        // an entry torn by a crash makes the ledger corrupt, so its range is
+        // never silently forgotten
+        file.write_all(format!("{:032x} {:032x} {}\n", fingerprint, counter, block_count).as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    // Function to reserve the counter blocks for the current length of the
+    // regular file at `input_file_path` and return that length, the caller
+    // must not encrypt more than that, also if the file grows meanwhile
+    pub fn reserve_file(&self, cipher: &dyn BlockCipher, counter: u128, input_file_path: &Path) -> Result<u64, LedgerError> {
+
+        let length = std::fs::metadata(input_file_path).ok().filter(|metadata| metadata.is_file())
+                                                       .ok_or(LedgerError::UnknownLength)?.len();
+        self.reserve(cipher, counter, length)?;
+        Ok(length)
+    }
+}
+
+// Function to start an empty ledger with a fresh random salt
+fn create_header(file: &mut File) -> io::Result<u128> {
+
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).map_err(|e| io::Error::other(e.to_string()))?;
+    let salt = u128::from_be_bytes(salt);
+    file.write_all(format!("{} {:032x}\n", HEADER, salt).as_bytes())?;
+    Ok(salt)
+}
+
+fn parse_header(line: &str) -> Option<u128> {
+    let salt = line.strip_prefix(HEADER)?.strip_prefix(' ')?;
+    parse_hex_block(salt)
+}
+
+fn parse_entry(line: &str) -> Option<(u128, u128, u128)> {
+
+    let mut fields = line.split(' ');
+    let fingerprint = parse_hex_block(fields.next()?)?;
+    let first = parse_hex_block(fields.next()?)?;
+    let count: u128 = fields.next()?.parse().ok()?;
+    if fields.next().is_some() || count == 0 {
+        return None;
+    }
+    Some((fingerprint, first, count))
+}
+
+fn parse_hex_block(field: &str) -> Option<u128> {
+    if field.len() != 32 {
+        return None;
+    }
+    u128::from_str_radix(field, 16).ok()
+}
+
+// Function to derive the fingerprint of a key for the ledger with this salt
+fn key_fingerprint(cipher: &dyn BlockCipher, salt: u128) -> u128 {
+    let tag = hmac_sha256(&cipher.encrypt_block(salt).to_be_bytes(), &[FINGERPRINT_LABEL]);
+    u128::from_be_bytes(tag[..16].try_into().unwrap())
+}
+
+// Function to check whether two counter ranges share a block, both may wrap
+// around from 2^128 - 1 to 0
+fn ranges_overlap(first: u128, count: u128, other_first: u128, other_count: u128) -> bool {
+    // the distance from the start of one range to the other start, modulo 2^128
+    other_first.wrapping_sub(first) < count || first.wrapping_sub(other_first) < other_count
+}
+
+#[test]
+fn test_ledger_refuses_reused_ranges() {
+
+    use crate::aes_backend::ExpandedKey;
+    use crate::test_dir::TestDir;
+
+    let directory = TestDir::new("ledger");
+    let path = directory.join("ledger.txt");
+    let ledger = IvLedger::new(&path);
+    let key = ExpandedKey::new(&[0x11u8; 16]);
+    let other_key = ExpandedKey::new(&[0x22u8; 16]);
+
+    // 33 bytes need the blocks 100, 101 and 102
+    ledger.reserve(&key, 100, 33).unwrap();
+    assert!(matches!(ledger.reserve(&key, 102, 1), Err(LedgerError::Reuse { first: 100, block_count: 3 })));
+    assert!(matches!(ledger.reserve(&key, 90, 161), Err(LedgerError::Reuse { .. })));
+    ledger.reserve(&key, 103, 16).unwrap();
+    ledger.reserve(&key, 90, 160).unwrap();
+    ledger.reserve(&other_key, 100, 33).unwrap();
+    ledger.reserve(&key, 100, 0).unwrap();
+
+    // ranges wrap around like the counter
+    ledger.reserve(&key, u128::MAX, 32).unwrap();
+    assert!(matches!(ledger.reserve(&key, 0, 1), Err(LedgerError::Reuse { .. })));
+
+    // the fingerprints do not contain the key
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().count(), 1 + 5);
+    assert!(!content.contains("11111111"));
+
+    // concurrent processes are serialized by the file lock, here threads with
+    // their own file descriptors: exactly one of them gets the range
+    let successes: usize = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| IvLedger::new(&path).reserve(&key, 1000, 16).is_ok())).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap() as usize).sum()
+    });
+    assert_eq!(successes, 1);
+
+    std::fs::write(&path, "aes-ctr iv ledger 1 00\n").unwrap();
+    assert!(matches!(ledger.reserve(&key, 0, 1), Err(LedgerError::Corrupt { line: 1 })));
+
+    // bytes that are no text are reported in their line, I/O errors as such
+    let mut content = content.into_bytes();
+    content.splice(content.len() - 1..content.len() - 1, [0xff]);
+    std::fs::write(&path, &content).unwrap();
+    assert!(matches!(ledger.reserve(&key, 0, 1), Err(LedgerError::Corrupt { line: 6 })));
+    assert!(matches!(IvLedger::new(directory.path()).reserve(&key, 0, 1), Err(LedgerError::Io(_))));
+}
@@ -0,0 +1,470 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use hmac::Mac;
+
+use crate::aes_ctr_hmac::{hmac_sha256, HmacSha256};
+use crate::argon2::argon2id;
+use crate::scrypt::scrypt;
+use crate::secret::wipe_value;
+
+// Password-based key derivation
+//
+// Every KDF is identified by one byte followed by its cost parameters, so the
+// parameters can be stored next to the ciphertext and read back without
+// knowing them in advance.
+
+pub const SALT_LENGTH: usize = 16;
+pub const DEFAULT_PBKDF2_ITERATIONS: u32 = 600_000;
+
+// 128 MiB, as recommended for file encryption in the scrypt paper
+pub const DEFAULT_SCRYPT: KdfParams = KdfParams::Scrypt { log_n: 17, r: 8, p: 1 };
+
+// second recommended option of RFC 9106: 64 MiB, 3 passes, 4 lanes
+pub const DEFAULT_ARGON2ID: KdfParams = KdfParams::Argon2id { memory_kib: 64 * 1024, iterations: 3, lanes: 4 };
+
+const PBKDF2_ID: u8 = 1;
+const SCRYPT_ID: u8 = 2;
+const ARGON2ID_ID: u8 = 3;
+
+// upper bounds for calibration and for parameters read from a file
+const MAX_SCRYPT_LOG_N: u8 = 24;
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+// scrypt p and Argon2id lanes
+const MAX_PARALLELISM: u32 = 64;
+
+/// Upper bounds for the cost of KDF parameters read from a file
+///
+/// `validate` only keeps a header from crashing the derivation; these bounds
+/// keep it from demanding gigabytes of memory or hours of work before a file
+/// is decrypted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfLimits {
+    // memory of scrypt (all p instances) or Argon2id
+    pub memory_mib: u32,
+    pub pbkdf2_iterations: u32,
+    pub argon2id_passes: u32,
+}
+
+impl KdfLimits {
+
+    // well above the defaults and what calibrating for a few seconds yields
+    pub const DEFAULT: KdfLimits = KdfLimits { memory_mib: 1024, pbkdf2_iterations: 10_000_000, argon2id_passes: 16 };
+
+    // only the range checked by `validate`
+    pub const NONE: KdfLimits = KdfLimits { memory_mib: u32::MAX, pbkdf2_iterations: u32::MAX, argon2id_passes: u32::MAX };
+}
+
+/// Error returned when KDF parameters cost more than the limits allow
+#[derive(Debug, PartialEq)]
+pub struct CostLimitExceeded(pub KdfParams);
+
+impl fmt::Display for CostLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "key derivation {} exceeds the cost limits", self.0)
+    }
+}
+
+impl std::error::Error for CostLimitExceeded {}
+
+/// Available key derivation functions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KdfAlgorithm {
+    Pbkdf2,
+    Scrypt,
+    Argon2id,
+}
+
+impl KdfAlgorithm {
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KdfAlgorithm::Pbkdf2 => "pbkdf2",
+            KdfAlgorithm::Scrypt => "scrypt",
+            KdfAlgorithm::Argon2id => "argon2id",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<KdfAlgorithm> {
+        [KdfAlgorithm::Pbkdf2, KdfAlgorithm::Scrypt, KdfAlgorithm::Argon2id].iter().cloned()
+                                                                            .find(|algorithm| algorithm.name() == name)
+    }
+
+    pub fn default_params(self) -> KdfParams {
+        match self {
+            KdfAlgorithm::Pbkdf2 => KdfParams::Pbkdf2 { iterations: DEFAULT_PBKDF2_ITERATIONS },
+            KdfAlgorithm::Scrypt => DEFAULT_SCRYPT,
+            KdfAlgorithm::Argon2id => DEFAULT_ARGON2ID,
+        }
+    }
+
+    // Function to override some cost parameters of the defaults
+    //
+    // `iterations` is the PBKDF2 iteration count or the number of Argon2id
+    // passes, `memory_mib` the memory of scrypt (a power of two) or Argon2id,
+    // `parallelism` the scrypt p or the number of Argon2id lanes.
+    pub fn params_with_costs(self,
+                             iterations: Option<u32>,
+                             memory_mib: Option<u32>,
+                             parallelism: Option<u32>) -> Result<KdfParams, String> {
+
+        let params = match self.default_params() {
+            KdfParams::Pbkdf2 { iterations: default } => {
+                if memory_mib.is_some() || parallelism.is_some() {
+                    return Err(String::from("PBKDF2 has no memory or parallelism cost"));
+                }
+                KdfParams::Pbkdf2 { iterations: iterations.unwrap_or(default) }
+            }
+            KdfParams::Scrypt { log_n, r, p } => {
+                if iterations.is_some() {
+                    return Err(String::from("scrypt has no iteration count"));
+                }
+                // 128 * r * N bytes, so with r = 8 one MiB is N = 2^10
+                let log_n = match memory_mib {
+                    Some(memory) if memory.is_power_of_two() => memory.trailing_zeros() as u8 + 10,
+                    Some(memory) => return Err(format!("scrypt memory has to be a power of two (is {} MiB)", memory)),
+                    None => log_n,
+                };
+                KdfParams::Scrypt { log_n, r, p: parallelism.unwrap_or(p) }
+            }
+            KdfParams::Argon2id { memory_kib, iterations: passes, lanes } => KdfParams::Argon2id {
+                memory_kib: memory_mib.map(|memory| memory.saturating_mul(1024)).unwrap_or(memory_kib),
+                iterations: iterations.unwrap_or(passes),
+                lanes: parallelism.unwrap_or(lanes),
+            },
+        };
+
+        params.validate().map(|_| params)
+    }
+}
+
+/// Key derivation function together with its cost parameters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KdfParams {
+    // PBKDF2-HMAC-SHA-256 (RFC 8018)
+    Pbkdf2 { iterations: u32 },
+    // scrypt (RFC 7914) with cost N = 2^log_n
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    // Argon2id version 1.3 (RFC 9106)
+    Argon2id { memory_kib: u32, iterations: u32, lanes: u32 },
+}
+
+impl KdfParams {
+
+    pub fn algorithm(&self) -> KdfAlgorithm {
+        match self {
+            KdfParams::Pbkdf2 { .. } => KdfAlgorithm::Pbkdf2,
+            KdfParams::Scrypt { .. } => KdfAlgorithm::Scrypt,
+            KdfParams::Argon2id { .. } => KdfAlgorithm::Argon2id,
+        }
+    }
+
+    // Function to check the parameters, also the ones read from a file, which
+    // must not make the derivation panic or allocate without limit
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            KdfParams::Pbkdf2 { iterations: 0 } =>
+                Err(String::from("PBKDF2 iteration count must not be zero")),
+            KdfParams::Scrypt { log_n, r, p } if log_n == 0 || log_n > MAX_SCRYPT_LOG_N || r == 0 || p == 0 || p > MAX_PARALLELISM
+                                                 || log_n as u64 >= 16 * r as u64
+                                                 || (r as u64) * (p as u64) >= 1 << 30
+                                                 || (r as u64 * p as u64) << (log_n + 7) > (MAX_MEMORY_KIB as u64) << 10 =>
+                Err(format!("scrypt parameters out of range (N = 2^{}, r = {}, p = {})", log_n, r, p)),
+            KdfParams::Argon2id { memory_kib, iterations, lanes } if iterations == 0 || lanes == 0 || lanes > MAX_PARALLELISM
+                                                                     || memory_kib < 8 * lanes || memory_kib > MAX_MEMORY_KIB =>
+                Err(format!("Argon2id parameters out of range ({} KiB, {} passes, {} lanes)", memory_kib, iterations, lanes)),
+            _ => Ok(()),
+        }
+    }
+
+    // Function to check the cost against the limits, before deriving a key
+    // with parameters that came from a file
+    pub fn check_limits(&self, limits: &KdfLimits) -> Result<(), CostLimitExceeded> {
+
+        let memory_kib = match *self {
+            KdfParams::Pbkdf2 { .. } => 0,
+            KdfParams::Scrypt { log_n, r, p } => (r as u64 * p as u64) << (log_n + 7) >> 10,
+            KdfParams::Argon2id { memory_kib, .. } => memory_kib as u64,
+        };
+        let within = memory_kib <= (limits.memory_mib as u64) << 10 && match *self {
+            KdfParams::Pbkdf2 { iterations } => iterations <= limits.pbkdf2_iterations,
+            KdfParams::Scrypt { .. } => true,
+            KdfParams::Argon2id { iterations, .. } => iterations <= limits.argon2id_passes,
+        };
+
+        if within { Ok(()) } else { Err(CostLimitExceeded(*self)) }
+    }
+
+    // Function to derive `output.len()` bytes from a passphrase and a salt
+    pub fn derive(&self, passphrase: &[u8], salt: &[u8], output: &mut [u8]) {
+        match *self {
+            KdfParams::Pbkdf2 { iterations } => pbkdf2_hmac_sha256(passphrase, salt, iterations, output),
+            KdfParams::Scrypt { log_n, r, p } => scrypt(passphrase, salt, log_n, r, p, output),
+            KdfParams::Argon2id { memory_kib, iterations, lanes } => argon2id(passphrase, salt, memory_kib, iterations, lanes, output),
+        }
+    }
+
+    // Function to serialize the KDF as its id byte followed by the parameters
+    // (big endian)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            KdfParams::Pbkdf2 { iterations } => {
+                let mut bytes = vec![PBKDF2_ID];
+                bytes.extend_from_slice(&iterations.to_be_bytes());
+                bytes
+            }
+            KdfParams::Scrypt { log_n, r, p } => {
+                let mut bytes = vec![SCRYPT_ID, log_n];
+                bytes.extend_from_slice(&r.to_be_bytes());
+                bytes.extend_from_slice(&p.to_be_bytes());
+                bytes
+            }
+            KdfParams::Argon2id { memory_kib, iterations, lanes } => {
+                let mut bytes = vec![ARGON2ID_ID];
+                bytes.extend_from_slice(&memory_kib.to_be_bytes());
+                bytes.extend_from_slice(&iterations.to_be_bytes());
+                bytes.extend_from_slice(&lanes.to_be_bytes());
+                bytes
+            }
+        }
+    }
+
+    // Function to read parameters serialized by `to_bytes`
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<KdfParams> {
+
+        let read_u32 = |reader: &mut R| -> io::Result<u32> {
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_be_bytes(bytes))
+        };
+
+        let mut id = [0u8; 1];
+        reader.read_exact(&mut id)?;
+        let params = match id[0] {
+            PBKDF2_ID => KdfParams::Pbkdf2 { iterations: read_u32(reader)? },
+            SCRYPT_ID => {
+                let mut log_n = [0u8; 1];
+                reader.read_exact(&mut log_n)?;
+                KdfParams::Scrypt { log_n: log_n[0], r: read_u32(reader)?, p: read_u32(reader)? }
+            }
+            ARGON2ID_ID => KdfParams::Argon2id { memory_kib: read_u32(reader)?, iterations: read_u32(reader)?, lanes: read_u32(reader)? },
+            id => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown key derivation function {}", id))),
+        };
+
+        params.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(params)
+    }
+
+    // Function to double the cost, None if that would leave the allowed range
+    // or exceed the limits
+    fn doubled(&self, limits: &KdfLimits) -> Option<KdfParams> {
+        let memory_limit_kib = limits.memory_mib.saturating_mul(1024).min(MAX_MEMORY_KIB);
+        let params = match *self {
+            KdfParams::Pbkdf2 { iterations } => KdfParams::Pbkdf2 { iterations: iterations.checked_mul(2)? },
+            KdfParams::Scrypt { log_n, r, p } => KdfParams::Scrypt { log_n: log_n + 1, r, p },
+            KdfParams::Argon2id { memory_kib, iterations, lanes } if memory_kib < memory_limit_kib =>
+                KdfParams::Argon2id { memory_kib: memory_kib * 2, iterations, lanes },
+            KdfParams::Argon2id { memory_kib, iterations, lanes } =>
+                KdfParams::Argon2id { memory_kib, iterations: iterations.checked_mul(2)?, lanes },
+        };
+        params.validate().ok()?;
+        params.check_limits(limits).ok().map(|_| params)
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KdfParams::Pbkdf2 { iterations } => write!(f, "PBKDF2-HMAC-SHA-256 ({} iterations)", iterations),
+            KdfParams::Scrypt { log_n, r, p } => write!(f, "scrypt (N = 2^{}, r = {}, p = {})", log_n, r, p),
+            KdfParams::Argon2id { memory_kib, iterations, lanes } =>
+                write!(f, "Argon2id ({} KiB, {} passes, {} lanes)", memory_kib, iterations, lanes),
+        }
+    }
+}
+
+// Function to measure one derivation with the given parameters
+fn measure(params: &KdfParams) -> Duration {
+    let mut output = [0u8; 32];
+    let start = Instant::now();
+    params.derive(b"calibration passphrase", &[0u8; SALT_LENGTH], &mut output);
+    start.elapsed()
+}
+
+// Function to pick cost parameters that take about `target` per derivation on
+// this machine, but never fewer than the lowest calibration step
+//
+// Starting from a cheap setting, the cost is doubled as long as the doubled
+// cost is expected to stay within the target. scrypt and Argon2id grow their
+// memory (Argon2id keeps 3 passes and 4 lanes, and only adds passes once the
+// memory limit is reached), PBKDF2 its iteration count. The result always
+// stays within `KdfLimits::DEFAULT`, so decrypting never needs --kdf-no-limits.
+pub fn calibrate(algorithm: KdfAlgorithm, target: Duration) -> KdfParams {
+
+    let mut params = match algorithm {
+        KdfAlgorithm::Pbkdf2 => KdfParams::Pbkdf2 { iterations: 10_000 },
+        KdfAlgorithm::Scrypt => KdfParams::Scrypt { log_n: 10, r: 8, p: 1 },
+        KdfAlgorithm::Argon2id => KdfParams::Argon2id { memory_kib: 8 * 1024, iterations: 3, lanes: 4 },
+    };
+
+    let mut elapsed = measure(&params);
+    while elapsed * 2 <= target {
+        match params.doubled(&KdfLimits::DEFAULT) {
+            Some(doubled) => params = doubled,
+            None => break,
+        }
+        elapsed = measure(&params);
+    }
+
+    params
+}
+
+// Function to fill `output` with PBKDF2-HMAC-SHA-256 of the password
+pub fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) {
+
+    assert!(iterations > 0, "PBKDF2 needs at least one iteration");
+
+    // the keyed HMAC state is computed once and cloned for every iteration
+    let prf = HmacSha256::new_from_slice(password).unwrap();
+
+    for (i, chunk) in output.chunks_mut(32).enumerate() {
+
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+        let mut u: [u8; 32] = mac.finalize().into_bytes().into();
+        let mut block = u;
+
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize().into_bytes().into();
+            for (b, x) in block.iter_mut().zip(u.iter()) {
+                *b ^= *x;
+            }
+        }
+
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        wipe_value(&mut u);
+        wipe_value(&mut block);
+    }
+}
+
+// Function to fill `output` with HKDF-SHA-256 (RFC 5869) of the input key
+// material, at most 255 * 32 bytes
+//
+// Unlike the password-based functions above, HKDF is only meant for keys that
+// are already uniformly random, like a master key. An empty salt is the same
+// as the all-zero salt of the RFC, since HMAC pads the key with zeros anyway.
+pub fn hkdf_sha256(salt: &[u8], input_key_material: &[u8], info: &[u8], output: &mut [u8]) {
+
+    assert!(output.len() <= 255 * 32, "HKDF output is limited to 255 blocks");
+
+    // extract
+    let mut pseudorandom_key = hmac_sha256(salt, &[input_key_material]);
+
+    // expand
+    let mut block = [0u8; 32];
+    for (i, chunk) in output.chunks_mut(32).enumerate() {
+        let previous: &[u8] = if i == 0 { &[] } else { &block };
+        block = hmac_sha256(&pseudorandom_key, &[previous, info, &[i as u8 + 1]]);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+
+    wipe_value(&mut block);
+    wipe_value(&mut pseudorandom_key);
+}
+
+#[test]
+fn test_pbkdf2_rfc7914_vectors() {
+
+    // RFC 7914, section 11
+    let mut output = [0u8; 64];
+    pbkdf2_hmac_sha256(b"passwd", b"salt", 1, &mut output);
+    assert_eq!(hex::encode(&output[..]), concat!("55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                                                 "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"));
+
+    pbkdf2_hmac_sha256(b"Password", b"NaCl", 80000, &mut output);
+    assert_eq!(hex::encode(&output[..]), concat!("4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56",
+                                                 "a1d425a1225833549adb841b51c9b3176a272bdebba1d078478f62b397f33c8d"));
+}
+
+#[test]
+fn test_hkdf_rfc5869_vectors() {
+
+    // RFC 5869, appendix A, test cases 1 and 3
+    let mut output = [0u8; 42];
+    hkdf_sha256(&hex::decode("000102030405060708090a0b0c").unwrap(), &[0x0b; 22],
+                &hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap(), &mut output);
+    assert_eq!(hex::encode(&output[..]), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865");
+
+    hkdf_sha256(b"", &[0x0b; 22], b"", &mut output);
+    assert_eq!(hex::encode(&output[..]), "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8");
+}
+
+#[test]
+fn test_kdf_params_serialization() {
+
+    for params in [KdfParams::Pbkdf2 { iterations: 80000 }, DEFAULT_SCRYPT, DEFAULT_ARGON2ID].iter() {
+        assert_eq!(KdfParams::read_from(&mut &params.to_bytes()[..]).unwrap(), *params);
+    }
+    assert!(KdfParams::read_from(&mut &[9u8, 0, 0, 0, 1][..]).is_err());
+
+    // out of range costs from a file are rejected before any memory is allocated
+    for params in [KdfParams::Pbkdf2 { iterations: 0 },
+                   KdfParams::Scrypt { log_n: 40, r: 8, p: 1 },
+                   KdfParams::Scrypt { log_n: 10, r: 1, p: 65 },
+                   KdfParams::Argon2id { memory_kib: u32::MAX, iterations: 1, lanes: 1 },
+                   KdfParams::Argon2id { memory_kib: 1024 * 1024, iterations: 1, lanes: 65 }].iter() {
+        assert!(KdfParams::read_from(&mut &params.to_bytes()[..]).is_err());
+    }
+
+    assert_eq!(KdfAlgorithm::Scrypt.params_with_costs(None, Some(64), Some(2)),
+               Ok(KdfParams::Scrypt { log_n: 16, r: 8, p: 2 }));
+    assert!(KdfAlgorithm::Scrypt.params_with_costs(None, Some(48), None).is_err());
+    assert!(KdfAlgorithm::Pbkdf2.params_with_costs(None, Some(64), None).is_err());
+}
+
+#[test]
+fn test_kdf_limits() {
+
+    let limits = KdfLimits { memory_mib: 128, pbkdf2_iterations: 1000, argon2id_passes: 3 };
+    for params in [KdfParams::Pbkdf2 { iterations: 1000 }, DEFAULT_SCRYPT, DEFAULT_ARGON2ID].iter() {
+        assert_eq!(params.check_limits(&limits), Ok(()));
+        assert_eq!(params.check_limits(&KdfLimits::DEFAULT), Ok(()));
+    }
+    for params in [KdfParams::Pbkdf2 { iterations: 1001 },
+                   KdfParams::Scrypt { log_n: 17, r: 8, p: 2 },
+                   KdfParams::Argon2id { memory_kib: 128 * 1024 + 1, iterations: 1, lanes: 1 },
+                   KdfParams::Argon2id { memory_kib: 1024, iterations: 4, lanes: 1 }].iter() {
+        assert_eq!(params.check_limits(&limits), Err(CostLimitExceeded(*params)));
+    }
+
+    // the largest parameters `validate` accepts
+    let expensive = KdfParams::Argon2id { memory_kib: MAX_MEMORY_KIB, iterations: u32::MAX, lanes: 1 };
+    assert!(expensive.check_limits(&KdfLimits::DEFAULT).is_err());
+    assert_eq!(expensive.check_limits(&KdfLimits::NONE), Ok(()));
+}
+
+#[test]
+fn test_calibration_meets_target() {
+
+    // a target below the first step keeps the cheapest setting
+    assert_eq!(calibrate(KdfAlgorithm::Argon2id, Duration::from_millis(0)),
+               KdfParams::Argon2id { memory_kib: 8 * 1024, iterations: 3, lanes: 4 });
+
+    // costs only grow by doubling
+    match calibrate(KdfAlgorithm::Pbkdf2, Duration::from_millis(50)) {
+        KdfParams::Pbkdf2 { iterations } => assert!(iterations >= 10_000 && (iterations / 10_000).is_power_of_two()),
+        params => panic!("unexpected {}", params),
+    }
+
+    // doubling stops at the default limits, Argon2id adds passes instead of memory
+    let limits = KdfLimits::DEFAULT;
+    assert_eq!(KdfParams::Argon2id { memory_kib: 1024 * 1024, iterations: 3, lanes: 4 }.doubled(&limits),
+               Some(KdfParams::Argon2id { memory_kib: 1024 * 1024, iterations: 6, lanes: 4 }));
+    assert_eq!(KdfParams::Argon2id { memory_kib: 1024 * 1024, iterations: 16, lanes: 4 }.doubled(&limits), None);
+    assert_eq!(KdfParams::Scrypt { log_n: 20, r: 8, p: 1 }.doubled(&limits), None);
+    assert_eq!(KdfParams::Pbkdf2 { iterations: 8_000_000 }.doubled(&limits), None);
+}
@@ -0,0 +1,33 @@
+// the key expansion tests are kept as they were written, index loops and casts included
+#![cfg_attr(test, allow(clippy::needless_range_loop, clippy::unnecessary_cast))]
+
+pub mod secret;
+pub mod aes_tables;
+pub mod aes_ctr_optimized;
+pub mod aes_xcbc;
+pub mod pmac;
+pub mod aes_ctr_hmac;
+pub mod aes_ctr_stream;
+pub mod aes_ctr_pipeline;
+pub mod aes_ctr_batch;
+pub mod aes_ctr_keystream;
+pub mod aes_ctr_passphrase;
+pub mod aes_ctr_derive;
+pub mod header_file;
+pub mod kdf;
+pub mod scrypt;
+pub mod argon2;
+pub mod iv_ledger;
+#[cfg(target_os = "linux")]
+pub mod aes_ctr_direct;
+pub mod aegis;
+pub mod aes_backend;
+pub mod aes_bitsliced;
+pub mod bench;
+#[cfg(target_arch = "x86_64")]
+mod aes_ni;
+#[cfg(target_arch = "x86_64")]
+mod aes_vpaes;
+mod worker_pool;
+#[cfg(test)]
+mod test_dir;
@@ -1,9 +1,20 @@
 use structopt::StructOpt;
 use hex::FromHex;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::process;
+use std::convert::TryInto;
 
-mod aes_ctr_optimized;
+use aes_ctr::aes_ctr_optimized::{self, CtrOptions, MAX_THREAD_COUNT};
+use aes_ctr::aes_ctr_hmac::{self, EtmKeys};
+use aes_ctr::aes_ctr_stream::{self, MAX_CHUNK_SIZE};
+use aes_ctr::aes_ctr_pipeline::{DEFAULT_BUFFER_COUNT, DEFAULT_BUFFER_SIZE};
+use aes_ctr::aes_backend::{self, Backend};
+use aes_ctr::bench;
+use aes_ctr::aes_ctr_passphrase::{self, DecryptionError, PassphraseHeader};
+use aes_ctr::aes_ctr_derive;
+use aes_ctr::kdf::{self, KdfAlgorithm, KdfLimits};
+use aes_ctr::iv_ledger::IvLedger;
+use aes_ctr::secret::{self, SecretBytes};
 
 /// Command line arguments struct
 #[derive(StructOpt)]
@@ -13,16 +24,144 @@ struct Cli {
                 help = concat!("Encryption or decryption command\n",
                                "(has to be 'encrypt' or 'decrypt')"))]
     command: String,
-    #[structopt(short = "k", long = "key", required = true,
+    #[structopt(short = "k", long = "key",
                 help = concat!("Key for AES-CTR as hex string\n",
-                               "(has to be of length 128-bit or 256-bit,\n",
-                               "i.e. exactly 32 or 64 hex characters)"))]
-    key: String,
-    #[structopt(short = "v", long = "initialization-vector", required = true,
-                help = concat!("Initialization Vector (IV) for AES-CTR as hex string\n",
-                               "(has to be of length 128-bit,\n",
-                               "i.e. exactly 32 hex characters)"))]
-    iv: String,
+                               "(has to be of length 128-bit, 192-bit or 256-bit,\n",
+                               "i.e. exactly 32, 48 or 64 hex characters;\n",
+                               "with --mac or --stream it may also be 384-bit\n",
+                               "or 512-bit, i.e. an AES key followed by a\n",
+                               "256-bit HMAC key, which are then used as given;\n",
+                               "required unless --passphrase is given)"))]
+    key: Option<String>,
+    #[structopt(short = "P", long = "passphrase",
+                help = concat!("Derive the key from a passphrase with the --kdf function\n",
+                               "(the passphrase is read from the AES_CTR_PASSPHRASE\n",
+                               "environment variable or prompted for; salt and\n",
+                               "KDF parameters are stored in front of the\n",
+                               "ciphertext, the IV is derived as well unless\n",
+                               "--initialization-vector is given)"))]
+    passphrase: bool,
+    #[structopt(long = "kdf",
+                help = concat!("Key derivation function for --passphrase encryption\n",
+                               "(has to be 'pbkdf2', 'scrypt' or 'argon2id',\n",
+                               "default 'pbkdf2')"))]
+    kdf: Option<String>,
+    #[structopt(long = "kdf-iterations",
+                help = concat!("PBKDF2 iteration count (default 600000) or number of\n",
+                               "Argon2id passes (default 3) for --passphrase encryption"))]
+    kdf_iterations: Option<u32>,
+    #[structopt(long = "kdf-memory",
+                help = concat!("Memory cost in MiB of scrypt (a power of two, default 128)\n",
+                               "or Argon2id (default 64) for --passphrase encryption"))]
+    kdf_memory: Option<u32>,
+    #[structopt(long = "kdf-parallelism",
+                help = concat!("Parallelism of scrypt (default 1) or number of Argon2id\n",
+                               "lanes (default 4) for --passphrase encryption"))]
+    kdf_parallelism: Option<u32>,
+    #[structopt(long = "kdf-time",
+                help = concat!("Calibrate the --kdf cost parameters to take about this\n",
+                               "many milliseconds on this machine (can not be combined\n",
+                               "with explicit cost parameters)"))]
+    kdf_time: Option<u64>,
+    #[structopt(long = "kdf-no-limits",
+                help = concat!("Decrypt --passphrase files even if their key derivation\n",
+                               "needs more than 1024 MiB of memory, 10000000 PBKDF2\n",
+                               "iterations or 16 Argon2id passes"))]
+    kdf_no_limits: bool,
+    #[structopt(short = "d", long = "derive",
+                help = concat!("Use --key as master key and encrypt with a per-file key\n",
+                               "and IV derived with HKDF-SHA-256 from a random salt\n",
+                               "(the salt is stored in front of the ciphertext)"))]
+    derive: bool,
+    #[structopt(long = "context",
+                help = concat!("Context string such as a file path that is mixed into\n",
+                               "the --derive key derivation (not stored, has to be\n",
+                               "given again when decrypting)"))]
+    context: Option<String>,
+    #[structopt(long = "key-size",
+                help = "AES key size in bits for --passphrase encryption (128, 192 or 256, default 256)")]
+    key_size: Option<u16>,
+    #[structopt(short = "v", long = "initialization-vector",
+                help = concat!("Expert option: explicit Initialization Vector (IV) for\n",
+                               "AES-CTR as hex string (has to be of length 128-bit,\n",
+                               "i.e. exactly 32 hex characters; requires\n",
+                               "--allow-explicit-iv). By default a random\n",
+                               "IV is generated when encrypting and stored in front\n",
+                               "of the ciphertext, where decryption reads it from;\n",
+                               "an explicit IV is not stored in plain AES-CTR files\n",
+                               "and has to be given again for decryption. Never use\n",
+                               "an IV twice with the same key. Not used when\n",
+                               "decrypting with --mac or --passphrase, and not used\n",
+                               "with --stream or --derive)"))]
+    iv: Option<String>,
+    #[structopt(long = "allow-explicit-iv",
+                help = "Allow the expert option --initialization-vector")]
+    allow_explicit_iv: bool,
+    #[structopt(short = "m", long = "mac",
+                help = concat!("Encrypt-then-MAC with HMAC-SHA-256\n",
+                               "(output is IV || ciphertext || tag, the tag is\n",
+                               "verified before any plaintext is written; a\n",
+                               "128-bit, 192-bit or 256-bit key is a master key\n",
+                               "the AES and HMAC keys are derived from, longer\n",
+                               "keys are split into both, see --key)"))]
+    mac: bool,
+    #[structopt(short = "s", long = "stream",
+                help = concat!("Chunked online AEAD (STREAM construction)\n",
+                               "(every chunk is authenticated on its own, so\n",
+                               "decryption runs in constant memory; keys are\n",
+                               "used as with --mac)"))]
+    stream: bool,
+    #[structopt(long = "chunk-size", default_value = "65536",
+                help = "Chunk size in bytes for --stream encryption (at most 16777216)")]
+    chunk_size: u32,
+    #[structopt(short = "t", long = "threads", default_value = "1",
+                help = concat!("Number of threads for plain AES-CTR, at most 64\n",
+                               "(the output is identical for every thread count)"))]
+    threads: usize,
+    #[structopt(long = "mmap",
+                help = concat!("Memory-map input and output file for plain AES-CTR\n",
+                               "(pipes and special files are streamed as usual)"))]
+    mmap: bool,
+    #[structopt(long = "direct",
+                help = concat!("Bypass the page cache with O_DIRECT for plain AES-CTR\n",
+                               "(Linux only, falls back to buffered I/O if the\n",
+                               "filesystem does not support it; a stored IV is\n",
+                               "padded to 4096 bytes to keep the data aligned,\n",
+                               "files encrypted without --direct are decrypted\n",
+                               "with buffered I/O)"))]
+    direct: bool,
+    #[structopt(short = "p", long = "pipeline",
+                help = concat!("Read, encrypt and write in separate threads for\n",
+                               "plain AES-CTR (uses at most buffer count times\n",
+                               "buffer size bytes of buffer memory)"))]
+    pipeline: bool,
+    #[structopt(long = "buffer-count",
+                help = "Number of buffers for --pipeline (default 4)")]
+    buffer_count: Option<usize>,
+    #[structopt(long = "buffer-size",
+                help = concat!("Size of every --pipeline buffer in bytes\n",
+                               "(has to be a multiple of 16, default 1048576)"))]
+    buffer_size: Option<usize>,
+    #[structopt(short = "b", long = "backend",
+                help = concat!("AES implementation to use instead of the detected one\n",
+                               "(has to be 'aes-ni', 'vpaes', 'bitsliced' or\n",
+                               "'t-table'; by default AES-NI is used if the CPU\n",
+                               "supports it, otherwise one of the constant-time\n",
+                               "implementations 'vpaes' or 'bitsliced')"))]
+    backend: Option<String>,
+    #[structopt(long = "lock-memory",
+                help = concat!("Lock the buffer of the given key or passphrase into\n",
+                               "RAM with mlock, so it is never written to swap\n",
+                               "(Linux only; round keys and keystream buffers are\n",
+                               "wiped after use, but not locked)"))]
+    lock_memory: bool,
+    #[structopt(parse(from_os_str), long = "ledger",
+                help = concat!("IV ledger file for plain AES-CTR or --mac encryption\n",
+                               "(records a fingerprint of the key with every\n",
+                               "counter range used and refuses to encrypt with\n",
+                               "a range used before; the input has to be a\n",
+                               "regular file)"))]
+    ledger: Option<std::path::PathBuf>,
     #[structopt(parse(from_os_str), short = "i", long = "input-file", required = true,
                 help = "Path to input file")]
     input_file_path: std::path::PathBuf,
@@ -31,17 +170,137 @@ struct Cli {
     output_file_path: std::path::PathBuf,
 }
 
+/// Command line arguments of the `bench` subcommand
+#[derive(StructOpt)]
+#[structopt(name = "aes-ctr bench", about = "In-memory throughput of every available AES backend.")]
+struct BenchCli {
+    #[structopt(long = "buffer-sizes", use_delimiter = true,
+                help = concat!("Comma separated buffer sizes in bytes\n",
+                               "(default 4096,65536,1048576)"))]
+    buffer_sizes: Vec<usize>,
+    #[structopt(long = "total-size",
+                help = "Bytes to process per measurement (default 67108864)")]
+    total_size: Option<usize>,
+    #[structopt(long = "json", help = "Print the results as JSON instead of a table")]
+    json: bool,
+}
+
+// Function to run the `bench` subcommand with the arguments following it
+fn run_bench(args: BenchCli) {
+
+    let buffer_sizes = if args.buffer_sizes.is_empty() {
+        bench::DEFAULT_BUFFER_SIZES.to_vec()
+    } else {
+        args.buffer_sizes
+    };
+    if buffer_sizes.contains(&0) {
+        eprintln!("!!! ERROR: Buffer sizes must not be zero!");
+        process::exit(1);
+    }
+
+    let results = bench::run_benchmarks(&buffer_sizes, args.total_size.unwrap_or(bench::DEFAULT_TOTAL_BYTES));
+    if args.json {
+        print!("{}", bench::format_json(&results));
+    } else {
+        print!("{}", bench::format_table(&results));
+    }
+}
+
+// Function to read the passphrase from the AES_CTR_PASSPHRASE environment
+// variable or, without echo, from the terminal (twice when encrypting)
+fn read_passphrase(confirm: bool, lock_memory: bool) -> SecretBytes {
+
+    let mut passphrase = match std::env::var_os("AES_CTR_PASSPHRASE") {
+        Some(value) => SecretBytes::from_vec(value.into_encoded_bytes()),
+        None => {
+            let passphrase = prompt_passphrase("Passphrase: ");
+            if confirm && prompt_passphrase("Repeat passphrase: ").as_slice() != passphrase.as_slice() {
+                eprintln!("!!! ERROR: Passphrases do not match!");
+                process::exit(1);
+            }
+            passphrase
+        }
+    };
+
+    if passphrase.is_empty() {
+        eprintln!("!!! ERROR: Passphrase must not be empty!");
+        process::exit(1);
+    }
+    if lock_memory {
+        if let Err(e) = passphrase.lock_memory() {
+            eprintln!("!!! ERROR: Locking the passphrase into memory failed: {}", e);
+            process::exit(1);
+        }
+    }
+    passphrase
+}
+
+// Function to read one line from standard input with the terminal echo turned off
+fn prompt_passphrase(prompt: &str) -> SecretBytes {
+
+    eprint!("{}", prompt);
+    let echo = set_echo(false);
+
+    // large enough that typing never reallocates and leaves copies behind
+    let mut line = String::with_capacity(4096);
+    let read_result = std::io::stdin().read_line(&mut line);
+
+    if echo {
+        set_echo(true);
+        eprintln!();
+    }
+    if let Err(e) = read_result {
+        eprintln!("!!! ERROR: Reading the passphrase failed: {}", e);
+        process::exit(1);
+    }
+
+    let passphrase = SecretBytes::from_slice(line.trim_end_matches(['\n', '\r']).as_bytes());
+    secret::wipe(unsafe { line.as_bytes_mut() });
+    passphrase
+}
+
+// Function to switch the echo of the terminal on standard input on or off,
+// returns whether standard input is a terminal at all
+#[cfg(target_os = "linux")]
+fn set_echo(enabled: bool) -> bool {
+
+    unsafe {
+        let mut attributes: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut attributes) != 0 {
+            return false;
+        }
+        if enabled {
+            attributes.c_lflag |= libc::ECHO;
+        } else {
+            attributes.c_lflag &= !libc::ECHO;
+        }
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &attributes) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_echo(_enabled: bool) -> bool {
+    false
+}
+
 /// Main function
 fn main() {
 
+    // `aes-ctr bench ...` has its own arguments
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        let program = std::env::args().take(1);
+        run_bench(BenchCli::from_iter(program.chain(std::env::args().skip(2))));
+        return;
+    }
+
     // Initialize some variables
     let mut errors = 0;
     let mut key_size: u16 = 0;
-    let mut key_bytes: Vec<u8> = Vec::new();
+    let mut key_bytes = SecretBytes::zeroed(0);
     let mut iv_bytes: Vec<u8> = Vec::new();
 
     // Get command line arguments
-    let args = Cli::from_args();
+    let mut args = Cli::from_args();
 
     // User message: checking and parsing command line arguments
     println!("\n### Checking and parsing command line arguments ...");
@@ -54,54 +313,286 @@ fn main() {
     }
 
     // Check and parse key
-    if args.key.len() == 32 {
-        key_size = 128;
-        match Vec::from_hex(args.key) {
-            Ok(bytes) => key_bytes = bytes,
-            Err(e) => {
-                eprintln!("!!! ERROR: Key hex string parsing failed: {}", e);
-                errors += 1;
-            }
-        };
+    if let Some(key) = &args.key {
+        if args.passphrase {
+            eprintln!("!!! ERROR: --key and --passphrase can not be combined!");
+            errors += 1;
+        }
+        // an AES key, or with --mac and --stream also an AES key followed by an HMAC key
+        if matches!(key.len(), 32 | 48 | 64)
+            || (args.mac || args.stream) && (key.len() == 96 || key.len() == 128) {
+            key_size = (key.len() * 4) as u16;
+            match SecretBytes::from_hex(key) {
+                Ok(bytes) => key_bytes = bytes,
+                Err(e) => {
+                    eprintln!("!!! ERROR: Key hex string parsing failed: {}", e);
+                    errors += 1;
+                }
+            };
+        }
+        else {
+            eprintln!(concat!("!!! ERROR: Key hex string matches neither 128-bit, 192-bit nor 256-bit size!\n",
+                              "!!!        (is {} characters long, but should be 32, 48 or 64)"), key.len());
+            errors += 1;
+        }
+    }
+    else if args.passphrase {
+        key_size = args.key_size.unwrap_or(256);
+        if !key_size.is_multiple_of(8) || !aes_backend::is_aes_key_length(key_size as usize / 8) {
+            eprintln!(concat!("!!! ERROR: Key size not correct!\n",
+                              "!!!        (is {}, but should be 128, 192 or 256)"), key_size);
+            errors += 1;
+        }
+    }
+    else {
+        eprintln!("!!! ERROR: Key is missing! (use --key or --passphrase)");
+        errors += 1;
+    }
+
+    // the hex string is not needed anymore, zeros keep it valid UTF-8
+    if let Some(key) = args.key.as_mut() {
+        secret::wipe(unsafe { key.as_bytes_mut() });
+    }
+    if args.lock_memory {
+        if let Err(e) = key_bytes.lock_memory() {
+            eprintln!("!!! ERROR: Locking the key into memory failed: {}", e);
+            errors += 1;
+        }
+    }
+
+    // Check mode
+    if args.mac && args.stream {
+        eprintln!("!!! ERROR: --mac and --stream can not be combined!");
+        errors += 1;
+    }
+    if args.stream && (args.chunk_size == 0 || args.chunk_size > MAX_CHUNK_SIZE) {
+        eprintln!(concat!("!!! ERROR: Chunk size not correct!\n",
+                          "!!!        (is {}, but should be between 1 and {})"), args.chunk_size, MAX_CHUNK_SIZE);
+        errors += 1;
+    }
+    if args.threads == 0 || args.threads > MAX_THREAD_COUNT {
+        eprintln!(concat!("!!! ERROR: Number of threads not correct!\n",
+                          "!!!        (is {}, but should be between 1 and {})"), args.threads, MAX_THREAD_COUNT);
+        errors += 1;
+    }
+    if (args.threads > 1 || args.mmap || args.pipeline || args.direct) && (args.mac || args.stream) {
+        eprintln!("!!! ERROR: --threads, --mmap, --pipeline and --direct can not be combined with --mac or --stream!");
+        errors += 1;
+    }
+    if args.passphrase && (args.mac || args.stream || args.mmap || args.pipeline || args.direct) {
+        eprintln!("!!! ERROR: --passphrase can not be combined with --mac, --stream, --mmap, --pipeline or --direct!");
+        errors += 1;
+    }
+    if args.derive && (args.passphrase || args.mac || args.stream || args.mmap || args.pipeline || args.direct) {
+        eprintln!("!!! ERROR: --derive can not be combined with --passphrase, --mac, --stream, --mmap, --pipeline or --direct!");
+        errors += 1;
+    }
+    if args.ledger.is_some() && (args.command != "encrypt" || args.stream || args.passphrase || args.derive) {
+        eprintln!("!!! ERROR: --ledger is only used for plain AES-CTR or --mac encryption!");
+        errors += 1;
+    }
+    if args.context.is_some() && !args.derive {
+        eprintln!("!!! ERROR: --context requires --derive!");
+        errors += 1;
+    }
+    let kdf_costs_given = args.kdf_iterations.is_some() || args.kdf_memory.is_some() || args.kdf_parallelism.is_some();
+    if (args.kdf.is_some() || kdf_costs_given || args.kdf_time.is_some() || args.key_size.is_some())
+       && !(args.passphrase && args.command == "encrypt") {
+        eprintln!("!!! ERROR: --kdf, --kdf-* and --key-size require --passphrase and are only used when encrypting!");
+        errors += 1;
+    }
+    if args.kdf_no_limits && !(args.passphrase && args.command == "decrypt") {
+        eprintln!("!!! ERROR: --kdf-no-limits requires --passphrase and is only used when decrypting!");
+        errors += 1;
+    }
+    if kdf_costs_given && args.kdf_time.is_some() {
+        eprintln!("!!! ERROR: --kdf-time can not be combined with --kdf-iterations, --kdf-memory or --kdf-parallelism!");
+        errors += 1;
     }
-    else if args.key.len() == 64 {
-        key_size = 256;
-        match Vec::from_hex(args.key) {
-            Ok(bytes) => key_bytes = bytes,
+    if args.kdf_time == Some(0) {
+        eprintln!("!!! ERROR: KDF calibration time must not be zero!");
+        errors += 1;
+    }
+
+    // Check key derivation function, calibrated only once everything else is checked
+    let kdf_algorithm = match args.kdf.as_deref().map(KdfAlgorithm::from_name) {
+        None => Some(KdfAlgorithm::Pbkdf2),
+        Some(Some(algorithm)) => Some(algorithm),
+        Some(None) => {
+            eprintln!(concat!("!!! ERROR: Key derivation function not correct!\n",
+                              "!!!        (is '{}', but should be 'pbkdf2', 'scrypt' or 'argon2id')"), args.kdf.as_ref().unwrap());
+            errors += 1;
+            None
+        }
+    };
+    let kdf_params = kdf_algorithm.and_then(|algorithm| {
+        match algorithm.params_with_costs(args.kdf_iterations, args.kdf_memory, args.kdf_parallelism) {
+            Ok(params) => Some(params),
             Err(e) => {
-                eprintln!("!!! ERROR: Key hex string parsing failed: {}", e);
+                eprintln!("!!! ERROR: KDF parameters not correct: {}!", e);
                 errors += 1;
+                None
             }
-        };
+        }
+    });
+    if args.direct && (args.mmap || args.pipeline) {
+        eprintln!("!!! ERROR: --direct can not be combined with --mmap or --pipeline!");
+        errors += 1;
     }
-    else {
-        eprintln!(concat!("!!! ERROR: Key hex string neither matches 128-bit nor 256-bit size!\n",
-                          "!!!        (is {} characters long, but should be 32 or 64)"), args.key.len());
+    if (args.buffer_count.is_some() || args.buffer_size.is_some()) && !args.pipeline {
+        eprintln!("!!! ERROR: --buffer-count and --buffer-size require --pipeline!");
+        errors += 1;
+    }
+    let buffer_count = args.buffer_count.unwrap_or(DEFAULT_BUFFER_COUNT);
+    let buffer_size = args.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    if buffer_count == 0 {
+        eprintln!("!!! ERROR: Buffer count must not be zero!");
+        errors += 1;
+    }
+    if buffer_size == 0 || !buffer_size.is_multiple_of(16) {
+        eprintln!(concat!("!!! ERROR: Buffer size not correct!\n",
+                          "!!!        (is {}, but should be a non-zero multiple of 16)"), buffer_size);
         errors += 1;
     }
 
-    // Check and parse initializtion vector
-    if args.iv.len() == 32 {
-        match Vec::from_hex(args.iv) {
-            Ok(bytes) => iv_bytes = bytes,
-            Err(e) => {
-                eprintln!(" ! ERROR: IV hex string parsing failed: {}", e);
+    // Check backend
+    if let Some(name) = &args.backend {
+        match Backend::from_name(name) {
+            Some(backend) if backend.is_available() => aes_backend::force_backend(backend),
+            Some(_) => {
+                eprintln!("!!! ERROR: Backend '{}' is not available on this CPU!", name);
                 errors += 1;
             }
-        };
+            None => {
+                eprintln!(concat!("!!! ERROR: Backend not correct!\n",
+                                  "!!!        (is '{}', but should be 'aes-ni', 'vpaes', 'bitsliced' or 't-table')"), name);
+                errors += 1;
+            }
+        }
     }
-    else {
-        eprintln!(concat!("!!! ERROR: IV hex string does not match 128-bit size!\n",
-                          "!!!        (is {} characters long, but should be 32)"), args.iv.len());
+
+    // Check and parse initializtion vector
+    let iv_unused = args.stream || args.derive || ((args.mac || args.passphrase) && args.command == "decrypt");
+    if args.iv.is_some() != args.allow_explicit_iv {
+        eprintln!("!!! ERROR: --initialization-vector is an expert option and has to be given together with --allow-explicit-iv!");
         errors += 1;
     }
+    match args.iv {
+        Some(_) if iv_unused => {
+            eprintln!("!!! ERROR: IV must not be given with --stream or --derive, or when decrypting with --mac or --passphrase!");
+            errors += 1;
+        }
+        None => {}
+        Some(iv) if iv.len() == 32 => {
+            if args.command == "encrypt" && !args.passphrase {
+                eprintln!("!!! WARNING: Explicit IV given, it must never be used again with this key!");
+            }
+            match Vec::from_hex(iv) {
+                Ok(bytes) => iv_bytes = bytes,
+                Err(e) => {
+                    eprintln!(" ! ERROR: IV hex string parsing failed: {}", e);
+                    errors += 1;
+                }
+            };
+        }
+        Some(iv) => {
+            eprintln!(concat!("!!! ERROR: IV hex string does not match 128-bit size!\n",
+                              "!!!        (is {} characters long, but should be 32)"), iv.len());
+            errors += 1;
+        }
+    }
 
     // Handle encryption/decryption command with given parameters
     if errors == 0 {
+        let kdf_params = match args.kdf_time {
+            Some(milliseconds) => {
+                let params = kdf::calibrate(kdf_algorithm.unwrap(), Duration::from_millis(milliseconds));
+                println!("\n### Calibrated key derivation: {}", params);
+                Some(params)
+            }
+            None => kdf_params,
+        };
+        let passphrase = if args.passphrase { Some(read_passphrase(args.command == "encrypt", args.lock_memory)) } else { None };
+        let kdf_limits = if args.kdf_no_limits { KdfLimits::NONE } else { KdfLimits::DEFAULT };
         println!("\n### Performing {}ion ...", args.command);
         let now = Instant::now();
-        aes_ctr_optimized::handle_aes_ctr_command(args.command, key_size, key_bytes, iv_bytes,
-                                                  args.input_file_path, args.output_file_path);
+        // a plain AES key is used as master key, longer key material is split
+        let etm_keys = || if key_size <= 256 { EtmKeys::derive(key_bytes.as_slice()) } else { EtmKeys::split(key_bytes.as_slice()) };
+        if let Some(passphrase) = passphrase {
+            if args.command == "encrypt" {
+                let iv = if iv_bytes.is_empty() { None } else { Some(iv_bytes.as_slice().try_into().unwrap()) };
+                let kdf = kdf_params.unwrap();
+                if kdf.check_limits(&KdfLimits::DEFAULT).is_err() {
+                    eprintln!("!!! WARNING: {} exceeds the default limits, decrypting will need --kdf-no-limits!", kdf);
+                }
+                let header = PassphraseHeader::new(kdf, key_size as usize / 8, iv);
+                if let Err(e) = aes_ctr_passphrase::encrypt_file(passphrase.as_slice(), &header, &args.input_file_path,
+                                                                 &args.output_file_path, args.threads) {
+                    eprintln!("!!! ERROR: Encryption failed: {}", e);
+                    process::exit(1);
+                }
+            } else if let Err(e) = aes_ctr_passphrase::decrypt_file(passphrase.as_slice(), &kdf_limits, &args.input_file_path,
+                                                                    &args.output_file_path, args.threads) {
+                eprintln!("!!! ERROR: Decryption failed: {}", e);
+                if let DecryptionError::CostLimitExceeded(_) = e {
+                    eprintln!("!!! Use --kdf-no-limits if the file is trusted!");
+                }
+                process::exit(1);
+            }
+        } else if args.derive {
+            let context = args.context.as_deref().unwrap_or("").as_bytes();
+            if args.command == "encrypt" {
+                if let Err(e) = aes_ctr_derive::encrypt_file(key_bytes.as_slice(), context, &args.input_file_path,
+                                                             &args.output_file_path, args.threads) {
+                    eprintln!("!!! ERROR: Encryption failed: {}", e);
+                    process::exit(1);
+                }
+            } else if let Err(e) = aes_ctr_derive::decrypt_file(key_bytes.as_slice(), context, &args.input_file_path,
+                                                                &args.output_file_path, args.threads) {
+                eprintln!("!!! ERROR: Decryption failed: {}", e);
+                process::exit(1);
+            }
+        } else if args.stream {
+            if args.command == "encrypt" {
+                if let Err(e) = aes_ctr_stream::encrypt_file(etm_keys(), args.chunk_size, &args.input_file_path, &args.output_file_path) {
+                    eprintln!("!!! ERROR: Encryption failed: {}", e);
+                    process::exit(1);
+                }
+            } else if let Err(e) = aes_ctr_stream::decrypt_file(etm_keys(), &args.input_file_path, &args.output_file_path) {
+                eprintln!("!!! ERROR: Decryption failed: {}", e);
+                process::exit(1);
+            }
+        } else if args.mac {
+            let keys = etm_keys();
+            if args.command == "encrypt" {
+                let iv = if iv_bytes.is_empty() { aes_ctr_optimized::random_iv() } else { iv_bytes.as_slice().try_into().unwrap() };
+                let ledger = args.ledger.as_deref().map(IvLedger::new);
+                if let Err(e) = aes_ctr_hmac::encrypt_file(&keys, &iv, &args.input_file_path, &args.output_file_path, ledger.as_ref()) {
+                    eprintln!("!!! ERROR: Encryption failed: {}", e);
+                    process::exit(1);
+                }
+            } else if let Err(e) = aes_ctr_hmac::decrypt_file(&keys, &args.input_file_path, &args.output_file_path) {
+                eprintln!("!!! ERROR: Decryption failed: {}", e);
+                process::exit(1);
+            }
+        } else {
+            let ledger = args.ledger.as_deref().map(IvLedger::new);
+            let options = CtrOptions {
+                thread_count: args.threads,
+                mmap: args.mmap,
+                pipeline: args.pipeline,
+                direct: args.direct,
+                buffer_count,
+                buffer_size,
+                ledger: ledger.as_ref(),
+            };
+            let action = if args.command == "encrypt" { "Encryption" } else { "Decryption" };
+            if let Err(e) = aes_ctr_optimized::handle_aes_ctr_command(args.command, key_size, key_bytes, iv_bytes,
+                                                                      args.input_file_path, args.output_file_path, options) {
+                eprintln!("!!! ERROR: {} failed: {}", action, e);
+                process::exit(1);
+            }
+        }
         println!("\n### Finished! It took {:.10} seconds!", now.elapsed().as_secs_f32());
     }
     else {
@@ -0,0 +1,205 @@
+use std::convert::TryInto;
+use std::thread;
+
+use crate::aes_ctr_optimized::{encrypt_aes, key_expansion};
+use crate::secret::{wipe, wipe_value};
+
+// PMAC1 (Rogaway, "Efficient Instantiations of Tweakable Blockciphers and
+// Refinements to Modes OCB and PMAC", 2004)
+//
+// Every block except the last one is masked with its own Gray-code offset
+// and encrypted independently, so any range of blocks can be processed on
+// its own and the partial sums are combined with a plain xor.
+
+// doubling and halving in GF(2^128) with the polynomial x^128 + x^7 + x^2 + x + 1
+fn gf_double(value: u128) -> u128 {
+    (value << 1) ^ if value >> 127 == 1 { 0x87 } else { 0 }
+}
+
+fn gf_halve(value: u128) -> u128 {
+    (value >> 1) ^ if value & 1 == 1 { 0x80000000000000000000000000000043 } else { 0 }
+}
+
+/// Keyed PMAC1 instance that can be shared between threads
+pub struct Pmac {
+    expanded_keys: Vec<u32>,
+    // L(i) = L * x^i for every bit position of a 64-bit block index
+    l_table: [u128; 64],
+    // L(-1) = L * x^-1, used for a complete final block
+    l_inverse: u128,
+}
+
+/// Partial PMAC sum over a range of blocks
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PmacSum(u128);
+
+impl PmacSum {
+
+    // Function to combine the sums of two disjoint block ranges
+    pub fn combine(self, other: PmacSum) -> PmacSum {
+        PmacSum(self.0 ^ other.0)
+    }
+}
+
+impl Drop for Pmac {
+
+    fn drop(&mut self) {
+        wipe(&mut self.expanded_keys);
+        wipe_value(&mut self.l_table);
+        wipe_value(&mut self.l_inverse);
+    }
+}
+
+impl Pmac {
+
+    // Function to set up PMAC1 for a 128-bit or 256-bit key
+    pub fn new(key: &[u8]) -> Pmac {
+
+        assert!(key.len() == 16 || key.len() == 32, "PMAC key has to be 128-bit or 256-bit");
+        let key_count = if key.len() == 16 { 11 } else { 15 };
+        let expanded_keys = key_expansion(key.to_vec(), key_count);
+
+        let l = encrypt_aes(0, &expanded_keys);
+        let mut l_table = [0u128; 64];
+        l_table[0] = l;
+        for i in 1..64 {
+            l_table[i] = gf_double(l_table[i - 1]);
+        }
+
+        Pmac { expanded_keys, l_table, l_inverse: gf_halve(l) }
+    }
+
+    // Function to compute the offset of a (one-based) block index directly
+    // from its Gray code, so a worker can start anywhere in the message
+    fn offset(&self, block_index: u64) -> u128 {
+
+        let gray_code = block_index ^ (block_index >> 1);
+        let mut offset = 0u128;
+        for (bit, l) in self.l_table.iter().enumerate() {
+            if gray_code >> bit & 1 == 1 {
+                offset ^= l;
+            }
+        }
+        offset
+    }
+
+    // Function to process a range of complete blocks that starts at the given
+    // (zero-based) block index of the message; the final message block must
+    // not be part of any range but is passed to `finalize` instead
+    pub fn process_blocks(&self, first_block: u64, blocks: &[u8]) -> PmacSum {
+
+        assert!(blocks.len().is_multiple_of(16), "PMAC block ranges have to consist of complete blocks");
+
+        // offsets follow the Gray-code sequence: offset(i) = offset(i - 1) ^ L(ntz(i))
+        let mut block_index = first_block + 1;
+        let mut offset = self.offset(block_index);
+        let mut sum = 0u128;
+
+        for chunk in blocks.chunks_exact(16) {
+            sum ^= encrypt_aes(u128::from_be_bytes(chunk.try_into().unwrap()) ^ offset, &self.expanded_keys);
+            block_index += 1;
+            offset ^= self.l_table[block_index.trailing_zeros() as usize];
+        }
+
+        PmacSum(sum)
+    }
+
+    // Function to compute the tag from the combined sum of all complete
+    // blocks and the final (possibly partial or empty) message block
+    pub fn finalize(&self, sum: PmacSum, last_block: &[u8]) -> [u8; 16] {
+
+        assert!(last_block.len() <= 16, "final PMAC block is longer than 16 bytes");
+
+        let mut padded = [0u8; 16];
+        padded[..last_block.len()].copy_from_slice(last_block);
+        let mut sigma = sum.0;
+
+        if last_block.len() == 16 {
+            sigma ^= u128::from_be_bytes(padded) ^ self.l_inverse;
+        } else {
+            padded[last_block.len()] = 0x80;
+            sigma ^= u128::from_be_bytes(padded);
+        }
+
+        encrypt_aes(sigma, &self.expanded_keys).to_be_bytes()
+    }
+
+    // Function to compute the tag of a complete message in one go
+    pub fn mac(&self, message: &[u8]) -> [u8; 16] {
+
+        let (blocks, last_block) = split_message(message);
+        self.finalize(self.process_blocks(0, blocks), last_block)
+    }
+
+    // Function to compute the tag of a message with several threads, each
+    // processing an equally sized range of blocks
+    pub fn mac_parallel(&self, message: &[u8], thread_count: usize) -> [u8; 16] {
+
+        let (blocks, last_block) = split_message(message);
+        let block_count = blocks.len() / 16;
+        let blocks_per_thread = block_count.div_ceil(thread_count.max(1));
+
+        if blocks_per_thread == 0 {
+            return self.finalize(PmacSum::default(), last_block);
+        }
+
+        let sum = thread::scope(|scope| {
+            let workers: Vec<_> = blocks
+                .chunks(blocks_per_thread * 16)
+                .enumerate()
+                .map(|(i, range)| scope.spawn(move || self.process_blocks((i * blocks_per_thread) as u64, range)))
+                .collect();
+
+            workers.into_iter()
+                   .map(|worker| worker.join().unwrap())
+                   .fold(PmacSum::default(), PmacSum::combine)
+        });
+
+        self.finalize(sum, last_block)
+    }
+}
+
+// Function to split a message into its complete blocks and the final block
+// (which is always non-empty unless the whole message is empty)
+pub fn split_message(message: &[u8]) -> (&[u8], &[u8]) {
+    let full_blocks = if message.is_empty() { 0 } else { (message.len() - 1) / 16 };
+    message.split_at(full_blocks * 16)
+}
+
+#[test]
+fn test_pmac_aes_128_vectors() {
+
+    // PMAC1-AES-128 test vectors from Rogaway's reference implementation
+    let key: Vec<u8> = (0..16).collect();
+    let pmac = Pmac::new(&key);
+    let vectors = [
+        (0, "4399572cd6ea5341b8d35876a7098af7"),
+        (3, "256ba5193c1b991b4df0c51f388a9e27"),
+        (16, "ebbd822fa458daf6dfdad7c27da76338"),
+        (20, "0412ca150bbf79058d8c75a58c993f55"),
+        (32, "e97ac04e9e5e3399ce5355cd7407bc75"),
+        (34, "5cba7d5eb24f7c86ccc54604e53d5512"),
+    ];
+
+    for (length, expected) in vectors.iter() {
+        let message: Vec<u8> = (0..*length).map(|i| i as u8).collect();
+        assert_eq!(hex::encode(pmac.mac(&message)), *expected);
+    }
+
+    assert_eq!(hex::encode(pmac.mac(&[0u8; 1000])), "c2c9fa1d9985f6f0d2aff915a0e8d910");
+}
+
+#[test]
+fn test_pmac_parallel_matches_sequential() {
+
+    let key: Vec<u8> = (0..32).collect();
+    let pmac = Pmac::new(&key);
+
+    for length in [0, 15, 16, 17, 4096, 100_003].iter() {
+        let message: Vec<u8> = (0..*length).map(|i| (i * 7) as u8).collect();
+        let expected = pmac.mac(&message);
+        for thread_count in 1..6 {
+            assert_eq!(pmac.mac_parallel(&message, thread_count), expected);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::convert::TryInto;
+
+use crate::kdf::pbkdf2_hmac_sha256;
+use crate::secret::wipe;
+use crate::worker_pool::{available_threads, Task, WorkerPool};
+
+// scrypt (RFC 7914)
+//
+// The p independent ROMix instances run on at most as many threads as the
+// machine runs at once, each running instance needs 128 * r * N bytes of
+// memory.
+
+// Function to compute the Salsa20/8 core in place
+fn salsa20_8(block: &mut [u32; 16]) {
+
+    fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    }
+
+    let mut x = *block;
+    for _ in 0..4 {
+        // columns
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 5, 9, 13, 1);
+        quarter_round(&mut x, 10, 14, 2, 6);
+        quarter_round(&mut x, 15, 3, 7, 11);
+        // rows
+        quarter_round(&mut x, 0, 1, 2, 3);
+        quarter_round(&mut x, 5, 6, 7, 4);
+        quarter_round(&mut x, 10, 11, 8, 9);
+        quarter_round(&mut x, 15, 12, 13, 14);
+    }
+
+    for (b, x) in block.iter_mut().zip(x.iter()) {
+        *b = b.wrapping_add(*x);
+    }
+}
+
+// Function to run scryptBlockMix on 2 * r 64-byte blocks (as words)
+fn block_mix(input: &[u32], output: &mut [u32]) {
+
+    let block_count = input.len() / 16;
+    let r = block_count / 2;
+    let mut x: [u32; 16] = input[input.len() - 16..].try_into().unwrap();
+
+    // even blocks go to the first half of the output, odd ones to the second
+    for (i, block) in input.chunks_exact(16).enumerate() {
+        for (x, b) in x.iter_mut().zip(block.iter()) {
+            *x ^= *b;
+        }
+        salsa20_8(&mut x);
+        let position = if i % 2 == 0 { i / 2 } else { r + i / 2 };
+        output[position * 16..position * 16 + 16].copy_from_slice(&x);
+    }
+}
+
+// Function to run scryptROMix with cost N = 2^log_n on one block in place
+fn ro_mix(block: &mut [u32], log_n: u8) {
+
+    let n = 1usize << log_n;
+    let length = block.len();
+    let mut v = vec![0u32; n * length];
+    let mut x = block.to_vec();
+    let mut y = vec![0u32; length];
+
+    for i in 0..n {
+        v[i * length..(i + 1) * length].copy_from_slice(&x);
+        block_mix(&x, &mut y);
+        std::mem::swap(&mut x, &mut y);
+    }
+
+    for _ in 0..n {
+        // Integerify: the first 64 bits of the last 64-byte block
+        let j = (x[length - 16] as u64 | (x[length - 15] as u64) << 32) as usize & (n - 1);
+        for (x, v) in x.iter_mut().zip(v[j * length..(j + 1) * length].iter()) {
+            *x ^= *v;
+        }
+        block_mix(&x, &mut y);
+        std::mem::swap(&mut x, &mut y);
+    }
+
+    block.copy_from_slice(&x);
+    wipe(&mut v);
+    wipe(&mut x);
+    wipe(&mut y);
+}
+
+// Function to fill `output` with scrypt of the password, with N = 2^log_n
+pub fn scrypt(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32, output: &mut [u8]) {
+
+    assert!(log_n > 0 && (log_n as u32) < 16 * r && log_n < usize::BITS as u8, "scrypt N has to be a power of two below 2^(16 r)");
+    assert!(r > 0 && p > 0 && (r as u64) * (p as u64) < 1 << 30, "scrypt r and p have to be positive with r * p < 2^30");
+
+    let block_length = 128 * r as usize;
+    let mut bytes = vec![0u8; block_length * p as usize];
+    pbkdf2_hmac_sha256(password, salt, 1, &mut bytes);
+
+    let mut words: Vec<u32> = bytes.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+    let workers = WorkerPool::new(available_threads().min(p as usize));
+    workers.run(words.chunks_mut(block_length / 4).map(|block| Box::new(move || ro_mix(block, log_n)) as Task).collect());
+
+    for (b, w) in bytes.chunks_exact_mut(4).zip(words.iter()) {
+        b.copy_from_slice(&w.to_le_bytes());
+    }
+    pbkdf2_hmac_sha256(password, &bytes, 1, output);
+
+    wipe(&mut words);
+    wipe(&mut bytes);
+}
+
+#[test]
+fn test_scrypt_rfc7914_vectors() {
+
+    // RFC 7914, section 12
+    let mut output = [0u8; 64];
+    scrypt(b"", b"", 4, 1, 1, &mut output);
+    assert_eq!(hex::encode(&output[..]), concat!("77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442",
+                                                 "fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"));
+
+    scrypt(b"password", b"NaCl", 10, 8, 16, &mut output);
+    assert_eq!(hex::encode(&output[..]), concat!("fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162",
+                                                 "2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"));
+}
@@ -0,0 +1,159 @@
+use std::io;
+use std::mem;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+// Wiping of key material, round keys, keystream and plaintext buffers
+//
+// Secrets are overwritten with volatile writes followed by a compiler fence,
+// so the stores are not removed as dead even right before the memory is
+// freed. Byte strings holding keys live in `SecretBytes`, which can neither
+// be cloned nor printed and is wiped when dropped.
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Plain data for which all-zero bytes are a valid value: integers, SIMD
+/// vectors and arrays of them
+///
+/// The trait is sealed, so references, `NonZero*` integers or function
+/// pointers can never be zeroed through `wipe`.
+pub trait Wipeable: Copy + sealed::Sealed {}
+
+macro_rules! wipeable {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl Wipeable for $t {}
+        )*
+    };
+}
+
+wipeable!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+#[cfg(target_arch = "x86_64")]
+wipeable!(std::arch::x86_64::__m128i);
+
+impl<T: Wipeable, const N: usize> sealed::Sealed for [T; N] {}
+impl<T: Wipeable, const N: usize> Wipeable for [T; N] {}
+
+// Function to overwrite a slice of plain data with zero bytes
+pub fn wipe<T: Wipeable>(items: &mut [T]) {
+
+    let pointer = items.as_mut_ptr() as *mut u8;
+    for i in 0..mem::size_of_val(items) {
+        unsafe { std::ptr::write_volatile(pointer.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+// Function to overwrite a single value of plain data with zero bytes
+pub fn wipe_value<T: Wipeable>(value: &mut T) {
+    wipe(std::slice::from_mut(value));
+}
+
+/// Heap bytes holding a secret, wiped on drop
+///
+/// The length is fixed at construction, so the bytes are never moved to a new
+/// allocation behind the caller's back.
+pub struct SecretBytes {
+    bytes: Box<[u8]>,
+    locked: bool,
+}
+
+impl SecretBytes {
+
+    // Function to allocate `length` zero bytes
+    pub fn zeroed(length: usize) -> SecretBytes {
+        SecretBytes { bytes: vec![0u8; length].into_boxed_slice(), locked: false }
+    }
+
+    // Function to copy a secret into a new allocation
+    pub fn from_slice(bytes: &[u8]) -> SecretBytes {
+        let mut secret = SecretBytes::zeroed(bytes.len());
+        secret.bytes.copy_from_slice(bytes);
+        secret
+    }
+
+    // Function to take over a secret from a vector, the vector is wiped
+    // (without its spare capacity, which the caller has to keep clean)
+    pub fn from_vec(mut bytes: Vec<u8>) -> SecretBytes {
+        let secret = SecretBytes::from_slice(&bytes);
+        wipe(&mut bytes);
+        secret
+    }
+
+    // Function to decode a hex string without any intermediate copy
+    pub fn from_hex(hex_string: &str) -> Result<SecretBytes, hex::FromHexError> {
+        let mut secret = SecretBytes::zeroed(hex_string.len() / 2);
+        hex::decode_to_slice(hex_string, &mut secret.bytes)?;
+        Ok(secret)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Function to lock the bytes into RAM with `mlock`, so they are never
+    // written to swap; they are unlocked again on drop
+    #[cfg(target_os = "linux")]
+    pub fn lock_memory(&mut self) -> io::Result<()> {
+
+        if self.locked || self.bytes.is_empty() {
+            return Ok(());
+        }
+        if unsafe { libc::mlock(self.bytes.as_ptr() as *const libc::c_void, self.bytes.len()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.locked = true;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn lock_memory(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "memory locking is only supported on Linux"))
+    }
+}
+
+impl Drop for SecretBytes {
+
+    fn drop(&mut self) {
+        wipe(&mut self.bytes);
+        #[cfg(target_os = "linux")]
+        if self.locked {
+            unsafe { libc::munlock(self.bytes.as_ptr() as *const libc::c_void, self.bytes.len()) };
+        }
+    }
+}
+
+#[test]
+fn test_wipe_clears_plain_data() {
+
+    let mut words = [0xdead_beef_u32; 7];
+    wipe(&mut words);
+    assert_eq!(words, [0; 7]);
+
+    let mut block = u128::MAX;
+    wipe_value(&mut block);
+    assert_eq!(block, 0);
+
+    let mut secret = SecretBytes::from_hex("00ff10ab").unwrap();
+    assert_eq!(secret.as_slice(), &[0x00, 0xff, 0x10, 0xab]);
+    assert!(SecretBytes::from_hex("0g").is_err());
+
+    // locking may fail under a small RLIMIT_MEMLOCK, the bytes stay usable
+    let _ = secret.lock_memory();
+    secret.as_mut_slice()[0] = 1;
+    assert_eq!(secret.as_slice(), &[0x01, 0xff, 0x10, 0xab]);
+}
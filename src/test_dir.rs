@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Scratch directories for tests that need real files
+//
+// Every instance gets a directory of its own, named after the test, the
+// process and a counter, so tests running in parallel never share a file.
+// The directory is removed with everything in it on drop, also when an
+// assertion fails.
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+
+    // Function to create a fresh directory below the system temp directory
+    pub(crate) fn new(name: &str) -> TestDir {
+        TestDir::new_in(&std::env::temp_dir(), name)
+    }
+
+    // Function to create a fresh directory below `parent`
+    pub(crate) fn new_in(parent: &Path, name: &str) -> TestDir {
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = parent.join(format!("aes-ctr-{}-{}-{}", name, std::process::id(), count));
+        fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn join(&self, file_name: &str) -> PathBuf {
+        self.path.join(file_name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,162 @@
+use std::any::Any;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Fixed set of worker threads running tasks that borrow from the caller
+//
+// Loops over many buffers hand every buffer to the same threads instead of
+// spawning new ones each time. `run` only returns once every task it was
+// given has finished, also if one of them panics, so the borrowed data
+// outlives all uses by the workers although the threads outlive the call.
+
+pub(crate) type Task<'a> = Box<dyn FnOnce() + Send + 'a>;
+
+type TaskResult = Result<(), Box<dyn Any + Send>>;
+
+pub(crate) struct WorkerPool {
+    tasks: Option<Sender<Task<'static>>>,
+    finished: Receiver<TaskResult>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+
+    // Function to start the worker threads, a single thread runs every task
+    // on the calling thread without starting any
+    pub(crate) fn new(thread_count: usize) -> WorkerPool {
+
+        let (task_sender, task_receiver) = channel::<Task<'static>>();
+        let (finished_sender, finished) = channel();
+        let task_receiver = Arc::new(Mutex::new(task_receiver));
+
+        let workers = if thread_count <= 1 { Vec::new() } else {
+            (0..thread_count).map(|_| {
+                let task_receiver = Arc::clone(&task_receiver);
+                let finished_sender = finished_sender.clone();
+                thread::spawn(move || work(&task_receiver, &finished_sender))
+            }).collect()
+        };
+
+        WorkerPool { tasks: Some(task_sender), finished, workers }
+    }
+
+    // Function to return the number of tasks running at the same time
+    pub(crate) fn thread_count(&self) -> usize {
+        self.workers.len().max(1)
+    }
+
+    // Function to run all tasks and wait until every one of them finished,
+    // a panic of a task is passed on to the caller afterwards
+    pub(crate) fn run<'a>(&self, tasks: Vec<Task<'a>>) {
+
+        if self.workers.is_empty() {
+            tasks.into_iter().for_each(|task| task());
+            return;
+        }
+
+        // also when this call unwinds, it waits for every task already sent
+        let mut pending = PendingTasks { finished: &self.finished, count: 0 };
+        for task in tasks {
+            // the workers drop the task before it is reported as finished
+            // below, so nothing it borrows is used after this call returns
+            let task: Task<'static> = unsafe { mem::transmute::<Task<'a>, Task<'static>>(task) };
+            self.tasks.as_ref().unwrap().send(task).expect("worker threads stopped");
+            pending.count += 1;
+        }
+
+        let mut panic_payload = None;
+        while pending.count > 0 {
+            let result = self.finished.recv().expect("worker threads stopped");
+            pending.count -= 1;
+            if let Err(payload) = result {
+                panic_payload = Some(payload);
+            }
+        }
+        if let Some(payload) = panic_payload {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+// Tasks sent to the workers but not reported as finished yet
+struct PendingTasks<'p> {
+    finished: &'p Receiver<TaskResult>,
+    count: usize,
+}
+
+impl Drop for PendingTasks<'_> {
+
+    fn drop(&mut self) {
+        // a closed channel means no worker is left that could run a task
+        while self.count > 0 && self.finished.recv().is_ok() {
+            self.count -= 1;
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+
+    fn drop(&mut self) {
+        // closing the channel lets the workers leave their loops
+        self.tasks = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Function to return the number of threads the machine runs at the same time
+pub(crate) fn available_threads() -> usize {
+    thread::available_parallelism().map(|count| count.get()).unwrap_or(1)
+}
+
+// Function run by every worker thread until the pool is dropped
+fn work(tasks: &Mutex<Receiver<Task<'static>>>, finished: &Sender<TaskResult>) {
+
+    loop {
+        let task = match tasks.lock().unwrap().recv() {
+            Ok(task) => task,
+            Err(_) => break,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(task));
+        if finished.send(result).is_err() {
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_worker_pool_runs_borrowing_tasks() {
+
+    let pool = WorkerPool::new(3);
+    assert_eq!(pool.thread_count(), 3);
+
+    // more tasks than threads, every one writing into its own part of a buffer
+    // on the stack, and the same threads for every call
+    let mut data = [0u8; 100];
+    for round in 1..=4u8 {
+        let tasks: Vec<Task> = data.chunks_mut(10).map(|chunk| {
+            Box::new(move || chunk.iter_mut().for_each(|byte| *byte += round)) as Task
+        }).collect();
+        pool.run(tasks);
+    }
+    assert!(data.iter().all(|byte| *byte == 10));
+
+    // a panicking task reaches the caller once the other tasks are done, and
+    // the pool keeps working afterwards
+    let counter = std::sync::atomic::AtomicUsize::new(0);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut tasks: Vec<Task> = vec![Box::new(|| panic!("task failed"))];
+        tasks.extend((0..5).map(|_| Box::new(|| { counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst); }) as Task));
+        pool.run(tasks);
+    }));
+    assert!(result.is_err());
+    assert_eq!(counter.into_inner(), 5);
+    pool.run(vec![Box::new(|| data[0] = 0)]);
+    assert_eq!(data[0], 0);
+
+    assert_eq!(WorkerPool::new(1).thread_count(), 1);
+}